@@ -15,9 +15,10 @@
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::{HashMap, HashSet};
-use parking_lot::RwLock;
+use std::sync::Arc;
+use parking_lot::{Mutex, RwLock};
 use ethereum_types::Address;
-use crate::{ServerKeyId, error::Error};
+use crate::{ServerKeyId, clock::{Clock, SystemClock}, error::Error};
 
 /// ACL storage of Secret Store.
 pub trait AclStorage: Send + Sync {
@@ -55,3 +56,201 @@ impl AclStorage for InMemoryPermissiveAclStorage {
 			.unwrap_or(true))
 	}
 }
+
+/// What to do when ACL storage backend has failed to check access.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AclErrorPolicy {
+	/// Permit access.
+	FailOpen,
+	/// Deny access. This is the default policy.
+	#[default]
+	FailClosed,
+}
+
+/// ACL storage wrapper that converts backend errors into access decision,
+/// according to the configured policy.
+#[derive(Debug)]
+pub struct PolicyAclStorage<A> {
+	acl_storage: A,
+	policy: AclErrorPolicy,
+}
+
+impl<A: AclStorage> PolicyAclStorage<A> {
+	/// Create new ACL storage wrapper with given error policy.
+	pub fn new(acl_storage: A, policy: AclErrorPolicy) -> Self {
+		PolicyAclStorage {
+			acl_storage,
+			policy,
+		}
+	}
+
+	/// Get reference to the inner ACL storage.
+	pub fn inner(&self) -> &A {
+		&self.acl_storage
+	}
+}
+
+impl<A: AclStorage> AclStorage for PolicyAclStorage<A> {
+	fn check(&self, requester: Address, document: &ServerKeyId) -> Result<bool, Error> {
+		match self.acl_storage.check(requester, document) {
+			Ok(has_access) => Ok(has_access),
+			Err(error) => {
+				log::warn!(
+					target: "secretstore",
+					"ACL check of key {:?} has failed: {}. Applying {:?} policy",
+					document,
+					error,
+					self.policy,
+				);
+
+				Ok(self.policy == AclErrorPolicy::FailOpen)
+			},
+		}
+	}
+}
+
+/// Access check, made by the ACL storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclAuditEvent {
+	/// Requester address.
+	pub requester: Address,
+	/// Id of the key that the requester has tried to access.
+	pub document: ServerKeyId,
+	/// Has access been granted? Or the error if the check has failed.
+	pub outcome: Result<bool, Error>,
+	/// Check time (seconds since Unix epoch).
+	pub timestamp: u64,
+}
+
+/// Sink of access checks.
+pub trait AclAuditSink: Send + Sync {
+	/// Record access check.
+	fn record(&self, event: AclAuditEvent);
+}
+
+/// In-memory audit sink that is keeping all recorded events.
+#[derive(Default, Debug)]
+pub struct InMemoryAclAuditSink {
+	events: Mutex<Vec<AclAuditEvent>>,
+}
+
+impl InMemoryAclAuditSink {
+	/// Get all recorded events in the order they have been recorded.
+	pub fn events(&self) -> Vec<AclAuditEvent> {
+		self.events.lock().clone()
+	}
+}
+
+impl AclAuditSink for InMemoryAclAuditSink {
+	fn record(&self, event: AclAuditEvent) {
+		self.events.lock().push(event);
+	}
+}
+
+/// ACL storage wrapper that records every access check (including failed checks)
+/// to the audit sink.
+pub struct AuditedAclStorage<A> {
+	acl_storage: A,
+	sink: Arc<dyn AclAuditSink>,
+	clock: Arc<dyn Clock>,
+}
+
+impl<A: AclStorage> AuditedAclStorage<A> {
+	/// Create new ACL storage wrapper that is using system clock.
+	pub fn new(acl_storage: A, sink: Arc<dyn AclAuditSink>) -> Self {
+		Self::with_clock(acl_storage, sink, Arc::new(SystemClock))
+	}
+
+	/// Create new ACL storage wrapper that is using given clock.
+	pub fn with_clock(acl_storage: A, sink: Arc<dyn AclAuditSink>, clock: Arc<dyn Clock>) -> Self {
+		AuditedAclStorage {
+			acl_storage,
+			sink,
+			clock,
+		}
+	}
+
+	/// Get reference to the inner ACL storage.
+	pub fn inner(&self) -> &A {
+		&self.acl_storage
+	}
+}
+
+impl<A: AclStorage> AclStorage for AuditedAclStorage<A> {
+	fn check(&self, requester: Address, document: &ServerKeyId) -> Result<bool, Error> {
+		let outcome = self.acl_storage.check(requester, document);
+		self.sink.record(AclAuditEvent {
+			requester,
+			document: *document,
+			outcome: outcome.clone(),
+			timestamp: self.clock.now(),
+		});
+		outcome
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	use crate::testing::MockClock;
+	use super::*;
+
+	struct FailingAclStorage;
+
+	impl AclStorage for FailingAclStorage {
+		fn check(&self, _requester: Address, _document: &ServerKeyId) -> Result<bool, Error> {
+			Err(Error::Internal("backend is unreachable".into()))
+		}
+	}
+
+	#[test]
+	fn backend_error_denies_access_by_default() {
+		let acl_storage = PolicyAclStorage::new(FailingAclStorage, Default::default());
+		assert_eq!(acl_storage.check(Default::default(), &Default::default()), Ok(false));
+	}
+
+	#[test]
+	fn backend_error_permits_access_when_failing_open() {
+		let acl_storage = PolicyAclStorage::new(FailingAclStorage, AclErrorPolicy::FailOpen);
+		assert_eq!(acl_storage.check(Default::default(), &Default::default()), Ok(true));
+
+		let acl_storage = PolicyAclStorage::new(InMemoryPermissiveAclStorage::default(), AclErrorPolicy::FailOpen);
+		acl_storage.inner().forbid(Default::default(), Default::default());
+		assert_eq!(acl_storage.check(Default::default(), &Default::default()), Ok(false));
+	}
+
+	#[test]
+	fn access_decisions_are_audited() {
+		let sink = Arc::new(InMemoryAclAuditSink::default());
+		let clock = Arc::new(MockClock::new(100));
+		let acl_storage = AuditedAclStorage::with_clock(
+			InMemoryPermissiveAclStorage::default(),
+			sink.clone(),
+			clock.clone(),
+		);
+		let requester = Address::from_low_u64_be(1);
+		let document = ServerKeyId::from_low_u64_be(2);
+		acl_storage.inner().forbid(requester, document);
+
+		assert_eq!(acl_storage.check(requester, &ServerKeyId::from_low_u64_be(3)), Ok(true));
+		clock.advance(Duration::from_secs(5));
+		assert_eq!(acl_storage.check(requester, &document), Ok(false));
+
+		let failing_sink = Arc::new(InMemoryAclAuditSink::default());
+		let failing_acl_storage = AuditedAclStorage::with_clock(FailingAclStorage, failing_sink.clone(), clock);
+		assert!(failing_acl_storage.check(requester, &document).is_err());
+
+		assert_eq!(sink.events(), vec![
+			AclAuditEvent { requester, document: ServerKeyId::from_low_u64_be(3), outcome: Ok(true), timestamp: 100 },
+			AclAuditEvent { requester, document, outcome: Ok(false), timestamp: 105 },
+		]);
+		assert_eq!(failing_sink.events(), vec![
+			AclAuditEvent {
+				requester,
+				document,
+				outcome: Err(Error::Internal("backend is unreachable".into())),
+				timestamp: 105,
+			},
+		]);
+	}
+}
@@ -0,0 +1,156 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Node backup, used for disaster recovery.
+//!
+//! WARNING: backup contains UNENCRYPTED secret shares. It must be protected at least
+//! as well as the key storage itself.
+
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use crate::{
+	error::Error,
+	key_server_set::{KeyServerSet, NodeAddress},
+	key_storage::KeyStorage,
+	serialization::{SerializableAddress, SerializableH256, SerializableKeyShare},
+};
+
+/// Current version of the node backup format.
+pub const NODE_BACKUP_VERSION: u8 = 1;
+
+/// Everything that the single node holds: all key shares and the key server set snapshot.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeBackup {
+	/// Backup format version.
+	pub version: u8,
+	/// All key shares from the key storage.
+	pub key_shares: BTreeMap<SerializableH256, SerializableKeyShare>,
+	/// Current set of key servers.
+	pub current_set: BTreeMap<SerializableAddress, String>,
+	/// New set of key servers.
+	pub new_set: BTreeMap<SerializableAddress, String>,
+}
+
+/// What to do with keys that are already in the storage when backup is imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+	/// Keep key share from the storage.
+	SkipExisting,
+	/// Replace key share from the storage with the key share from backup.
+	Overwrite,
+}
+
+/// Export all key shares and the key server set snapshot.
+pub fn export_backup<Address: Into<NodeAddress> + Send + Sync>(
+	storage: &dyn KeyStorage,
+	set: &dyn KeyServerSet<NetworkAddress=Address>,
+) -> Result<NodeBackup, Error> {
+	let addresses = |nodes: BTreeMap<_, Address>| nodes
+		.into_iter()
+		.map(|(node, address)| (SerializableAddress::from(node), address.into().to_string()))
+		.collect();
+	let snapshot = set.snapshot();
+	Ok(NodeBackup {
+		version: NODE_BACKUP_VERSION,
+		key_shares: storage.iter().map(|(key_id, key_share)| (key_id.into(), key_share.into())).collect(),
+		current_set: addresses(snapshot.current_set),
+		new_set: addresses(snapshot.new_set),
+	})
+}
+
+/// Import key shares from the backup. Importing the same backup twice has the same effect
+/// as importing it once. The key server set is not imported - it is up to the caller to
+/// restore it using the backup addresses.
+pub fn import_backup(storage: &dyn KeyStorage, backup: NodeBackup, mode: ImportMode) -> Result<(), Error> {
+	if backup.version != NODE_BACKUP_VERSION {
+		return Err(Error::UnsupportedSerializationVersion(backup.version));
+	}
+
+	for (key_id, key_share) in backup.key_shares {
+		let key_id = key_id.into();
+		match (storage.contains(&key_id), mode) {
+			(false, _) => storage.insert(key_id, key_share.into())?,
+			(true, ImportMode::SkipExisting) => (),
+			(true, ImportMode::Overwrite) => storage.update(key_id, key_share.into())?,
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+	use ethereum_types::H256;
+	use parity_crypto::publickey::Secret;
+	use crate::{
+		KeyServerId, ServerKeyId,
+		key_server_set::InMemoryKeyServerSet,
+		key_storage::{InMemoryKeyStorage, KeyShare, KeyShareVersion},
+	};
+	use super::*;
+
+	fn key_share(seed: u64) -> KeyShare {
+		KeyShare {
+			threshold: 1,
+			versions: vec![KeyShareVersion::new(
+				vec![(KeyServerId::from_low_u64_be(1), Secret::from(H256::from_low_u64_be(seed)))].into_iter().collect(),
+				Secret::from(H256::from_low_u64_be(seed + 1)),
+			)],
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn backup_is_exported_and_imported() {
+		let address: SocketAddr = "127.0.0.1:8083".parse().unwrap();
+		let set = InMemoryKeyServerSet::new(false, vec![(KeyServerId::from_low_u64_be(1), address)].into_iter().collect());
+		let storage = InMemoryKeyStorage::default();
+		storage.insert(ServerKeyId::from_low_u64_be(1), key_share(10)).unwrap();
+		storage.insert(ServerKeyId::from_low_u64_be(2), key_share(20)).unwrap();
+
+		let backup = export_backup(&storage, &set).unwrap();
+		assert_eq!(backup.current_set.values().collect::<Vec<_>>(), vec!["127.0.0.1:8083"]);
+		let serialized = serde_json::to_string(&backup).unwrap();
+		let backup: NodeBackup = serde_json::from_str(&serialized).unwrap();
+
+		// key 2 already exists and is skipped
+		let restored = InMemoryKeyStorage::default();
+		restored.insert(ServerKeyId::from_low_u64_be(2), key_share(30)).unwrap();
+		import_backup(&restored, backup.clone(), ImportMode::SkipExisting).unwrap();
+		import_backup(&restored, backup.clone(), ImportMode::SkipExisting).unwrap();
+		assert_eq!(restored.get(&ServerKeyId::from_low_u64_be(1)).unwrap(), Some(key_share(10)));
+		assert_eq!(restored.get(&ServerKeyId::from_low_u64_be(2)).unwrap(), Some(key_share(30)));
+
+		import_backup(&restored, backup, ImportMode::Overwrite).unwrap();
+		assert_eq!(restored.iter().collect::<Vec<_>>(), storage.iter().collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn backup_with_unknown_version_is_rejected() {
+		let backup = NodeBackup {
+			version: NODE_BACKUP_VERSION + 1,
+			key_shares: Default::default(),
+			current_set: Default::default(),
+			new_set: Default::default(),
+		};
+		assert_eq!(
+			import_backup(&InMemoryKeyStorage::default(), backup, ImportMode::Overwrite),
+			Err(Error::UnsupportedSerializationVersion(NODE_BACKUP_VERSION + 1)),
+		);
+	}
+}
@@ -0,0 +1,143 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::{
+	collections::HashMap,
+	future::Future,
+	pin::Pin,
+	sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}},
+	task::{Context, Poll, Waker},
+};
+use parking_lot::Mutex;
+
+/// Token that is used to cancel operations. All clones of the token are sharing the
+/// same state, so cancelling any clone cancels all of them.
+#[derive(Default, Clone)]
+pub struct CancellationToken {
+	inner: Arc<CancellationTokenInner>,
+}
+
+#[derive(Default)]
+struct CancellationTokenInner {
+	is_cancelled: AtomicBool,
+	next_waiter_id: AtomicU64,
+	/// Wakers of pending `Cancelled` futures, keyed by future id.
+	waiters: Mutex<HashMap<u64, Waker>>,
+}
+
+/// Future that is resolved when the token is cancelled.
+pub struct Cancelled {
+	id: u64,
+	token: CancellationToken,
+}
+
+impl CancellationToken {
+	/// Create new token.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Cancel the token. Does nothing if the token is already cancelled.
+	pub fn cancel(&self) {
+		self.inner.is_cancelled.store(true, Ordering::SeqCst);
+		// wake outside of the lock, because waking may drop `Cancelled` futures
+		let waiters = std::mem::take(&mut *self.inner.waiters.lock());
+		for (_, waiter) in waiters {
+			waiter.wake();
+		}
+	}
+
+	/// Is the token cancelled?
+	pub fn is_cancelled(&self) -> bool {
+		self.inner.is_cancelled.load(Ordering::SeqCst)
+	}
+
+	/// Get future that is resolved when the token is cancelled.
+	pub fn cancelled(&self) -> Cancelled {
+		Cancelled {
+			id: self.inner.next_waiter_id.fetch_add(1, Ordering::Relaxed),
+			token: self.clone(),
+		}
+	}
+}
+
+impl Future for Cancelled {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		if self.token.is_cancelled() {
+			return Poll::Ready(());
+		}
+
+		// check again after registering waker, so that we do not miss concurrent cancel
+		{
+			let mut waiters = self.token.inner.waiters.lock();
+			match waiters.get_mut(&self.id) {
+				Some(waker) if waker.will_wake(cx.waker()) => (),
+				Some(waker) => *waker = cx.waker().clone(),
+				None => {
+					waiters.insert(self.id, cx.waker().clone());
+				},
+			}
+		}
+		match self.token.is_cancelled() {
+			true => Poll::Ready(()),
+			false => Poll::Pending,
+		}
+	}
+}
+
+impl Drop for Cancelled {
+	fn drop(&mut self) {
+		self.token.inner.waiters.lock().remove(&self.id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::{FutureExt, executor::block_on};
+	use super::*;
+
+	#[test]
+	fn cancelled_future_is_resolved_when_token_is_cancelled() {
+		let token = CancellationToken::new();
+		let mut cancelled = token.cancelled();
+		assert_eq!((&mut cancelled).now_or_never(), None);
+
+		token.clone().cancel();
+		assert!(token.is_cancelled());
+		block_on(cancelled);
+	}
+
+	#[test]
+	fn pending_cancelled_future_registers_single_waker() {
+		let token = CancellationToken::new();
+		let mut cancelled1 = token.cancelled();
+		let mut cancelled2 = token.cancelled();
+		for _ in 0..100 {
+			assert_eq!((&mut cancelled1).now_or_never(), None);
+			assert_eq!((&mut cancelled2).now_or_never(), None);
+		}
+		assert_eq!(token.inner.waiters.lock().len(), 2);
+
+		drop(cancelled1);
+		assert_eq!(token.inner.waiters.lock().len(), 1);
+		token.cancel();
+		assert!(token.inner.waiters.lock().is_empty());
+		block_on(cancelled2);
+	}
+}
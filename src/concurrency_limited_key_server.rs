@@ -0,0 +1,554 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	future::Future,
+	sync::Arc,
+};
+use futures::future::{BoxFuture, FutureExt, ready};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use ethereum_types::{Address, H256};
+use parity_crypto::publickey::{Public, Signature};
+use crate::{
+	KeyServerPublic, ServerKeyId,
+	error::Error,
+	key_server::{
+		AdminSessionsServer, DocumentKeyServer, KeyServer, KeyServerCapabilities, MessageSigner, Origin,
+		ServerKeyGenerator, SessionKind, SessionResult, SignatureScheme, ServerKeyGenerationParams,
+		ServerKeyGenerationResult, ServerKeyRetrievalParams, ServerKeyRetrievalResult, DocumentKeyStoreParams,
+		DocumentKeyStoreResult, DocumentKeyGenerationParams, DocumentKeyGenerationResult,
+		EncryptedDocumentKeyGenerationResult, DocumentKeyRetrievalParams, DocumentKeyRetrievalResult,
+		DocumentKeyCommonRetrievalParams, DocumentKeyCommonRetrievalResult,
+		DocumentKeyCommonPartialRetrievalResult, DocumentKeyShadowRetrievalParams,
+		DocumentKeyShadowRetrievalResult, KeyOwnershipTransferParams, KeyOwnershipTransferResult,
+		SchnorrSigningParams, SchnorrSigningResult, EcdsaSigningParams,
+		EcdsaSigningResult, SigningParams, SigningResult, ServersSetChangeResult,
+	},
+	requester::Requester,
+};
+
+/// What to do with operation when concurrency limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyLimitPolicy {
+	/// Reject operation with `Error::TooManySessions`.
+	Reject,
+	/// Start operation when any of active operations is completed.
+	Queue,
+}
+
+/// Key server wrapper that limits number of concurrent operations.
+///
+/// Operation is counted as active from the call until returned future is completed (or
+/// dropped). Besides the total limit, there could be additional limits for operations of
+/// given kind. Batch operations (`restore_document_key_common_many`) and read-only
+/// operations that aren't touching other key servers (`plan_servers_set_change`) aren't
+/// limited.
+pub struct ConcurrencyLimitedKeyServer<K> {
+	key_server: Arc<K>,
+	policy: ConcurrencyLimitPolicy,
+	limit: Arc<Semaphore>,
+	kind_limits: HashMap<SessionKind, Arc<Semaphore>>,
+}
+
+impl<K> ConcurrencyLimitedKeyServer<K> {
+	/// Create new key server that allows at most `limit` concurrent operations.
+	pub fn new(key_server: K, limit: usize, policy: ConcurrencyLimitPolicy) -> Self {
+		ConcurrencyLimitedKeyServer {
+			key_server: Arc::new(key_server),
+			policy,
+			limit: Arc::new(Semaphore::new(limit)),
+			kind_limits: HashMap::new(),
+		}
+	}
+
+	/// Additionally limit number of concurrent operations of given kind.
+	pub fn with_kind_limit(mut self, kind: SessionKind, limit: usize) -> Self {
+		self.kind_limits.insert(kind, Arc::new(Semaphore::new(limit)));
+		self
+	}
+
+	/// Get reference to the inner key server.
+	pub fn inner(&self) -> &K {
+		&self.key_server
+	}
+}
+
+impl<K: Send + Sync + 'static> ConcurrencyLimitedKeyServer<K> {
+	/// Start operation of given kind when concurrency limits are allowing that.
+	fn limited<P, R, F>(
+		&self,
+		kind: SessionKind,
+		origin: Option<Origin>,
+		params: P,
+		start: impl FnOnce(&K) -> F + Send + 'static,
+	) -> BoxFuture<'static, SessionResult<P, R>> where
+		P: Send + 'static,
+		R: Send + 'static,
+		F: Future<Output = SessionResult<P, R>> + Send + 'static,
+	{
+		let mut semaphores: Vec<_> = self.kind_limits.get(&kind).cloned().into_iter().collect();
+		semaphores.push(self.limit.clone());
+
+		match (try_acquire(&semaphores), self.policy) {
+			(Some(permits), _) => {
+				let operation = start(&self.key_server);
+				async move {
+					let result = operation.await;
+					drop(permits);
+					result
+				}.boxed()
+			},
+			(None, ConcurrencyLimitPolicy::Reject) => ready(SessionResult {
+				origin,
+				params,
+				result: Err(Error::TooManySessions),
+			}).boxed(),
+			(None, ConcurrencyLimitPolicy::Queue) => {
+				let key_server = self.key_server.clone();
+				async move {
+					let permits = acquire(semaphores).await;
+					let result = start(&key_server).await;
+					drop(permits);
+					result
+				}.boxed()
+			},
+		}
+	}
+}
+
+/// Try to acquire permits of all semaphores.
+fn try_acquire(semaphores: &[Arc<Semaphore>]) -> Option<Vec<OwnedSemaphorePermit>> {
+	semaphores.iter().map(|semaphore| semaphore.clone().try_acquire_owned().ok()).collect()
+}
+
+/// Acquire permits of all semaphores. Semaphores are always acquired in the same order:
+/// kind permit goes first, so operations that are waiting for the kind permit aren't
+/// holding global permits, required by operations of other kinds.
+async fn acquire(semaphores: Vec<Arc<Semaphore>>) -> Vec<OwnedSemaphorePermit> {
+	let mut permits = Vec::with_capacity(semaphores.len());
+	for semaphore in semaphores {
+		permits.push(semaphore.acquire_owned().await);
+	}
+	permits
+}
+
+impl<K> ServerKeyGenerator for ConcurrencyLimitedKeyServer<K> where
+	K: ServerKeyGenerator + Send + Sync + 'static,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+{
+	type GenerateKeyFuture = BoxFuture<'static, ServerKeyGenerationResult>;
+	type RestoreKeyFuture = BoxFuture<'static, ServerKeyRetrievalResult>;
+	type SupportsEcdsaFuture = K::SupportsEcdsaFuture;
+	type KeyAuthorFuture = K::KeyAuthorFuture;
+
+	fn generate_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateKeyFuture {
+		self.limited(
+			SessionKind::ServerKeyGeneration,
+			origin,
+			ServerKeyGenerationParams { key_id },
+			move |key_server| key_server.generate_key(origin, key_id, author, threshold),
+		)
+	}
+
+	fn restore_key_public(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Option<Requester>,
+	) -> Self::RestoreKeyFuture {
+		self.limited(
+			SessionKind::ServerKeyRetrieval,
+			origin,
+			ServerKeyRetrievalParams { key_id },
+			move |key_server| key_server.restore_key_public(origin, key_id, author),
+		)
+	}
+
+	fn supports_ecdsa(&self, key_id: ServerKeyId, requester: Requester) -> Self::SupportsEcdsaFuture {
+		self.key_server.supports_ecdsa(key_id, requester)
+	}
+
+	fn key_author(&self, key_id: ServerKeyId, requester: Requester) -> Self::KeyAuthorFuture {
+		self.key_server.key_author(key_id, requester)
+	}
+}
+
+impl<K> DocumentKeyServer for ConcurrencyLimitedKeyServer<K> where
+	K: DocumentKeyServer + Send + Sync + 'static,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::StoreDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyEncryptedFuture: 'static,
+	K::RestoreDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyCommonFuture: 'static,
+	K::RestoreDocumentKeyShadowFuture: 'static,
+	K::RestoreDocumentKeyAtVersionFuture: 'static,
+	K::RestoreDocumentKeyCommonPartialFuture: 'static,
+	K::TransferKeyOwnershipFuture: 'static,
+{
+	type StoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyStoreResult>;
+	type GenerateDocumentKeyFuture = BoxFuture<'static, DocumentKeyGenerationResult>;
+	type GenerateDocumentKeyEncryptedFuture = BoxFuture<'static, EncryptedDocumentKeyGenerationResult>;
+	type RestoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonFuture = BoxFuture<'static, DocumentKeyCommonRetrievalResult>;
+	type RestoreDocumentKeyShadowFuture = BoxFuture<'static, DocumentKeyShadowRetrievalResult>;
+	type RestoreDocumentKeyAtVersionFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonManyFuture = K::RestoreDocumentKeyCommonManyFuture;
+	type RestoreDocumentKeyCommonPartialFuture = BoxFuture<'static, DocumentKeyCommonPartialRetrievalResult>;
+	type TransferKeyOwnershipFuture = BoxFuture<'static, KeyOwnershipTransferResult>;
+
+	fn store_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		common_point: Public,
+		encrypted_document_key: Public,
+	) -> Self::StoreDocumentKeyFuture {
+		self.limited(
+			SessionKind::DocumentKeyStore,
+			origin,
+			DocumentKeyStoreParams { key_id },
+			move |key_server| key_server.store_document_key(origin, key_id, author, common_point, encrypted_document_key),
+		)
+	}
+
+	fn generate_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyFuture {
+		self.limited(
+			SessionKind::DocumentKeyGeneration,
+			origin,
+			DocumentKeyGenerationParams { key_id },
+			move |key_server| key_server.generate_document_key(origin, key_id, author, threshold),
+		)
+	}
+
+	fn generate_document_key_encrypted(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyEncryptedFuture {
+		self.limited(
+			SessionKind::DocumentKeyGeneration,
+			origin,
+			DocumentKeyGenerationParams { key_id },
+			move |key_server| key_server.generate_document_key_encrypted(origin, key_id, author, threshold),
+		)
+	}
+
+	fn restore_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyFuture {
+		self.limited(
+			SessionKind::DocumentKeyRetrieval,
+			origin,
+			DocumentKeyRetrievalParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.restore_document_key(origin, key_id, requester),
+		)
+	}
+
+	fn restore_document_key_at_version(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		version: H256,
+	) -> Self::RestoreDocumentKeyAtVersionFuture {
+		self.limited(
+			SessionKind::DocumentKeyRetrieval,
+			origin,
+			DocumentKeyRetrievalParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.restore_document_key_at_version(origin, key_id, requester, version),
+		)
+	}
+
+	fn restore_document_key_common(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonFuture {
+		self.limited(
+			SessionKind::DocumentKeyCommonRetrieval,
+			origin,
+			DocumentKeyCommonRetrievalParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.restore_document_key_common(origin, key_id, requester),
+		)
+	}
+
+	fn restore_document_key_common_many(
+		&self,
+		origin: Option<Origin>,
+		key_ids: &[ServerKeyId],
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonManyFuture {
+		self.key_server.restore_document_key_common_many(origin, key_ids, requester)
+	}
+
+	fn restore_document_key_common_partial(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonPartialFuture {
+		self.limited(
+			SessionKind::DocumentKeyCommonRetrieval,
+			origin,
+			DocumentKeyCommonRetrievalParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.restore_document_key_common_partial(origin, key_id, requester),
+		)
+	}
+
+	fn restore_document_key_shadow(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyShadowFuture {
+		self.limited(
+			SessionKind::DocumentKeyShadowRetrieval,
+			origin,
+			DocumentKeyShadowRetrievalParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.restore_document_key_shadow(origin, key_id, requester),
+		)
+	}
+
+	fn transfer_key_ownership(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		current_author: Requester,
+		new_author: Address,
+	) -> Self::TransferKeyOwnershipFuture {
+		self.limited(
+			SessionKind::KeyOwnershipTransfer,
+			origin,
+			KeyOwnershipTransferParams { key_id, new_author },
+			move |key_server| key_server.transfer_key_ownership(origin, key_id, current_author, new_author),
+		)
+	}
+}
+
+impl<K> MessageSigner for ConcurrencyLimitedKeyServer<K> where
+	K: MessageSigner + Send + Sync + 'static,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::SignMessageSchnorrFuture: 'static,
+	K::SignMessageEcdsaFuture: 'static,
+	K::SignMessageFuture: 'static,
+{
+	type SignMessageSchnorrFuture = BoxFuture<'static, SchnorrSigningResult>;
+	type SignMessageEcdsaFuture = BoxFuture<'static, EcdsaSigningResult>;
+	type SignMessageFuture = BoxFuture<'static, SigningResult>;
+
+	fn sign_message_schnorr(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageSchnorrFuture {
+		self.limited(
+			SessionKind::SchnorrSigning,
+			origin,
+			SchnorrSigningParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.sign_message_schnorr(origin, key_id, requester, message),
+		)
+	}
+
+	fn sign_message_ecdsa(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageEcdsaFuture {
+		self.limited(
+			SessionKind::EcdsaSigning,
+			origin,
+			EcdsaSigningParams { key_id, requester: requester.clone() },
+			move |key_server| key_server.sign_message_ecdsa(origin, key_id, requester, message),
+		)
+	}
+
+	fn sign_message(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> Self::SignMessageFuture {
+		let kind = match scheme {
+			SignatureScheme::Schnorr => SessionKind::SchnorrSigning,
+			SignatureScheme::Ecdsa => SessionKind::EcdsaSigning,
+		};
+		self.limited(
+			kind,
+			origin,
+			SigningParams { key_id, requester: requester.clone(), scheme },
+			move |key_server| key_server.sign_message(origin, key_id, requester, message, scheme),
+		)
+	}
+}
+
+impl<K> AdminSessionsServer for ConcurrencyLimitedKeyServer<K> where
+	K: AdminSessionsServer + Send + Sync + 'static,
+	K::ChangeServersSetFuture: 'static,
+{
+	type ChangeServersSetFuture = BoxFuture<'static, ServersSetChangeResult>;
+	type PlanServersSetChangeFuture = K::PlanServersSetChangeFuture;
+
+	fn change_servers_set(
+		&self,
+		origin: Option<Origin>,
+		old_set_signature: Signature,
+		new_set_signature: Signature,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::ChangeServersSetFuture {
+		self.limited(
+			SessionKind::ServersSetChange,
+			origin,
+			(),
+			move |key_server| key_server.change_servers_set(origin, old_set_signature, new_set_signature, new_servers_set),
+		)
+	}
+
+	fn plan_servers_set_change(
+		&self,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::PlanServersSetChangeFuture {
+		self.key_server.plan_servers_set_change(new_servers_set)
+	}
+}
+
+impl<K> KeyServer for ConcurrencyLimitedKeyServer<K> where
+	K: KeyServer,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::StoreDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyEncryptedFuture: 'static,
+	K::RestoreDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyCommonFuture: 'static,
+	K::RestoreDocumentKeyShadowFuture: 'static,
+	K::RestoreDocumentKeyAtVersionFuture: 'static,
+	K::RestoreDocumentKeyCommonPartialFuture: 'static,
+	K::TransferKeyOwnershipFuture: 'static,
+	K::SignMessageSchnorrFuture: 'static,
+	K::SignMessageEcdsaFuture: 'static,
+	K::SignMessageFuture: 'static,
+	K::ChangeServersSetFuture: 'static,
+{
+	fn capabilities(&self) -> KeyServerCapabilities {
+		self.key_server.capabilities()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::block_on;
+	use parity_crypto::publickey::{Generator, Random};
+	use crate::{key_server::SessionIntrospection, testing::MockKeyServer};
+	use super::*;
+
+	fn generate_key(
+		key_server: &ConcurrencyLimitedKeyServer<MockKeyServer>,
+		key_id: u64,
+	) -> BoxFuture<'static, ServerKeyGenerationResult> {
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		key_server.generate_key(None, ServerKeyId::from_low_u64_be(key_id), requester, 0)
+	}
+
+	#[test]
+	fn excess_operation_is_rejected() {
+		let key_server = ConcurrencyLimitedKeyServer::new(MockKeyServer::new(), 2, ConcurrencyLimitPolicy::Reject);
+		let operation1 = generate_key(&key_server, 1);
+		let operation2 = generate_key(&key_server, 2);
+		let operation3 = generate_key(&key_server, 3);
+		assert_eq!(block_on(operation3).result.map(|_| ()), Err(Error::TooManySessions));
+
+		block_on(operation1).result.unwrap();
+		block_on(generate_key(&key_server, 4)).result.unwrap();
+		block_on(operation2).result.unwrap();
+	}
+
+	#[test]
+	fn excess_operation_is_queued() {
+		let key_server = ConcurrencyLimitedKeyServer::new(MockKeyServer::new(), 2, ConcurrencyLimitPolicy::Queue);
+		let operation1 = generate_key(&key_server, 1);
+		let operation2 = generate_key(&key_server, 2);
+		let mut operation3 = generate_key(&key_server, 3);
+
+		// mock key server registers session when operation is started
+		assert!((&mut operation3).now_or_never().is_none());
+		assert_eq!(key_server.inner().active_sessions().len(), 2);
+
+		block_on(operation1).result.unwrap();
+		block_on(operation3).result.unwrap();
+		block_on(operation2).result.unwrap();
+	}
+
+	#[test]
+	fn operations_of_limited_kind_are_rejected() {
+		let key_server = ConcurrencyLimitedKeyServer::new(MockKeyServer::new(), 10, ConcurrencyLimitPolicy::Reject)
+			.with_kind_limit(SessionKind::ServerKeyGeneration, 1);
+		let _operation1 = generate_key(&key_server, 1);
+		assert_eq!(block_on(generate_key(&key_server, 2)).result.map(|_| ()), Err(Error::TooManySessions));
+		assert_eq!(
+			block_on(key_server.restore_key_public(None, ServerKeyId::from_low_u64_be(1), None)).result.map(|_| ()),
+			Err(Error::ServerKeyIsNotFound),
+		);
+	}
+
+	#[test]
+	fn queued_operation_of_limited_kind_does_not_block_other_kinds() {
+		let key_server = ConcurrencyLimitedKeyServer::new(MockKeyServer::new(), 2, ConcurrencyLimitPolicy::Queue)
+			.with_kind_limit(SessionKind::ServerKeyGeneration, 1);
+		let operation1 = generate_key(&key_server, 1);
+		let mut operation2 = generate_key(&key_server, 2);
+		assert!((&mut operation2).now_or_never().is_none());
+
+		let restore_key = key_server.restore_key_public(None, ServerKeyId::from_low_u64_be(1), None);
+		assert_eq!(
+			restore_key.now_or_never().map(|result| result.result.map(|_| ())),
+			Some(Err(Error::ServerKeyIsNotFound)),
+		);
+
+		block_on(operation1).result.unwrap();
+		block_on(operation2).result.unwrap();
+	}
+}
@@ -0,0 +1,286 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{collections::BTreeSet, future::Future, time::Duration};
+use crate::{KeyServerId, error::Error};
+
+/// Default timeout of the consensus phase of the session.
+pub const DEFAULT_CONSENSUS_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default timeout of establishing the session with other key servers.
+pub const DEFAULT_ESTABLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Key server sessions configuration. Key servers are consulting it to fail sessions
+/// that are waiting for other key servers for too long (see `wait_for_establish` and
+/// `wait_for_consensus`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionConfig {
+	/// Time to wait for confirmations from consensus group members. When it elapses,
+	/// session fails with `Error::ConsensusUnreachable`.
+	pub consensus_timeout: Duration,
+	/// Time to wait until session is established with other key servers. When it
+	/// elapses, session fails with `Error::ConsensusTemporaryUnreachable`.
+	pub establish_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+	fn default() -> Self {
+		SessionConfig {
+			consensus_timeout: DEFAULT_CONSENSUS_TIMEOUT,
+			establish_timeout: DEFAULT_ESTABLISH_TIMEOUT,
+		}
+	}
+}
+
+impl SessionConfig {
+	/// Set consensus timeout.
+	pub fn with_consensus_timeout(mut self, consensus_timeout: Duration) -> Self {
+		self.consensus_timeout = consensus_timeout;
+		self
+	}
+
+	/// Set session establish timeout.
+	pub fn with_establish_timeout(mut self, establish_timeout: Duration) -> Self {
+		self.establish_timeout = establish_timeout;
+		self
+	}
+}
+
+/// Wait until consensus is reached (i.e. until `consensus` future is resolved), but no
+/// longer than `config.consensus_timeout`. Must be called from within tokio runtime.
+pub async fn wait_for_consensus<T>(
+	config: &SessionConfig,
+	consensus: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	tokio::time::timeout(config.consensus_timeout, consensus)
+		.await
+		.unwrap_or(Err(Error::ConsensusUnreachable))
+}
+
+/// Wait until session is established with other key servers (i.e. until `establish`
+/// future is resolved), but no longer than `config.establish_timeout`. Must be called
+/// from within tokio runtime.
+pub async fn wait_for_establish<T>(
+	config: &SessionConfig,
+	establish: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	tokio::time::timeout(config.establish_timeout, establish)
+		.await
+		.unwrap_or(Err(Error::ConsensusTemporaryUnreachable))
+}
+
+/// Get number of confirmations required for consensus over key with given threshold.
+///
+/// Key with threshold `t` is generated so that any `t + 1` key servers are able to
+/// restore its secret portion, while any `t` key servers are not. So consensus group
+/// is formed when `t + 1` key servers have confirmed their participation.
+pub fn required_quorum(threshold: usize) -> usize {
+	threshold + 1
+}
+
+/// Returns true if given number of confirmations is enough for consensus over key
+/// with given threshold.
+pub fn has_quorum(confirmations: usize, threshold: usize) -> bool {
+	confirmations >= required_quorum(threshold)
+}
+
+/// Check that given number of key shares is enough to restore key with given threshold.
+pub fn check_shares_quorum(have: usize, threshold: usize) -> Result<(), Error> {
+	match has_quorum(have, threshold) {
+		true => Ok(()),
+		false => Err(Error::NotEnoughShares { have, need: required_quorum(threshold) }),
+	}
+}
+
+/// Key threshold, validated against number of nodes that are holding key shares.
+#[cfg(feature = "strict-types")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Threshold(usize);
+
+#[cfg(feature = "strict-types")]
+impl Threshold {
+	/// Create threshold of the key that is shared among `node_count` nodes. Fails
+	/// if there are not enough nodes to restore the key (i.e. if `threshold >= node_count`).
+	pub fn new(threshold: usize, node_count: usize) -> Result<Self, Error> {
+		match threshold < node_count {
+			true => Ok(Threshold(threshold)),
+			false => Err(Error::NotEnoughNodesForThreshold),
+		}
+	}
+
+	/// Get raw threshold value.
+	pub fn value(&self) -> usize {
+		self.0
+	}
+
+	/// Get number of nodes that are required to restore the key or to sign message.
+	/// See `required_quorum`.
+	pub fn required_signers(&self) -> usize {
+		required_quorum(self.0)
+	}
+}
+
+#[cfg(feature = "strict-types")]
+impl From<Threshold> for usize {
+	fn from(threshold: Threshold) -> usize {
+		threshold.0
+	}
+}
+
+/// State of confirmations tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerState {
+	/// Not enough confirmations yet.
+	Pending,
+	/// Required number of nodes have confirmed.
+	Reached,
+	/// Too many nodes have rejected, so required number of confirmations is unreachable.
+	Impossible,
+}
+
+/// Tracks confirmations and rejections from a set of nodes until quorum is reached.
+#[derive(Debug, Clone)]
+pub struct ConfirmationTracker {
+	required: usize,
+	total: usize,
+	confirmed: BTreeSet<KeyServerId>,
+	rejected: BTreeSet<KeyServerId>,
+}
+
+impl ConfirmationTracker {
+	/// Create new tracker that requires `required` confirmations from `total` nodes.
+	pub fn new(required: usize, total: usize) -> Self {
+		ConfirmationTracker {
+			required,
+			total,
+			confirmed: BTreeSet::new(),
+			rejected: BTreeSet::new(),
+		}
+	}
+
+	/// Record confirmation from the node. Repeated confirmations from the same node are ignored.
+	pub fn confirm(&mut self, node: KeyServerId) -> TrackerState {
+		self.rejected.remove(&node);
+		self.confirmed.insert(node);
+		self.state()
+	}
+
+	/// Record rejection from the node. Repeated rejections from the same node are ignored.
+	pub fn reject(&mut self, node: KeyServerId) -> TrackerState {
+		self.confirmed.remove(&node);
+		self.rejected.insert(node);
+		self.state()
+	}
+
+	/// Get current state of the tracker.
+	pub fn state(&self) -> TrackerState {
+		if self.confirmed.len() >= self.required {
+			TrackerState::Reached
+		} else if self.total.saturating_sub(self.rejected.len()) < self.required {
+			TrackerState::Impossible
+		} else {
+			TrackerState::Pending
+		}
+	}
+
+	/// Get nodes that have confirmed.
+	pub fn confirmed(&self) -> &BTreeSet<KeyServerId> {
+		&self.confirmed
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::FutureExt;
+	use parity_crypto::publickey::{Generator, Random};
+	use crate::{ServerKeyId, key_server::ServerKeyGenerator, requester::Requester, testing::{MockKeyServer, SessionPhase}};
+	use super::*;
+
+	#[test]
+	fn quorum_is_computed() {
+		for threshold in 0..5 {
+			assert_eq!(required_quorum(threshold), threshold + 1);
+			assert!(!has_quorum(threshold, threshold));
+			assert!(has_quorum(threshold + 1, threshold));
+			assert!(has_quorum(threshold + 2, threshold));
+		}
+	}
+
+	#[cfg(feature = "strict-types")]
+	#[test]
+	fn threshold_is_validated() {
+		let threshold = Threshold::new(2, 3).unwrap();
+		assert_eq!(threshold.value(), 2);
+		assert_eq!(threshold.required_signers(), 3);
+		assert_eq!(Threshold::new(0, 1).map(|threshold| threshold.required_signers()), Ok(1));
+		assert_eq!(Threshold::new(3, 3), Err(Error::NotEnoughNodesForThreshold));
+		assert_eq!(Threshold::new(4, 3), Err(Error::NotEnoughNodesForThreshold));
+	}
+
+	#[test]
+	fn tracker_reaches_quorum() {
+		let mut tracker = ConfirmationTracker::new(2, 3);
+		assert_eq!(tracker.state(), TrackerState::Pending);
+		assert_eq!(tracker.confirm(KeyServerId::from_low_u64_be(1)), TrackerState::Pending);
+		assert_eq!(tracker.reject(KeyServerId::from_low_u64_be(2)), TrackerState::Pending);
+		assert_eq!(tracker.confirm(KeyServerId::from_low_u64_be(3)), TrackerState::Reached);
+	}
+
+	#[test]
+	fn tracker_ignores_duplicate_confirmations() {
+		let mut tracker = ConfirmationTracker::new(2, 3);
+		assert_eq!(tracker.confirm(KeyServerId::from_low_u64_be(1)), TrackerState::Pending);
+		assert_eq!(tracker.confirm(KeyServerId::from_low_u64_be(1)), TrackerState::Pending);
+		assert_eq!(tracker.confirmed().len(), 1);
+	}
+
+	#[test]
+	fn tracker_detects_impossible_quorum() {
+		let mut tracker = ConfirmationTracker::new(2, 3);
+		assert_eq!(tracker.reject(KeyServerId::from_low_u64_be(1)), TrackerState::Pending);
+		assert_eq!(tracker.reject(KeyServerId::from_low_u64_be(1)), TrackerState::Pending);
+		assert_eq!(tracker.reject(KeyServerId::from_low_u64_be(2)), TrackerState::Impossible);
+	}
+
+	#[test]
+	fn consensus_fails_when_confirmation_never_arrives() {
+		let config = SessionConfig::default().with_consensus_timeout(Duration::from_millis(10));
+		let mut runtime = crate::executor::tokio_runtime().unwrap();
+		let key_server = MockKeyServer::new().with_session_config(config);
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let generate_key = |key_id| key_server
+			.generate_key(None, ServerKeyId::from_low_u64_be(key_id), requester.clone(), 0)
+			.map(|session_result| session_result.result.map(|_| ()));
+
+		key_server.stall_next_sessions(vec![SessionPhase::Consensus]);
+		assert_eq!(runtime.block_on_std(generate_key(1)), Err(Error::ConsensusUnreachable));
+		assert_eq!(runtime.block_on_std(generate_key(2)), Ok(()));
+	}
+
+	#[test]
+	fn establish_fails_when_session_is_never_established() {
+		let config = SessionConfig::default().with_establish_timeout(Duration::from_millis(10));
+		let mut runtime = crate::executor::tokio_runtime().unwrap();
+		let key_server = MockKeyServer::new().with_session_config(config);
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+
+		key_server.stall_next_sessions(vec![SessionPhase::Establish]);
+		let generate_key = key_server.generate_key(None, ServerKeyId::from_low_u64_be(1), requester, 0);
+		assert_eq!(
+			runtime.block_on_std(generate_key).result.map(|_| ()),
+			Err(Error::ConsensusTemporaryUnreachable),
+		);
+	}
+}
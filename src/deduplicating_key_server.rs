@@ -0,0 +1,584 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	future::Future,
+	sync::{Arc, atomic::{AtomicU64, Ordering}},
+	time::{Duration, Instant},
+};
+use futures::future::{self, BoxFuture, FutureExt, WeakShared};
+use parking_lot::Mutex;
+use ethereum_types::{Address, H256};
+use parity_crypto::publickey::{Public, Signature};
+use crate::{
+	KeyServerPublic, ServerKeyId,
+	key_server::{
+		AdminSessionsServer, DocumentKeyServer, KeyServer, KeyServerCapabilities, MessageSigner, Origin, ServerKeyGenerator,
+		SignatureScheme, ServerKeyGenerationResult, ServerKeyRetrievalResult, DocumentKeyStoreResult,
+		DocumentKeyGenerationResult, DocumentKeyRetrievalResult, DocumentKeyShadowRetrievalResult,
+		SchnorrSigningResult, EcdsaSigningResult, ServersSetChangeResult, SessionResult,
+	},
+	requester::Requester,
+	service::ServiceTask,
+};
+
+/// Key server wrapper that deduplicates identical requests.
+///
+/// If identical task (see `ServiceTask`) is already being processed, the new caller
+/// is attached to the existing session instead of starting new session. Results of
+/// completed sessions are cached for `cache_duration`, so that late retries receive
+/// the same result. Non-fatal errors aren't cached, so retries are starting new
+/// sessions. Session origin isn't a part of the task, so deduplicated result
+/// has the origin of the first call.
+///
+/// Only operations that are represented by `ServiceTask` are deduplicated. All other
+/// operations are passed directly to the inner key server.
+pub struct DeduplicatingKeyServer<K> {
+	key_server: K,
+	generate_key: Deduplicator<ServerKeyGenerationResult>,
+	restore_key_public: Deduplicator<ServerKeyRetrievalResult>,
+	store_document_key: Deduplicator<DocumentKeyStoreResult>,
+	generate_document_key: Deduplicator<DocumentKeyGenerationResult>,
+	restore_document_key: Deduplicator<DocumentKeyRetrievalResult>,
+	restore_document_key_shadow: Deduplicator<DocumentKeyShadowRetrievalResult>,
+	sign_message_schnorr: Deduplicator<SchnorrSigningResult>,
+	sign_message_ecdsa: Deduplicator<EcdsaSigningResult>,
+	change_servers_set: Deduplicator<ServersSetChangeResult>,
+}
+
+/// Deduplicator of tasks that are resolving to the same type.
+struct Deduplicator<T> {
+	cache_duration: Duration,
+	next_task_id: AtomicU64,
+	tasks: Arc<Mutex<HashMap<ServiceTask, DeduplicatedTask<T>>>>,
+}
+
+/// Task that is either active, or has been completed recently.
+enum DeduplicatedTask<T> {
+	/// Task is active. Only callers are holding the future, so the task is dropped
+	/// when all callers have dropped their futures.
+	Active {
+		id: u64,
+		future: WeakShared<BoxFuture<'static, T>>,
+	},
+	/// Task has been completed.
+	Completed {
+		result: T,
+		completed_at: Instant,
+	},
+}
+
+/// Result of deduplicated task.
+trait DeduplicatedResult {
+	/// Returns true if result may be returned to the late retries of the same task.
+	fn is_cacheable(&self) -> bool;
+}
+
+impl<K> DeduplicatingKeyServer<K> {
+	/// Create new deduplicating key server.
+	pub fn new(key_server: K, cache_duration: Duration) -> Self {
+		DeduplicatingKeyServer {
+			key_server,
+			generate_key: Deduplicator::new(cache_duration),
+			restore_key_public: Deduplicator::new(cache_duration),
+			store_document_key: Deduplicator::new(cache_duration),
+			generate_document_key: Deduplicator::new(cache_duration),
+			restore_document_key: Deduplicator::new(cache_duration),
+			restore_document_key_shadow: Deduplicator::new(cache_duration),
+			sign_message_schnorr: Deduplicator::new(cache_duration),
+			sign_message_ecdsa: Deduplicator::new(cache_duration),
+			change_servers_set: Deduplicator::new(cache_duration),
+		}
+	}
+
+	/// Get reference to the inner key server.
+	pub fn inner(&self) -> &K {
+		&self.key_server
+	}
+
+	/// Forget expired results and tasks that have been abandoned by all callers.
+	/// This is called on every deduplicated request, but could also be called
+	/// periodically to free memory when key server is idle.
+	pub fn prune(&self) {
+		self.generate_key.prune();
+		self.restore_key_public.prune();
+		self.store_document_key.prune();
+		self.generate_document_key.prune();
+		self.restore_document_key.prune();
+		self.restore_document_key_shadow.prune();
+		self.sign_message_schnorr.prune();
+		self.sign_message_ecdsa.prune();
+		self.change_servers_set.prune();
+	}
+
+	/// Prune all deduplicators and then run task using given deduplicator.
+	fn deduplicate<T, F>(
+		&self,
+		deduplicator: &Deduplicator<T>,
+		task: ServiceTask,
+		start: impl FnOnce() -> F,
+	) -> BoxFuture<'static, T> where
+		T: DeduplicatedResult + Clone + Send + Sync + 'static,
+		F: Future<Output = T> + Send + 'static,
+	{
+		self.prune();
+		deduplicator.run(task, start)
+	}
+}
+
+impl<P, R> DeduplicatedResult for SessionResult<P, R> {
+	fn is_cacheable(&self) -> bool {
+		match self.result {
+			Err(ref error) => !error.is_non_fatal(),
+			Ok(_) => true,
+		}
+	}
+}
+
+impl<T: DeduplicatedResult + Clone + Send + Sync + 'static> Deduplicator<T> {
+	/// Create new deduplicator.
+	fn new(cache_duration: Duration) -> Self {
+		Deduplicator {
+			cache_duration,
+			next_task_id: AtomicU64::new(0),
+			tasks: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Forget tasks that have been abandoned by all callers and results that have expired.
+	fn prune(&self) {
+		let now = Instant::now();
+		let cache_duration = self.cache_duration;
+		self.tasks.lock().retain(|_, task| match *task {
+			DeduplicatedTask::Active { ref future, .. } => future.upgrade().is_some(),
+			DeduplicatedTask::Completed { completed_at, .. } => now.duration_since(completed_at) < cache_duration,
+		});
+	}
+
+	/// Attach to the active (or recently completed) task, or start new task using `start`.
+	fn run<F>(&self, task: ServiceTask, start: impl FnOnce() -> F) -> BoxFuture<'static, T> where
+		F: Future<Output = T> + Send + 'static,
+	{
+		let mut tasks = self.tasks.lock();
+		match tasks.get(&task) {
+			Some(DeduplicatedTask::Active { ref future, .. }) => if let Some(future) = future.upgrade() {
+				return future.boxed();
+			},
+			Some(DeduplicatedTask::Completed { ref result, .. }) => return future::ready(result.clone()).boxed(),
+			None => (),
+		}
+
+		let id = self.next_task_id.fetch_add(1, Ordering::Relaxed);
+		let future = start();
+		let future = {
+			let tasks = self.tasks.clone();
+			let task = task.clone();
+			async move {
+				let result = future.await;
+				let mut tasks = tasks.lock();
+				let is_same_task = match tasks.get(&task) {
+					Some(DeduplicatedTask::Active { id: active_id, .. }) => *active_id == id,
+					_ => false,
+				};
+				if is_same_task {
+					match result.is_cacheable() {
+						true => {
+							tasks.insert(task, DeduplicatedTask::Completed {
+								result: result.clone(),
+								completed_at: Instant::now(),
+							});
+						},
+						false => {
+							tasks.remove(&task);
+						},
+					}
+				}
+				result
+			}
+		}.boxed().shared();
+		tasks.insert(task, DeduplicatedTask::Active {
+			id,
+			future: future.downgrade().expect("future has just been created and not polled; qed"),
+		});
+
+		future.boxed()
+	}
+}
+
+impl<K> ServerKeyGenerator for DeduplicatingKeyServer<K> where
+	K: ServerKeyGenerator,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+{
+	type GenerateKeyFuture = BoxFuture<'static, ServerKeyGenerationResult>;
+	type RestoreKeyFuture = BoxFuture<'static, ServerKeyRetrievalResult>;
+	type SupportsEcdsaFuture = K::SupportsEcdsaFuture;
+	type KeyAuthorFuture = K::KeyAuthorFuture;
+
+	fn generate_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateKeyFuture {
+		self.deduplicate(
+			&self.generate_key,
+			ServiceTask::GenerateServerKey(key_id, author.clone(), threshold),
+			|| self.key_server.generate_key(origin, key_id, author, threshold),
+		)
+	}
+
+	fn restore_key_public(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Option<Requester>,
+	) -> Self::RestoreKeyFuture {
+		self.deduplicate(
+			&self.restore_key_public,
+			ServiceTask::RetrieveServerKey(key_id, author.clone()),
+			|| self.key_server.restore_key_public(origin, key_id, author),
+		)
+	}
+
+	fn supports_ecdsa(&self, key_id: ServerKeyId, requester: Requester) -> Self::SupportsEcdsaFuture {
+		self.key_server.supports_ecdsa(key_id, requester)
+	}
+
+	fn key_author(&self, key_id: ServerKeyId, requester: Requester) -> Self::KeyAuthorFuture {
+		self.key_server.key_author(key_id, requester)
+	}
+}
+
+impl<K> DocumentKeyServer for DeduplicatingKeyServer<K> where
+	K: DocumentKeyServer,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::StoreDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyShadowFuture: 'static,
+{
+	type StoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyStoreResult>;
+	type GenerateDocumentKeyFuture = BoxFuture<'static, DocumentKeyGenerationResult>;
+	type GenerateDocumentKeyEncryptedFuture = K::GenerateDocumentKeyEncryptedFuture;
+	type RestoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonFuture = K::RestoreDocumentKeyCommonFuture;
+	type RestoreDocumentKeyShadowFuture = BoxFuture<'static, DocumentKeyShadowRetrievalResult>;
+	type RestoreDocumentKeyAtVersionFuture = K::RestoreDocumentKeyAtVersionFuture;
+	type RestoreDocumentKeyCommonManyFuture = K::RestoreDocumentKeyCommonManyFuture;
+	type RestoreDocumentKeyCommonPartialFuture = K::RestoreDocumentKeyCommonPartialFuture;
+	type TransferKeyOwnershipFuture = K::TransferKeyOwnershipFuture;
+
+	fn store_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		common_point: Public,
+		encrypted_document_key: Public,
+	) -> Self::StoreDocumentKeyFuture {
+		self.deduplicate(
+			&self.store_document_key,
+			ServiceTask::StoreDocumentKey(key_id, author.clone(), common_point, encrypted_document_key),
+			|| self.key_server.store_document_key(origin, key_id, author, common_point, encrypted_document_key),
+		)
+	}
+
+	fn generate_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyFuture {
+		self.deduplicate(
+			&self.generate_document_key,
+			ServiceTask::GenerateDocumentKey(key_id, author.clone(), threshold),
+			|| self.key_server.generate_document_key(origin, key_id, author, threshold),
+		)
+	}
+
+	fn generate_document_key_encrypted(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyEncryptedFuture {
+		self.key_server.generate_document_key_encrypted(origin, key_id, author, threshold)
+	}
+
+	fn restore_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyFuture {
+		self.deduplicate(
+			&self.restore_document_key,
+			ServiceTask::RetrieveDocumentKey(key_id, requester.clone()),
+			|| self.key_server.restore_document_key(origin, key_id, requester),
+		)
+	}
+
+	fn restore_document_key_at_version(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		version: H256,
+	) -> Self::RestoreDocumentKeyAtVersionFuture {
+		self.key_server.restore_document_key_at_version(origin, key_id, requester, version)
+	}
+
+	fn restore_document_key_common(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonFuture {
+		self.key_server.restore_document_key_common(origin, key_id, requester)
+	}
+
+	fn restore_document_key_common_many(
+		&self,
+		origin: Option<Origin>,
+		key_ids: &[ServerKeyId],
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonManyFuture {
+		self.key_server.restore_document_key_common_many(origin, key_ids, requester)
+	}
+
+	fn restore_document_key_common_partial(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonPartialFuture {
+		self.key_server.restore_document_key_common_partial(origin, key_id, requester)
+	}
+
+	fn restore_document_key_shadow(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyShadowFuture {
+		self.deduplicate(
+			&self.restore_document_key_shadow,
+			ServiceTask::RetrieveShadowDocumentKey(key_id, requester.clone()),
+			|| self.key_server.restore_document_key_shadow(origin, key_id, requester),
+		)
+	}
+
+	fn transfer_key_ownership(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		current_author: Requester,
+		new_author: Address,
+	) -> Self::TransferKeyOwnershipFuture {
+		self.key_server.transfer_key_ownership(origin, key_id, current_author, new_author)
+	}
+}
+
+impl<K> MessageSigner for DeduplicatingKeyServer<K> where
+	K: MessageSigner,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::SignMessageSchnorrFuture: 'static,
+	K::SignMessageEcdsaFuture: 'static,
+{
+	type SignMessageSchnorrFuture = BoxFuture<'static, SchnorrSigningResult>;
+	type SignMessageEcdsaFuture = BoxFuture<'static, EcdsaSigningResult>;
+	type SignMessageFuture = K::SignMessageFuture;
+
+	fn sign_message_schnorr(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageSchnorrFuture {
+		self.deduplicate(
+			&self.sign_message_schnorr,
+			ServiceTask::SchnorrSignMessage(key_id, requester.clone(), message),
+			|| self.key_server.sign_message_schnorr(origin, key_id, requester, message),
+		)
+	}
+
+	fn sign_message_ecdsa(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageEcdsaFuture {
+		self.deduplicate(
+			&self.sign_message_ecdsa,
+			ServiceTask::EcdsaSignMessage(key_id, requester.clone(), message),
+			|| self.key_server.sign_message_ecdsa(origin, key_id, requester, message),
+		)
+	}
+
+	fn sign_message(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> Self::SignMessageFuture {
+		self.key_server.sign_message(origin, key_id, requester, message, scheme)
+	}
+}
+
+impl<K> AdminSessionsServer for DeduplicatingKeyServer<K> where
+	K: AdminSessionsServer,
+	K::ChangeServersSetFuture: 'static,
+{
+	type ChangeServersSetFuture = BoxFuture<'static, ServersSetChangeResult>;
+	type PlanServersSetChangeFuture = K::PlanServersSetChangeFuture;
+
+	fn change_servers_set(
+		&self,
+		origin: Option<Origin>,
+		old_set_signature: Signature,
+		new_set_signature: Signature,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::ChangeServersSetFuture {
+		self.deduplicate(
+			&self.change_servers_set,
+			ServiceTask::ChangeServersSet(old_set_signature.clone(), new_set_signature.clone(), new_servers_set.clone()),
+			|| self.key_server.change_servers_set(origin, old_set_signature, new_set_signature, new_servers_set),
+		)
+	}
+
+	fn plan_servers_set_change(
+		&self,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::PlanServersSetChangeFuture {
+		self.key_server.plan_servers_set_change(new_servers_set)
+	}
+}
+
+impl<K> KeyServer for DeduplicatingKeyServer<K> where
+	K: KeyServer,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::StoreDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyShadowFuture: 'static,
+	K::SignMessageSchnorrFuture: 'static,
+	K::SignMessageEcdsaFuture: 'static,
+	K::ChangeServersSetFuture: 'static,
+{
+	fn capabilities(&self) -> KeyServerCapabilities {
+		self.key_server.capabilities()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::{executor::block_on, future::join};
+	use parity_crypto::publickey::{Generator, Random};
+	use crate::{error::Error, testing::MockKeyServer};
+	use super::*;
+
+	#[test]
+	fn concurrent_identical_requests_are_deduplicated() {
+		let key_server = DeduplicatingKeyServer::new(MockKeyServer::new(), Duration::from_secs(60));
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		// mock key server fails to generate the same key twice
+		let (result1, result2) = block_on(join(
+			key_server.generate_key(None, key_id, requester.clone(), 0),
+			key_server.generate_key(None, key_id, requester.clone(), 0),
+		));
+		let key = result1.result.unwrap().key;
+		assert_eq!(result2.result.unwrap().key, key);
+		assert_eq!(key_server.inner().started_sessions(), 1);
+
+		// late retry receives cached result
+		let result3 = block_on(key_server.generate_key(None, key_id, requester.clone(), 0));
+		assert_eq!(result3.result.unwrap().key, key);
+
+		// different request isn't deduplicated
+		let result4 = block_on(key_server.generate_key(None, key_id, requester, 1));
+		assert_eq!(result4.result.map(|_| ()), Err(Error::NotEnoughNodesForThreshold));
+	}
+
+	#[test]
+	fn cached_result_expires() {
+		let key_server = DeduplicatingKeyServer::new(MockKeyServer::new(), Duration::from_secs(0));
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap();
+		assert_eq!(
+			block_on(key_server.generate_key(None, key_id, requester, 0)).result.map(|_| ()),
+			Err(Error::ServerKeyAlreadyGenerated),
+		);
+	}
+
+	#[test]
+	fn non_fatal_failure_is_not_cached() {
+		let key_server = DeduplicatingKeyServer::new(MockKeyServer::new(), Duration::from_secs(60));
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		key_server.inner().fail_next_sessions(vec![Error::ConsensusTemporaryUnreachable]);
+		assert_eq!(
+			block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.map(|_| ()),
+			Err(Error::ConsensusTemporaryUnreachable),
+		);
+		assert!(block_on(key_server.generate_key(None, key_id, requester, 0)).result.is_ok());
+		assert_eq!(key_server.inner().started_sessions(), 2);
+	}
+
+	#[test]
+	fn abandoned_task_is_pruned() {
+		let key_server = DeduplicatingKeyServer::new(MockKeyServer::new(), Duration::from_secs(60));
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		drop(key_server.generate_key(None, key_id, requester.clone(), 0));
+		key_server.prune();
+		assert!(key_server.generate_key.tasks.lock().is_empty());
+
+		assert!(block_on(key_server.generate_key(None, key_id, requester, 0)).result.is_ok());
+		assert_eq!(key_server.inner().started_sessions(), 2);
+	}
+
+	#[test]
+	fn expired_results_are_pruned_by_other_operations() {
+		let key_server = DeduplicatingKeyServer::new(MockKeyServer::new(), Duration::from_secs(0));
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap();
+		assert_eq!(key_server.generate_key.tasks.lock().len(), 1);
+
+		block_on(key_server.restore_key_public(None, key_id, Some(requester))).result.unwrap();
+		assert!(key_server.generate_key.tasks.lock().is_empty());
+	}
+}
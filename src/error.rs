@@ -18,7 +18,7 @@ use std::fmt;
 use std::net;
 use std::io::Error as IoError;
 use serde::{Serialize, Deserialize};
-use crate::KeyServerId;
+use crate::{KeyServerId, ServerKeyId};
 
 /// Secret store error.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -49,6 +49,12 @@ pub enum Error {
 	InvalidMessageVersion,
 	/// Message is invalid because of replay-attack protection.
 	ReplayProtection,
+	/// Service request with the same nonce has already been processed.
+	ReplayedRequest,
+	/// Service request deadline has passed.
+	RequestExpired,
+	/// Operation has been cancelled by the caller.
+	Cancelled,
 	/// Connection to node, required for this session is not established.
 	NodeDisconnected,
 	/// Server key with this ID is already generated.
@@ -59,6 +65,18 @@ pub enum Error {
 	DocumentKeyAlreadyStored,
 	/// Document key with this ID is not yet stored.
 	DocumentKeyIsNotFound,
+	/// Key share version with given hash is not found.
+	UnknownKeyVersion,
+	/// Key with this ID has been removed and can't be stored again unless forced.
+	KeyTombstoned,
+	/// Service task is missing required field or has invalid field value.
+	InvalidServiceTask(String),
+	/// Requested servers set can't be used as the new servers set.
+	InvalidServersSet(String),
+	/// Key shares from different nodes are not describing the same key.
+	ConflictingKeyShares(String),
+	/// Key share is missing required data or has invalid field value.
+	InvalidKeyShare(String),
 	/// Consensus is temporary unreachable. Means that something is currently blocking us from either forming
 	/// consensus group (like disconnecting from too many nodes, which are AGREE to participate in consensus)
 	/// or from rejecting request (disconnecting from AccessDenied-nodes).
@@ -68,24 +86,54 @@ pub enum Error {
 	/// of 100 nodes. Key threshold is 6 (i.e. 7 nodes are required for consensus). 4 nodes are responding with
 	/// reject => consensus is considered unreachable, even though another 90 nodes still can respond with OK.
 	ConsensusUnreachable,
-	/// Acl storage error.
+	/// Requester has no access to the key.
 	AccessDenied,
+	/// Can't start session, because there are too many active sessions.
+	TooManySessions,
 	/// Can't start session, because exclusive session is active.
 	ExclusiveSessionActive,
 	/// Can't start exclusive session, because there are other active sessions.
 	HasActiveSessions,
 	/// Insufficient requester data.
 	InsufficientRequesterData(String),
+	/// ECDSA signature can't be generated, because key threshold is too high for
+	/// current number of nodes. ECDSA signing requires at least `2 * threshold + 1` nodes.
+	EcdsaThresholdTooHigh {
+		/// Threshold of the server key.
+		threshold: usize,
+		/// Number of nodes in the current set.
+		node_count: usize,
+	},
+	/// Not enough key shares have been received to restore the key.
+	NotEnoughShares {
+		/// Number of received key shares.
+		have: usize,
+		/// Number of key shares, required to restore the key.
+		need: usize,
+	},
 	/// Cryptographic error.
 	EthKey(String),
 	/// I/O error has occurred.
 	Io(String),
 	/// Deserialization error has occurred.
 	Serde(String),
+	/// Serialized data has unsupported version.
+	UnsupportedSerializationVersion(u8),
+	/// Document key is encrypted using unsupported scheme.
+	UnsupportedEncryptionScheme(u8),
 	/// Hyper error.
 	Hyper(String),
 	/// Database-related error.
 	Database(String),
+	/// Key storage operation has failed.
+	Storage {
+		/// Name of the failed operation.
+		op: String,
+		/// Id of the key that the operation has been called for.
+		key_id: ServerKeyId,
+		/// Underlying error.
+		error: Box<Error>,
+	},
 	/// Internal error.
 	Internal(String),
 }
@@ -105,11 +153,16 @@ impl Error {
 			// invalid message errors => restarting/updating/excluding node is a solution
 			Error::InvalidMessage | Error::InvalidMessageVersion | Error::ReplayProtection |
 			// connectivity problems => waiting for reconnect && restarting session is a solution
-			Error::NodeDisconnected |
+			Error::NodeDisconnected | Error::NotEnoughShares { .. } |
 			// temporary (?) consensus problems, related to other non-fatal errors => restarting is probably (!) a solution
 			Error::ConsensusTemporaryUnreachable |
 			// exclusive session errors => waiting && restarting is a solution
-			Error::ExclusiveSessionActive | Error::HasActiveSessions => true,
+			Error::ExclusiveSessionActive | Error::HasActiveSessions |
+			// overload errors => waiting && restarting is a solution
+			Error::TooManySessions => true,
+
+			// storage errors are as fatal as underlying errors
+			Error::Storage { ref error, .. } => error.is_non_fatal(),
 
 			// fatal errors:
 
@@ -117,12 +170,34 @@ impl Error {
 			Error::InvalidNodeAddress | Error::InvalidNodeId(_) |
 			// wrong session input params errors
 			Error::NotEnoughNodesForThreshold | Error::ServerKeyAlreadyGenerated | Error::ServerKeyIsNotFound |
-				Error::DocumentKeyAlreadyStored | Error::DocumentKeyIsNotFound | Error::InsufficientRequesterData(_) |
+				Error::DocumentKeyAlreadyStored | Error::DocumentKeyIsNotFound | Error::UnknownKeyVersion |
+				Error::KeyTombstoned |
+				Error::InsufficientRequesterData(_) | Error::InvalidServiceTask(_) | Error::InvalidServersSet(_) |
+				Error::EcdsaThresholdTooHigh { .. } |
+			// key shares from different nodes are inconsistent
+			Error::ConflictingKeyShares(_) | Error::InvalidKeyShare(_) |
+			// replayed, expired or cancelled service requests
+			Error::ReplayedRequest | Error::RequestExpired | Error::Cancelled |
 			// access denied/consensus error
 			Error::AccessDenied | Error::ConsensusUnreachable |
 			// indeterminate internal errors, which could be either fatal (db failure, invalid request), or not (network error),
 			// but we still consider these errors as fatal
-			Error::EthKey(_) | Error::Serde(_) | Error::Hyper(_) | Error::Database(_) | Error::Internal(_) | Error::Io(_) => false,
+			Error::EthKey(_) | Error::Serde(_) | Error::UnsupportedSerializationVersion(_) | Error::UnsupportedEncryptionScheme(_) | Error::Hyper(_) | Error::Database(_) | Error::Internal(_) | Error::Io(_) => false,
+		}
+	}
+}
+
+impl Error {
+	/// Attach key storage operation context to the error. If the error already has
+	/// the context (i.e. it has been attached by the wrapped storage), it is preserved.
+	pub fn with_key_context(self, op: &str, key_id: &ServerKeyId) -> Error {
+		match self {
+			Error::Storage { .. } => self,
+			error => Error::Storage {
+				op: op.into(),
+				key_id: *key_id,
+				error: Box::new(error),
+			},
 		}
 	}
 }
@@ -141,21 +216,47 @@ impl fmt::Display for Error {
 			Error::InvalidMessage => write!(f, "invalid message is received"),
 			Error::InvalidMessageVersion => write!(f, "unsupported message is received"),
 			Error::ReplayProtection => write!(f, "replay message is received"),
+			Error::ReplayedRequest => write!(f, "request with the same nonce has already been processed"),
+			Error::RequestExpired => write!(f, "request deadline has passed"),
+			Error::Cancelled => write!(f, "operation has been cancelled"),
 			Error::NodeDisconnected => write!(f, "node required for this operation is currently disconnected"),
 			Error::ServerKeyAlreadyGenerated => write!(f, "Server key with this ID is already generated"),
 			Error::ServerKeyIsNotFound => write!(f, "Server key with this ID is not found"),
 			Error::DocumentKeyAlreadyStored => write!(f, "Document key with this ID is already stored"),
 			Error::DocumentKeyIsNotFound => write!(f, "Document key with this ID is not found"),
+			Error::UnknownKeyVersion => write!(f, "Key version with this hash is not found"),
+			Error::KeyTombstoned => write!(f, "Key with this ID has been removed"),
+			Error::InvalidServiceTask(ref msg) => write!(f, "Invalid service task: {}", msg),
+			Error::InvalidServersSet(ref msg) => write!(f, "Invalid servers set: {}", msg),
+			Error::ConflictingKeyShares(ref msg) => write!(f, "Conflicting key shares: {}", msg),
+			Error::InvalidKeyShare(ref msg) => write!(f, "Invalid key share: {}", msg),
 			Error::ConsensusUnreachable => write!(f, "Consensus unreachable"),
 			Error::ConsensusTemporaryUnreachable => write!(f, "Consensus temporary unreachable"),
 			Error::AccessDenied => write!(f, "Access denied"),
+			Error::TooManySessions => write!(f, "Too many active sessions"),
 			Error::ExclusiveSessionActive => write!(f, "Exclusive session active"),
 			Error::HasActiveSessions => write!(f, "Unable to start exclusive session"),
 			Error::InsufficientRequesterData(ref e) => write!(f, "Insufficient requester data: {}", e),
+			Error::EcdsaThresholdTooHigh { threshold, node_count } => write!(
+				f,
+				"ECDSA signing with threshold {} requires at least {} nodes, but there are only {}",
+				threshold,
+				2 * threshold + 1,
+				node_count,
+			),
+			Error::NotEnoughShares { have, need } => write!(
+				f,
+				"not enough nodes available: {} key shares are required, but only {} have been received",
+				need,
+				have,
+			),
 			Error::EthKey(ref e) => write!(f, "cryptographic error {}", e),
 			Error::Hyper(ref msg) => write!(f, "Hyper error: {}", msg),
 			Error::Serde(ref msg) => write!(f, "Serialization error: {}", msg),
+			Error::UnsupportedSerializationVersion(version) => write!(f, "Unsupported serialization version: {}", version),
+			Error::UnsupportedEncryptionScheme(scheme) => write!(f, "Unsupported encryption scheme: {}", scheme),
 			Error::Database(ref msg) => write!(f, "Database error: {}", msg),
+			Error::Storage { ref op, ref key_id, ref error } => write!(f, "Key storage {} of key {:?} has failed: {}", op, key_id, error),
 			Error::Internal(ref msg) => write!(f, "Internal error: {}", msg),
 			Error::Io(ref msg) => write!(f, "IO error: {}", msg),
 		}
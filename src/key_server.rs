@@ -16,18 +16,47 @@
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::future::Future;
+use futures::{FutureExt, StreamExt, future::{self, BoxFuture, Either}, stream::{self, BoxStream}};
 use ethereum_types::{Address, H160, H256};
-use parity_crypto::publickey::{Public, Signature};
+use parity_bytes::Bytes;
+use parity_crypto::publickey::{Public, Secret, Signature, ec_math_utils, recover, verify_public};
+use tiny_keccak::{Hasher, Keccak};
 use crate::{
 	KeyServerId, KeyServerPublic, ServerKeyId,
+	cancellation::CancellationToken,
 	error::Error,
+	key_server_set::MigrationId,
+	key_storage::KeyStorage,
 	requester::Requester,
 };
 
 /// Session origin.
 pub type Origin = H160;
 
+/// Every session has its own unique id.
+pub type SessionId = H256;
+
+/// Scheme that has been used to encrypt document key with requester public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionScheme {
+	/// ECIES with default MAC (see `encrypt_for_requester`).
+	Ecies,
+}
+
+/// Document key, encrypted with requester public key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptedDocumentKey {
+	/// Encryption scheme.
+	pub scheme: EncryptionScheme,
+	/// Encrypted document key.
+	pub ciphertext: Bytes,
+}
+
+/// Common point of the document key encryption (`k * G`).
+pub type CommonPoint = Public;
+
 /// Session result.
+#[derive(Clone)]
 pub struct SessionResult<P, R> {
 	/// Session origin.
 	pub origin: Option<Origin>,
@@ -37,6 +66,68 @@ pub struct SessionResult<P, R> {
 	pub result: Result<R, Error>,
 }
 
+/// Update of the session state.
+#[derive(Clone)]
+pub enum SessionUpdate<R> {
+	/// Session is initializing.
+	Initializing,
+	/// Session is waiting for confirmations from other nodes: (number of confirmations, total number of nodes).
+	AwaitingConfirmations(usize, usize),
+	/// Session is computing result.
+	Finalizing,
+	/// Session has completed successfully. This is the terminal update.
+	Completed(R),
+	/// Session has failed. This is the terminal update.
+	Failed(Error),
+}
+
+/// Stream of session updates.
+pub type SessionUpdates<R> = BoxStream<'static, SessionUpdate<R>>;
+
+impl<P, R> From<SessionResult<P, R>> for SessionUpdate<R> {
+	fn from(result: SessionResult<P, R>) -> Self {
+		match result.result {
+			Ok(artifacts) => SessionUpdate::Completed(artifacts),
+			Err(error) => SessionUpdate::Failed(error),
+		}
+	}
+}
+
+/// Convert one-shot session future into the stream of session updates.
+pub fn session_updates<P, R, F>(future: F) -> SessionUpdates<R> where
+	F: Future<Output = SessionResult<P, R>> + Send + 'static,
+	P: 'static,
+	R: Send + 'static,
+{
+	Box::pin(stream::once(async { SessionUpdate::Initializing })
+		.chain(stream::once(future.map(Into::into))))
+}
+
+/// Make session future cancellable. When token is cancelled before session is completed,
+/// the session future is dropped (thus aborting the session) and `Error::Cancelled` is returned.
+pub fn cancellable<P, R, F>(
+	origin: Option<Origin>,
+	params: P,
+	future: F,
+	token: CancellationToken,
+) -> BoxFuture<'static, SessionResult<P, R>> where
+	F: Future<Output = SessionResult<P, R>> + Send + 'static,
+	P: Send + 'static,
+	R: Send + 'static,
+{
+	Box::pin(future::select(token.cancelled(), Box::pin(future)).map(move |result| match result {
+		Either::Left((_, session)) => {
+			drop(session);
+			SessionResult {
+				origin,
+				params,
+				result: Err(Error::Cancelled),
+			}
+		},
+		Either::Right((result, _)) => result,
+	}))
+}
+
 /// Essential server key generation params.
 #[derive(Clone)]
 pub struct ServerKeyGenerationParams {
@@ -49,6 +140,10 @@ pub struct ServerKeyGenerationParams {
 pub struct ServerKeyGenerationArtifacts {
 	/// Public portion of generated server key.
 	pub key: Public,
+	/// Key servers that have participated in generation session.
+	pub participants: BTreeSet<KeyServerId>,
+	/// Hash of the generated key share version.
+	pub version: H256,
 }
 
 /// Result of server key generation session.
@@ -81,6 +176,10 @@ pub trait ServerKeyGenerator {
 	type GenerateKeyFuture: Future<Output = ServerKeyGenerationResult> + Send;
 	/// SK restore future.
 	type RestoreKeyFuture: Future<Output = ServerKeyRetrievalResult> + Send;
+	/// ECDSA support check future.
+	type SupportsEcdsaFuture: Future<Output = Result<bool, Error>> + Send;
+	/// Key author query future.
+	type KeyAuthorFuture: Future<Output = Result<Option<Address>, Error>> + Send;
 
 	/// Generate new SK.
 	/// `key_id` is the caller-provided identifier of generated SK.
@@ -104,6 +203,26 @@ pub trait ServerKeyGenerator {
 		key_id: ServerKeyId,
 		author: Option<Requester>,
 	) -> Self::RestoreKeyFuture;
+	/// Check if ECDSA signatures could be generated using the given SK with the current set of
+	/// key servers (see `check_ecdsa_threshold`). This is a read-only operation that doesn't
+	/// start any sessions. `requester` must have access to the key, so that the existence of
+	/// the key isn't revealed to everyone.
+	fn supports_ecdsa(&self, key_id: ServerKeyId, requester: Requester) -> Self::SupportsEcdsaFuture;
+	/// Get author of the given SK, or `None` if the key is unknown. This is a read-only
+	/// operation that doesn't start any sessions. `requester` must have access to the key,
+	/// so that the existence of the key isn't revealed to everyone.
+	fn key_author(&self, key_id: ServerKeyId, requester: Requester) -> Self::KeyAuthorFuture;
+	/// The same as `generate_key`, but intermediate session state is reported.
+	/// Stream ends with either `SessionUpdate::Completed`, or `SessionUpdate::Failed`.
+	fn generate_key_with_updates(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> SessionUpdates<ServerKeyGenerationArtifacts> where Self::GenerateKeyFuture: 'static {
+		session_updates(self.generate_key(origin, key_id, author, threshold))
+	}
 }
 
 /// Essential document key store params.
@@ -120,6 +239,22 @@ pub struct DocumentKeyStoreArtifacts;
 /// Result of document key store session.
 pub type DocumentKeyStoreResult = SessionResult<DocumentKeyStoreParams, DocumentKeyStoreArtifacts>;
 
+/// Essential key ownership transfer params.
+#[derive(Clone)]
+pub struct KeyOwnershipTransferParams {
+	/// Key id.
+	pub key_id: ServerKeyId,
+	/// Address of the new author.
+	pub new_author: Address,
+}
+
+/// Key ownership transfer artifacts.
+#[derive(Clone)]
+pub struct KeyOwnershipTransferArtifacts;
+
+/// Result of key ownership transfer session.
+pub type KeyOwnershipTransferResult = SessionResult<KeyOwnershipTransferParams, KeyOwnershipTransferArtifacts>;
+
 /// Essential document key generation params.
 #[derive(Clone)]
 pub struct DocumentKeyGenerationParams {
@@ -137,6 +272,21 @@ pub struct DocumentKeyGenerationArtifacts {
 /// Result of document key generation session.
 pub type DocumentKeyGenerationResult = SessionResult<DocumentKeyGenerationParams, DocumentKeyGenerationArtifacts>;
 
+/// Encrypted document key generation artifacts.
+#[derive(Clone)]
+pub struct EncryptedDocumentKeyGenerationArtifacts {
+	/// Generated document key, encrypted with requester public key.
+	pub encrypted_document_key: EncryptedDocumentKey,
+	/// Common point of the stored document key.
+	pub common_point: CommonPoint,
+}
+
+/// Result of encrypted document key generation session.
+pub type EncryptedDocumentKeyGenerationResult = SessionResult<
+	DocumentKeyGenerationParams,
+	EncryptedDocumentKeyGenerationArtifacts,
+>;
+
 /// Essential document key retrieval params.
 #[derive(Clone)]
 pub struct DocumentKeyRetrievalParams {
@@ -149,8 +299,8 @@ pub struct DocumentKeyRetrievalParams {
 /// Document key retrieval artifacts.
 #[derive(Clone)]
 pub struct DocumentKeyRetrievalArtifacts {
-	/// Restored document key. UNENCRYPTED.
-	pub document_key: Public,
+	/// Restored document key, encrypted with requester public key.
+	pub encrypted_document_key: EncryptedDocumentKey,
 }
 
 /// Result of document key retrieval session.
@@ -184,6 +334,28 @@ pub type DocumentKeyCommonRetrievalResult = SessionResult<
 	DocumentKeyCommonRetrievalArtifacts,
 >;
 
+/// Partial document key common retrieval artifacts.
+///
+/// Returned when consensus over `threshold` has been reached, but `common_point`
+/// hasn't been confirmed by enough key servers. It is up to the caller to decide
+/// whether this data is usable.
+#[derive(Clone)]
+pub struct DocumentKeyCommonPartialRetrievalArtifacts {
+	/// Threshold that has been used to generate associated server key.
+	pub threshold: usize,
+	/// The common point of portion of encrypted document keys, if it has been
+	/// confirmed by enough key servers.
+	pub common_point: Option<CommonPoint>,
+	/// Number of key servers that have confirmed the `common_point`.
+	pub confirmations: usize,
+}
+
+/// Result of partial document key common retrieval session.
+pub type DocumentKeyCommonPartialRetrievalResult = SessionResult<
+	DocumentKeyCommonRetrievalParams,
+	DocumentKeyCommonPartialRetrievalArtifacts,
+>;
+
 /// Essential document key shadow retrieval params.
 #[derive(Clone)]
 pub struct DocumentKeyShadowRetrievalParams {
@@ -212,6 +384,148 @@ pub struct DocumentKeyShadowRetrievalArtifacts {
 	pub participants_coefficients: BTreeMap<KeyServerId, Vec<u8>>,
 }
 
+/// Document key shadow: shadow coefficients of all key servers that have participated
+/// in decryption session, encrypted with requester public key.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DocumentKeyShadow {
+	coefficients: Vec<Vec<u8>>,
+}
+
+impl DocumentKeyShadow {
+	/// Get encrypted shadow coefficients.
+	pub fn coefficients(&self) -> &[Vec<u8>] {
+		&self.coefficients
+	}
+
+	/// Decrypt shadow coefficients using requester secret and compute their sum.
+	pub fn coefficients_sum(&self, requester_secret: &Secret) -> Result<Secret, Error> {
+		let mut shadows_sum: Option<Secret> = None;
+		for encrypted_shadow in &self.coefficients {
+			let shadow = crate::decrypt_with_secret(requester_secret, encrypted_shadow)?;
+			let shadow = Secret::import_key(&shadow)?;
+			match shadows_sum.as_mut() {
+				Some(shadows_sum) => shadows_sum.add(&shadow)?,
+				None => shadows_sum = Some(shadow),
+			}
+		}
+
+		shadows_sum.ok_or_else(|| Error::InsufficientRequesterData("no shadow coefficients".into()))
+	}
+
+	/// Encode shadow as number of coefficients (4 bytes, BE), followed by coefficients.
+	/// Every coefficient is encoded as its length (4 bytes, BE), followed by coefficient bytes.
+	pub fn encode(&self) -> Bytes {
+		let mut encoded = Vec::new();
+		encoded.extend_from_slice(&(self.coefficients.len() as u32).to_be_bytes());
+		for coefficient in &self.coefficients {
+			encoded.extend_from_slice(&(coefficient.len() as u32).to_be_bytes());
+			encoded.extend_from_slice(coefficient);
+		}
+		encoded
+	}
+
+	/// Decode shadow, encoded with `encode`.
+	pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+		fn read<'a>(encoded: &mut &'a [u8], size: usize) -> Result<&'a [u8], Error> {
+			if encoded.len() < size {
+				return Err(Error::InvalidMessage);
+			}
+
+			let (data, rest) = encoded.split_at(size);
+			*encoded = rest;
+			Ok(data)
+		}
+		fn read_u32(encoded: &mut &[u8]) -> Result<usize, Error> {
+			let mut data = [0u8; 4];
+			data.copy_from_slice(read(encoded, 4)?);
+			Ok(u32::from_be_bytes(data) as usize)
+		}
+
+		let mut encoded = encoded;
+		let coefficients_count = read_u32(&mut encoded)?;
+		let mut coefficients = Vec::new();
+		for _ in 0..coefficients_count {
+			let coefficient_len = read_u32(&mut encoded)?;
+			coefficients.push(read(&mut encoded, coefficient_len)?.to_vec());
+		}
+
+		if !encoded.is_empty() {
+			return Err(Error::InvalidMessage);
+		}
+
+		Ok(DocumentKeyShadow { coefficients })
+	}
+}
+
+impl From<Vec<Vec<u8>>> for DocumentKeyShadow {
+	fn from(coefficients: Vec<Vec<u8>>) -> Self {
+		DocumentKeyShadow { coefficients }
+	}
+}
+
+/// Shadow with single coefficient (i.e. shadow, computed by the single key server).
+impl From<Vec<u8>> for DocumentKeyShadow {
+	fn from(coefficient: Vec<u8>) -> Self {
+		DocumentKeyShadow { coefficients: vec![coefficient] }
+	}
+}
+
+impl DocumentKeyShadowRetrievalArtifacts {
+	/// Get portion of artifacts that is the same among all key servers. Shadow
+	/// coefficients and partially decrypted document key are not included.
+	pub fn common(&self) -> DocumentKeyCommonRetrievalArtifacts {
+		DocumentKeyCommonRetrievalArtifacts {
+			common_point: self.common_point,
+			threshold: self.threshold,
+		}
+	}
+
+	/// Merge artifacts, received from other key server. Common point, threshold and partially
+	/// decrypted document key of both artifacts must be the same. Coefficients of participants
+	/// that are unknown to this artifacts are added.
+	pub fn merge(&mut self, other: &Self) -> Result<(), Error> {
+		if self.common_point != other.common_point {
+			return Err(Error::ConflictingKeyShares("common point mismatch".into()));
+		}
+		if self.threshold != other.threshold {
+			return Err(Error::ConflictingKeyShares("threshold mismatch".into()));
+		}
+		if self.encrypted_document_key != other.encrypted_document_key {
+			return Err(Error::ConflictingKeyShares("encrypted document key mismatch".into()));
+		}
+
+		// check everything before modifying, so that the artifacts are left untouched on error
+		for (participant, other_coefficient) in &other.participants_coefficients {
+			match self.participants_coefficients.get(participant) {
+				Some(coefficient) if coefficient != other_coefficient => return Err(Error::ConflictingKeyShares(
+					format!("shadow coefficient of node {:?} mismatch", participant),
+				)),
+				_ => (),
+			}
+		}
+
+		self.participants_coefficients.extend(
+			other.participants_coefficients.iter().map(|(participant, coefficient)| (*participant, coefficient.clone())),
+		);
+		Ok(())
+	}
+
+	/// Get document key shadow, made of coefficients of all participants.
+	pub fn shadow(&self) -> DocumentKeyShadow {
+		self.participants_coefficients.values().cloned().collect::<Vec<_>>().into()
+	}
+
+	/// Decrypt document key using requester secret. See `DocumentKeyServer::restore_document_key_shadow`
+	/// for details.
+	pub fn decrypt(&self, requester_secret: &Secret) -> Result<Public, Error> {
+		let shadows_sum = self.shadow().coefficients_sum(requester_secret)?;
+		let mut document_key = self.common_point;
+		ec_math_utils::public_mul_secret(&mut document_key, &shadows_sum)?;
+		ec_math_utils::public_add(&mut document_key, &self.encrypted_document_key)?;
+		Ok(document_key)
+	}
+}
+
 /// Result of document key shadow retrieval session.
 pub type DocumentKeyShadowRetrievalResult = SessionResult<
 	DocumentKeyShadowRetrievalParams,
@@ -224,12 +538,22 @@ pub trait DocumentKeyServer: ServerKeyGenerator {
 	type StoreDocumentKeyFuture: Future<Output = DocumentKeyStoreResult> + Send;
 	/// DK generation future.
 	type GenerateDocumentKeyFuture: Future<Output = DocumentKeyGenerationResult> + Send;
+	/// Encrypted DK generation future.
+	type GenerateDocumentKeyEncryptedFuture: Future<Output = EncryptedDocumentKeyGenerationResult> + Send;
 	/// DK restore future.
 	type RestoreDocumentKeyFuture: Future<Output = DocumentKeyRetrievalResult> + Send;
 	/// DK common part restore future.
 	type RestoreDocumentKeyCommonFuture: Future<Output = DocumentKeyCommonRetrievalResult> + Send;
 	/// DK shadow restore future.
 	type RestoreDocumentKeyShadowFuture: Future<Output = DocumentKeyShadowRetrievalResult> + Send;
+	/// DK restore at given key version future.
+	type RestoreDocumentKeyAtVersionFuture: Future<Output = DocumentKeyRetrievalResult> + Send;
+	/// DK common part bulk restore future.
+	type RestoreDocumentKeyCommonManyFuture: Future<Output = Vec<DocumentKeyCommonRetrievalResult>> + Send;
+	/// DK common part partial restore future.
+	type RestoreDocumentKeyCommonPartialFuture: Future<Output = DocumentKeyCommonPartialRetrievalResult> + Send;
+	/// Key ownership transfer future.
+	type TransferKeyOwnershipFuture: Future<Output = KeyOwnershipTransferResult> + Send;
 
 	/// Store externally generated DK.
 	/// `key_id` is identifier of previously generated SK.
@@ -251,7 +575,8 @@ pub trait DocumentKeyServer: ServerKeyGenerator {
 	/// `key_id` is the caller-provided identifier of generated SK.
 	/// `author` is the author of server && document key entry.
 	/// `threshold + 1` is the minimal number of nodes, required to restore private key.
-	/// Result is a DK, encrypted with caller public key.
+	/// WARNING: result is an UNENCRYPTED DK, so it is unsafe to pass it anywhere.
+	/// Consider using `generate_document_key_encrypted` instead.
 	fn generate_document_key(
 		&self,
 		origin: Option<Origin>,
@@ -259,6 +584,17 @@ pub trait DocumentKeyServer: ServerKeyGenerator {
 		author: Requester,
 		threshold: usize,
 	) -> Self::GenerateDocumentKeyFuture;
+	/// Generate and store both SK and DK. The same as `generate_document_key`, but
+	/// the DK is encrypted with author public key, so that it never leaves key server
+	/// in plain form.
+	/// Result is a DK, encrypted with author public key, and common point of the stored DK.
+	fn generate_document_key_encrypted(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyEncryptedFuture;
 	/// Restore previously stored DK.
 	/// DK is decrypted on the key server (which might be considered unsafe), and then encrypted with caller public key.
 	/// `key_id` is identifier of previously generated SK.
@@ -270,6 +606,34 @@ pub trait DocumentKeyServer: ServerKeyGenerator {
 		key_id: ServerKeyId,
 		requester: Requester,
 	) -> Self::RestoreDocumentKeyFuture;
+	/// The same as `restore_document_key`, but the session is aborted and `Error::Cancelled`
+	/// is returned when `token` is cancelled before the session is completed.
+	fn restore_document_key_cancellable(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		token: CancellationToken,
+	) -> BoxFuture<'static, DocumentKeyRetrievalResult> where Self::RestoreDocumentKeyFuture: 'static {
+		cancellable(
+			origin,
+			DocumentKeyRetrievalParams { key_id, requester: requester.clone() },
+			self.restore_document_key(origin, key_id, requester),
+			token,
+		)
+	}
+	/// Restore previously stored DK, using shares of given key version instead of the latest one.
+	/// This could be used to recover from partially applied servers set change.
+	/// `version` is the hash of key share version. If there's no such version,
+	/// `Error::UnknownKeyVersion` is returned.
+	/// Result is a DK, encrypted with caller public key.
+	fn restore_document_key_at_version(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		version: H256,
+	) -> Self::RestoreDocumentKeyAtVersionFuture;
 	/// Restore portion of DK that is the same among all key servers.
 	fn restore_document_key_common(
 		&self,
@@ -277,6 +641,25 @@ pub trait DocumentKeyServer: ServerKeyGenerator {
 		key_id: ServerKeyId,
 		requester: Requester,
 	) -> Self::RestoreDocumentKeyCommonFuture;
+	/// Restore portions of DKs that are the same among all key servers for several keys at once.
+	/// Every key is processed independently, so failure to restore one key doesn't affect others.
+	/// Results are returned in the same order as `key_ids`.
+	fn restore_document_key_common_many(
+		&self,
+		origin: Option<Origin>,
+		key_ids: &[ServerKeyId],
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonManyFuture;
+	/// Restore portion of DK that is the same among all key servers, tolerating
+	/// key servers that are unable to confirm the common point.
+	/// Unlike `restore_document_key_common`, this succeeds when consensus over `threshold`
+	/// is reached, even if `common_point` isn't confirmed by enough key servers.
+	fn restore_document_key_common_partial(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonPartialFuture;
 	/// Restore previously stored DK.
 	/// To decrypt DK on client:
 	/// 1) use requestor secret key to decrypt secret coefficients from result.decrypt_shadows
@@ -290,6 +673,16 @@ pub trait DocumentKeyServer: ServerKeyGenerator {
 		key_id: ServerKeyId,
 		requester: Requester,
 	) -> Self::RestoreDocumentKeyShadowFuture;
+	/// Transfer ownership of SK (and DK) to the new author.
+	/// `current_author` must be the current author of the key, otherwise `Error::AccessDenied` is returned.
+	/// ACL is not consulted: only the author is allowed to transfer ownership.
+	fn transfer_key_ownership(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		current_author: Requester,
+		new_author: Address,
+	) -> Self::TransferKeyOwnershipFuture;
 }
 
 /// Essential Schnorr signing params.
@@ -332,12 +725,46 @@ pub struct EcdsaSigningArtifacts {
 /// Result of ECDSA signing session.
 pub type EcdsaSigningResult = SessionResult<EcdsaSigningParams, EcdsaSigningArtifacts>;
 
+/// Signature scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+	/// Schnorr signature.
+	Schnorr,
+	/// ECDSA signature.
+	Ecdsa,
+}
+
+/// Essential signing params.
+#[derive(Clone)]
+pub struct SigningParams {
+	/// Key id.
+	pub key_id: ServerKeyId,
+	/// Key requester.
+	pub requester: Requester,
+	/// Requested signature scheme.
+	pub scheme: SignatureScheme,
+}
+
+/// Signing artifacts.
+#[derive(Clone)]
+pub enum SigningArtifacts {
+	/// Schnorr signing artifacts.
+	Schnorr(SchnorrSigningArtifacts),
+	/// ECDSA signing artifacts.
+	Ecdsa(EcdsaSigningArtifacts),
+}
+
+/// Result of signing session.
+pub type SigningResult = SessionResult<SigningParams, SigningArtifacts>;
+
 /// Message signer.
 pub trait MessageSigner: ServerKeyGenerator {
 	/// Schnorr signing future.
 	type SignMessageSchnorrFuture: Future<Output = SchnorrSigningResult> + Send;
 	/// ECDSA signing future.
 	type SignMessageEcdsaFuture: Future<Output = EcdsaSigningResult> + Send;
+	/// Signing future.
+	type SignMessageFuture: Future<Output = SigningResult> + Send;
 
 	/// Generate Schnorr signature for message with previously generated SK.
 	/// `key_id` is the caller-provided identifier of generated SK.
@@ -352,7 +779,7 @@ pub trait MessageSigner: ServerKeyGenerator {
 		message: H256,
 	) -> Self::SignMessageSchnorrFuture;
 	/// Generate ECDSA signature for message with previously generated SK.
-	/// WARNING: only possible when SK was generated using t <= 2 * N.
+	/// WARNING: only possible when SK was generated using 2 * t + 1 <= N (see `check_ecdsa_threshold`).
 	/// `key_id` is the caller-provided identifier of generated SK.
 	/// `signature` is `key_id`, signed with caller public key.
 	/// `message` is the hash of message to be signed.
@@ -364,17 +791,192 @@ pub trait MessageSigner: ServerKeyGenerator {
 		requester: Requester,
 		message: H256,
 	) -> Self::SignMessageEcdsaFuture;
+	/// Generate signature of given scheme for message with previously generated SK.
+	/// Same as `sign_message_schnorr` or `sign_message_ecdsa`, depending on the `scheme`.
+	fn sign_message(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> Self::SignMessageFuture;
+	/// The same as `sign_message`, but intermediate session state is reported.
+	/// Stream ends with either `SessionUpdate::Completed`, or `SessionUpdate::Failed`.
+	fn sign_message_with_updates(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> SessionUpdates<SigningArtifacts> where Self::SignMessageFuture: 'static {
+		session_updates(self.sign_message(origin, key_id, requester, message, scheme))
+	}
+}
+
+/// Plan of the servers set change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServersSetChangePlan {
+	/// Nodes that are not in the current set, but are in the new set.
+	pub joining: BTreeSet<KeyServerId>,
+	/// Nodes that are in the current set, but are not in the new set.
+	pub leaving: BTreeSet<KeyServerId>,
+	/// Nodes that are in both current and new set.
+	pub staying: BTreeSet<KeyServerId>,
+	/// Keys, which shares are going to be redistributed. These are all keys, which
+	/// last version shares are not owned exactly by nodes of the new set.
+	pub keys_to_reshare: BTreeSet<ServerKeyId>,
+}
+
+impl ServersSetChangePlan {
+	/// Compute plan of changing `current_set` to the `new_set` for all keys from the key storage.
+	pub fn new(
+		current_set: &BTreeSet<KeyServerId>,
+		new_set: &BTreeSet<KeyServerId>,
+		key_storage: &dyn KeyStorage,
+	) -> Result<Self, Error> {
+		let mut keys_to_reshare = BTreeSet::new();
+		for (key_id, key_share) in key_storage.iter() {
+			let last_version = key_share.last_version()?;
+			if !last_version.id_numbers.keys().eq(new_set.iter()) {
+				keys_to_reshare.insert(key_id);
+			}
+		}
+
+		Ok(ServersSetChangePlan {
+			joining: new_set.difference(current_set).cloned().collect(),
+			leaving: current_set.difference(new_set).cloned().collect(),
+			staying: current_set.intersection(new_set).cloned().collect(),
+			keys_to_reshare,
+		})
+	}
+}
+
+/// Check that the `new_set` could replace the current servers set without
+/// making any key from the key storage unrecoverable.
+///
+/// The set is rejected if it is empty or if it has less than `threshold + 1`
+/// nodes for some stored key. So the single-node set is only accepted while
+/// there are no keys with non-zero threshold.
+pub fn check_servers_set(
+	new_set: &BTreeSet<KeyServerPublic>,
+	key_storage: &dyn KeyStorage,
+) -> Result<(), Error> {
+	if new_set.is_empty() {
+		return Err(Error::InvalidServersSet("new servers set is empty".into()));
+	}
+
+	for (key_id, key_share) in key_storage.iter() {
+		if key_share.threshold >= new_set.len() {
+			return Err(Error::InvalidServersSet(format!(
+				"key {} with threshold {} requires at least {} nodes, but only {} are in the new set",
+				key_id,
+				key_share.threshold,
+				key_share.threshold + 1,
+				new_set.len(),
+			)));
+		}
+	}
+
+	Ok(())
+}
+
+/// Servers set change artifacts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServersSetChangeArtifacts {
+	/// Id of the completed migration.
+	pub migration_id: MigrationId,
+	/// Servers set after the change.
+	pub new_set: BTreeSet<KeyServerPublic>,
+	/// Number of keys, which shares have been redistributed.
+	pub keys_resharded: usize,
+}
+
+/// Result of servers set change session.
+pub type ServersSetChangeResult = SessionResult<(), ServersSetChangeArtifacts>;
+
+/// Artifacts of any session.
+#[derive(Clone)]
+pub enum Artifacts {
+	/// Server key generation artifacts.
+	ServerKeyGeneration(ServerKeyGenerationArtifacts),
+	/// Server key retrieval artifacts.
+	ServerKeyRetrieval(ServerKeyRetrievalArtifacts),
+	/// Document key store artifacts.
+	DocumentKeyStore(DocumentKeyStoreArtifacts),
+	/// Key ownership transfer artifacts.
+	KeyOwnershipTransfer(KeyOwnershipTransferArtifacts),
+	/// Document key generation artifacts.
+	DocumentKeyGeneration(DocumentKeyGenerationArtifacts),
+	/// Encrypted document key generation artifacts.
+	EncryptedDocumentKeyGeneration(EncryptedDocumentKeyGenerationArtifacts),
+	/// Document key retrieval artifacts.
+	DocumentKeyRetrieval(DocumentKeyRetrievalArtifacts),
+	/// Document key common portion retrieval artifacts.
+	DocumentKeyCommonRetrieval(DocumentKeyCommonRetrievalArtifacts),
+	/// Document key common portion partial retrieval artifacts.
+	DocumentKeyCommonPartialRetrieval(DocumentKeyCommonPartialRetrievalArtifacts),
+	/// Document key shadow retrieval artifacts.
+	DocumentKeyShadowRetrieval(DocumentKeyShadowRetrievalArtifacts),
+	/// Schnorr signing artifacts.
+	SchnorrSigning(SchnorrSigningArtifacts),
+	/// ECDSA signing artifacts.
+	EcdsaSigning(EcdsaSigningArtifacts),
+	/// Servers set change artifacts.
+	ServersSetChange(ServersSetChangeArtifacts),
+}
+
+macro_rules! impl_artifacts_from {
+	($($variant: ident($artifacts: ident)),*) => {
+		$(
+			impl From<$artifacts> for Artifacts {
+				fn from(artifacts: $artifacts) -> Self {
+					Artifacts::$variant(artifacts)
+				}
+			}
+		)*
+	}
+}
+
+impl_artifacts_from!(
+	ServerKeyGeneration(ServerKeyGenerationArtifacts),
+	ServerKeyRetrieval(ServerKeyRetrievalArtifacts),
+	DocumentKeyStore(DocumentKeyStoreArtifacts),
+	KeyOwnershipTransfer(KeyOwnershipTransferArtifacts),
+	DocumentKeyGeneration(DocumentKeyGenerationArtifacts),
+	EncryptedDocumentKeyGeneration(EncryptedDocumentKeyGenerationArtifacts),
+	DocumentKeyRetrieval(DocumentKeyRetrievalArtifacts),
+	DocumentKeyCommonRetrieval(DocumentKeyCommonRetrievalArtifacts),
+	DocumentKeyCommonPartialRetrieval(DocumentKeyCommonPartialRetrievalArtifacts),
+	DocumentKeyShadowRetrieval(DocumentKeyShadowRetrievalArtifacts),
+	SchnorrSigning(SchnorrSigningArtifacts),
+	EcdsaSigning(EcdsaSigningArtifacts),
+	ServersSetChange(ServersSetChangeArtifacts)
+);
+
+impl From<SigningArtifacts> for Artifacts {
+	fn from(artifacts: SigningArtifacts) -> Self {
+		match artifacts {
+			SigningArtifacts::Schnorr(artifacts) => Artifacts::SchnorrSigning(artifacts),
+			SigningArtifacts::Ecdsa(artifacts) => Artifacts::EcdsaSigning(artifacts),
+		}
+	}
 }
 
 /// Administrative sessions server.
 pub trait AdminSessionsServer {
 	/// Change servers set future.
-	type ChangeServersSetFuture: Future<Output = SessionResult<(), ()>> + Send;
+	type ChangeServersSetFuture: Future<Output = ServersSetChangeResult> + Send;
+	/// Servers set change planning future.
+	type PlanServersSetChangeFuture: Future<Output = Result<ServersSetChangePlan, Error>> + Send;
 
 	/// Change servers set so that nodes in new_servers_set became owners of shares for all keys.
 	/// And old nodes (i.e. cluster nodes except new_servers_set) have clear databases.
 	/// WARNING: newly generated keys will be distributed among all cluster nodes. So this session
 	/// must be followed with cluster nodes change (either via contract, or config files).
+	/// `old_set_signature` and `new_set_signature` are administrator signatures of
+	/// `service::servers_set_hash` of the current and the new set.
 	fn change_servers_set(
 		&self,
 		origin: Option<Origin>,
@@ -382,10 +984,244 @@ pub trait AdminSessionsServer {
 		new_set_signature: Signature,
 		new_servers_set: BTreeSet<KeyServerPublic>,
 	) -> Self::ChangeServersSetFuture;
+	/// Compute what would be changed if servers set is changed to the `new_servers_set`.
+	/// This is a read-only operation that doesn't start any sessions.
+	fn plan_servers_set_change(
+		&self,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::PlanServersSetChangeFuture;
+}
+
+/// Kind of key server session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SessionKind {
+	/// Server key generation session.
+	ServerKeyGeneration,
+	/// Server key retrieval session.
+	ServerKeyRetrieval,
+	/// Document key store session.
+	DocumentKeyStore,
+	/// Document key generation session.
+	DocumentKeyGeneration,
+	/// Document key retrieval session.
+	DocumentKeyRetrieval,
+	/// Document key common part retrieval session.
+	DocumentKeyCommonRetrieval,
+	/// Document key shadow retrieval session.
+	DocumentKeyShadowRetrieval,
+	/// Schnorr signing session.
+	SchnorrSigning,
+	/// ECDSA signing session.
+	EcdsaSigning,
+	/// Servers set change session.
+	ServersSetChange,
+	/// Key ownership transfer session.
+	KeyOwnershipTransfer,
+}
+
+impl SessionKind {
+	/// Get session kind code.
+	pub fn code(&self) -> u8 {
+		match *self {
+			SessionKind::ServerKeyGeneration => 1,
+			SessionKind::ServerKeyRetrieval => 2,
+			SessionKind::DocumentKeyStore => 3,
+			SessionKind::DocumentKeyGeneration => 4,
+			SessionKind::DocumentKeyRetrieval => 5,
+			SessionKind::DocumentKeyCommonRetrieval => 6,
+			SessionKind::DocumentKeyShadowRetrieval => 7,
+			SessionKind::SchnorrSigning => 8,
+			SessionKind::EcdsaSigning => 9,
+			SessionKind::ServersSetChange => 10,
+			SessionKind::KeyOwnershipTransfer => 11,
+		}
+	}
+
+	/// Get session kind from its code.
+	pub fn from_code(code: u8) -> Option<Self> {
+		match code {
+			1 => Some(SessionKind::ServerKeyGeneration),
+			2 => Some(SessionKind::ServerKeyRetrieval),
+			3 => Some(SessionKind::DocumentKeyStore),
+			4 => Some(SessionKind::DocumentKeyGeneration),
+			5 => Some(SessionKind::DocumentKeyRetrieval),
+			6 => Some(SessionKind::DocumentKeyCommonRetrieval),
+			7 => Some(SessionKind::DocumentKeyShadowRetrieval),
+			8 => Some(SessionKind::SchnorrSigning),
+			9 => Some(SessionKind::EcdsaSigning),
+			10 => Some(SessionKind::ServersSetChange),
+			11 => Some(SessionKind::KeyOwnershipTransfer),
+			_ => None,
+		}
+	}
+}
+
+impl EncryptionScheme {
+	/// Get encryption scheme code.
+	pub fn code(&self) -> u8 {
+		match *self {
+			EncryptionScheme::Ecies => 1,
+		}
+	}
+
+	/// Get encryption scheme from its code.
+	pub fn from_code(code: u8) -> Option<Self> {
+		match code {
+			1 => Some(EncryptionScheme::Ecies),
+			_ => None,
+		}
+	}
+}
+
+impl EncryptedDocumentKey {
+	/// Encode encrypted document key as 1-byte scheme code, followed by ciphertext.
+	pub fn encode(&self) -> Bytes {
+		let mut encoded = Vec::with_capacity(1 + self.ciphertext.len());
+		encoded.push(self.scheme.code());
+		encoded.extend_from_slice(&self.ciphertext);
+		encoded
+	}
+
+	/// Decode encrypted document key, encoded with `encode`.
+	pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+		let (&scheme, ciphertext) = encoded.split_first().ok_or(Error::InvalidMessage)?;
+		Ok(EncryptedDocumentKey {
+			scheme: EncryptionScheme::from_code(scheme).ok_or(Error::UnsupportedEncryptionScheme(scheme))?,
+			ciphertext: ciphertext.to_vec(),
+		})
+	}
+
+	/// Decrypt document key using requester secret.
+	pub fn decrypt(&self, requester_secret: &Secret) -> Result<Public, Error> {
+		match self.scheme {
+			EncryptionScheme::Ecies => {
+				let document_key = crate::decrypt_with_secret(requester_secret, &self.ciphertext)?;
+				if document_key.len() != Public::len_bytes() {
+					return Err(Error::InvalidMessage);
+				}
+				Ok(Public::from_slice(&document_key))
+			},
+		}
+	}
+}
+
+impl From<Bytes> for EncryptedDocumentKey {
+	fn from(ciphertext: Bytes) -> Self {
+		EncryptedDocumentKey {
+			scheme: EncryptionScheme::Ecies,
+			ciphertext,
+		}
+	}
+}
+
+/// Information about session that is currently active on the key server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveSessionInfo {
+	/// Session id.
+	pub session_id: SessionId,
+	/// Id of the key this session is working with.
+	pub key_id: ServerKeyId,
+	/// Session kind.
+	pub kind: SessionKind,
+	/// Timestamp (in seconds since Unix epoch) when session has been started.
+	pub started_at: u64,
+	/// Session requester, if known.
+	pub requester: Option<Requester>,
+}
+
+/// Key server sessions introspection.
+pub trait SessionIntrospection {
+	/// Get all sessions that are started, but not yet completed.
+	fn active_sessions(&self) -> Vec<ActiveSessionInfo>;
+}
+
+/// Operations that are supported by key server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyServerCapabilities {
+	/// Schnorr signing is supported.
+	pub schnorr: bool,
+	/// ECDSA signing is supported.
+	pub ecdsa: bool,
+	/// BLS signing is supported.
+	pub bls: bool,
+	/// Administrative sessions are supported.
+	pub admin: bool,
+	/// Document keys could be generated by the key server.
+	pub document_key_generation: bool,
+}
+
+impl Default for KeyServerCapabilities {
+	/// All operations of the key server traits are supported. There are no BLS
+	/// signing operations in these traits, so BLS signing is unsupported.
+	fn default() -> Self {
+		KeyServerCapabilities {
+			schnorr: true,
+			ecdsa: true,
+			bls: false,
+			admin: true,
+			document_key_generation: true,
+		}
+	}
+}
+
+impl KeyServerCapabilities {
+	/// Returns true if signing using given scheme is supported.
+	pub fn supports_signature_scheme(&self, scheme: SignatureScheme) -> bool {
+		match scheme {
+			SignatureScheme::Schnorr => self.schnorr,
+			SignatureScheme::Ecdsa => self.ecdsa,
+		}
+	}
 }
 
 /// Key server.
 pub trait KeyServer: AdminSessionsServer + DocumentKeyServer + MessageSigner + Send + Sync + 'static {
+	/// Get operations that are supported by this key server. Unsupported operations
+	/// could be rejected before calling key server.
+	fn capabilities(&self) -> KeyServerCapabilities {
+		Default::default()
+	}
+}
+
+/// Check that ECDSA signature could be generated with key of given threshold
+/// when there are `node_count` nodes in the set.
+pub fn check_ecdsa_threshold(threshold: usize, node_count: usize) -> Result<(), Error> {
+	match 2 * threshold < node_count {
+		true => Ok(()),
+		false => Err(Error::EcdsaThresholdTooHigh { threshold, node_count }),
+	}
+}
+
+/// Verify Schnorr signature of the message, produced with given server key.
+pub fn verify_schnorr(server_key: &Public, message: &H256, artifacts: &SchnorrSigningArtifacts) -> Result<bool, Error> {
+	// k * G = s * G + c * Y
+	let mut nonce_public = *server_key;
+	ec_math_utils::public_mul_secret(&mut nonce_public, &artifacts.signature_c.into())?;
+	let mut signature_s_public = ec_math_utils::generation_point();
+	ec_math_utils::public_mul_secret(&mut signature_s_public, &artifacts.signature_s.into())?;
+	ec_math_utils::public_add(&mut nonce_public, &signature_s_public)?;
+	Ok(*schnorr_challenge(&nonce_public, message) == artifacts.signature_c)
+}
+
+/// Verify ECDSA signature of the message, produced with given server key. Signature
+/// is only considered valid if server key could be recovered from it.
+pub fn verify_ecdsa(server_key: &Public, message: &H256, artifacts: &EcdsaSigningArtifacts) -> Result<bool, Error> {
+	if !verify_public(server_key, &artifacts.signature, message)? {
+		return Ok(false);
+	}
+
+	Ok(recover(&artifacts.signature, message)? == *server_key)
+}
+
+/// Compute Schnorr challenge: `H(nonce_public || message)`.
+pub(crate) fn schnorr_challenge(nonce_public: &Public, message: &H256) -> Secret {
+	let mut keccak = Keccak::v256();
+	keccak.update(nonce_public.as_bytes());
+	keccak.update(message.as_bytes());
+
+	let mut challenge = [0u8; 32];
+	keccak.finalize(&mut challenge);
+	Secret::from(challenge)
 }
 
 impl<P, R> SessionResult<P, R> {
@@ -405,3 +1241,130 @@ impl<P, R> Into<Result<R, Error>> for SessionResult<P, R> {
 		self.result
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn artifacts_are_converted_to_uniform_enum() {
+		let artifacts = Artifacts::from(ServerKeyGenerationArtifacts {
+			key: Public::from_low_u64_be(1),
+			participants: Default::default(),
+			version: H256::from_low_u64_be(2),
+		});
+		match artifacts {
+			Artifacts::ServerKeyGeneration(artifacts) => assert_eq!(artifacts.key, Public::from_low_u64_be(1)),
+			_ => panic!("expected server key generation artifacts"),
+		}
+
+		let servers_set_change_artifacts = ServersSetChangeArtifacts {
+			migration_id: H256::from_low_u64_be(3),
+			new_set: Default::default(),
+			keys_resharded: 4,
+		};
+		match Artifacts::from(servers_set_change_artifacts.clone()) {
+			Artifacts::ServersSetChange(artifacts) => assert_eq!(artifacts, servers_set_change_artifacts),
+			_ => panic!("expected servers set change artifacts"),
+		}
+	}
+
+	#[test]
+	fn encrypted_document_key_is_encoded_and_decoded() {
+		let encrypted_document_key = EncryptedDocumentKey::from(vec![1, 2, 3]);
+		let encoded = encrypted_document_key.encode();
+		assert_eq!(encoded, vec![1, 1, 2, 3]);
+		assert_eq!(EncryptedDocumentKey::decode(&encoded), Ok(encrypted_document_key));
+	}
+
+	#[test]
+	fn encrypted_document_key_with_unknown_scheme_is_rejected() {
+		assert_eq!(EncryptedDocumentKey::decode(&[42, 1, 2, 3]), Err(Error::UnsupportedEncryptionScheme(42)));
+		assert_eq!(EncryptedDocumentKey::decode(&[]), Err(Error::InvalidMessage));
+	}
+
+	#[test]
+	fn document_key_shadow_is_encoded_and_decoded() {
+		let shadow = DocumentKeyShadow::from(vec![vec![1, 2], vec![], vec![3]]);
+		let encoded = shadow.encode();
+		assert_eq!(encoded, vec![0, 0, 0, 3, 0, 0, 0, 2, 1, 2, 0, 0, 0, 0, 0, 0, 0, 1, 3]);
+		assert_eq!(DocumentKeyShadow::decode(&encoded), Ok(shadow));
+		assert_eq!(DocumentKeyShadow::decode(&DocumentKeyShadow::default().encode()), Ok(Default::default()));
+		assert_eq!(DocumentKeyShadow::from(vec![1, 2]).coefficients(), &[vec![1, 2]]);
+	}
+
+	#[test]
+	fn malformed_document_key_shadow_is_rejected() {
+		// truncated count
+		assert_eq!(DocumentKeyShadow::decode(&[0, 0, 1]), Err(Error::InvalidMessage));
+		// missing coefficient
+		assert_eq!(DocumentKeyShadow::decode(&[0, 0, 0, 1]), Err(Error::InvalidMessage));
+		// coefficient is shorter than its length
+		assert_eq!(DocumentKeyShadow::decode(&[0, 0, 0, 1, 0, 0, 0, 2, 1]), Err(Error::InvalidMessage));
+		// trailing bytes
+		assert_eq!(DocumentKeyShadow::decode(&[0, 0, 0, 0, 1]), Err(Error::InvalidMessage));
+	}
+
+	#[test]
+	fn shadow_retrieval_artifacts_are_merged() {
+		let artifacts = |participants: Vec<u64>| DocumentKeyShadowRetrievalArtifacts {
+			common_point: Public::from_low_u64_be(1),
+			threshold: 1,
+			encrypted_document_key: Public::from_low_u64_be(2),
+			participants_coefficients: participants.into_iter()
+				.map(|i| (KeyServerId::from_low_u64_be(i), vec![i as u8]))
+				.collect(),
+		};
+
+		let mut merged = artifacts(vec![1, 2]);
+		merged.merge(&artifacts(vec![2, 3])).unwrap();
+		assert_eq!(merged.participants_coefficients, artifacts(vec![1, 2, 3]).participants_coefficients);
+
+		let mut conflicting = artifacts(vec![2]);
+		conflicting.participants_coefficients.insert(KeyServerId::from_low_u64_be(2), vec![42]);
+		assert!(merged.merge(&conflicting).is_err());
+		assert_eq!(merged.participants_coefficients, artifacts(vec![1, 2, 3]).participants_coefficients);
+	}
+
+	#[test]
+	fn shadow_retrieval_artifacts_with_different_common_point_are_not_merged() {
+		let artifacts = DocumentKeyShadowRetrievalArtifacts {
+			common_point: Public::from_low_u64_be(1),
+			threshold: 1,
+			encrypted_document_key: Public::from_low_u64_be(2),
+			participants_coefficients: vec![(KeyServerId::from_low_u64_be(1), vec![1])].into_iter().collect(),
+		};
+		let other = DocumentKeyShadowRetrievalArtifacts {
+			common_point: Public::from_low_u64_be(3),
+			participants_coefficients: vec![(KeyServerId::from_low_u64_be(2), vec![2])].into_iter().collect(),
+			..artifacts.clone()
+		};
+
+		let mut merged = artifacts.clone();
+		assert_eq!(merged.merge(&other), Err(Error::ConflictingKeyShares("common point mismatch".into())));
+		assert_eq!(merged.participants_coefficients, artifacts.participants_coefficients);
+	}
+
+	#[test]
+	fn shadow_retrieval_artifacts_are_projected_to_common() {
+		let artifacts = DocumentKeyShadowRetrievalArtifacts {
+			common_point: Public::from_low_u64_be(1),
+			threshold: 2,
+			encrypted_document_key: Public::from_low_u64_be(3),
+			participants_coefficients: vec![(KeyServerId::from_low_u64_be(4), vec![5])].into_iter().collect(),
+		};
+		let common = artifacts.common();
+		assert_eq!(common.common_point, Public::from_low_u64_be(1));
+		assert_eq!(common.threshold, 2);
+	}
+
+	#[test]
+	fn ecdsa_threshold_check_works() {
+		assert_eq!(check_ecdsa_threshold(0, 1), Ok(()));
+		assert_eq!(check_ecdsa_threshold(2, 5), Ok(()));
+		assert_eq!(
+			check_ecdsa_threshold(2, 4),
+			Err(Error::EcdsaThresholdTooHigh { threshold: 2, node_count: 4 }),
+		);
+	}
+}
@@ -15,12 +15,25 @@
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::{
-	collections::BTreeMap,
-	fmt::Debug,
-	net::SocketAddr,
+	collections::{BTreeMap, BTreeSet},
+	fmt::{self, Debug},
+	fs::{File, OpenOptions},
+	io::{BufRead, BufReader, ErrorKind, Write},
+	net::{SocketAddr, ToSocketAddrs},
+	path::PathBuf,
+	str::FromStr,
+	sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}},
 };
+use parking_lot::RwLock;
 use ethereum_types::H256;
-use crate::KeyServerId;
+use parity_crypto::publickey::{KeyPair, Secret, public_to_address};
+use crate::{
+	KeyServerId, ServerKeyId,
+	error::Error,
+	key_server::SessionId,
+	key_storage::{CanonicalReader, KeyStorage},
+	serialization::VersionedEncoding,
+};
 
 /// Every migration process has its own unique id.
 pub type MigrationId = H256;
@@ -34,6 +47,28 @@ pub struct KeyServerSetSnapshot<Address> {
 	pub new_set: BTreeMap<KeyServerId, Address>,
 	/// Current migration data.
 	pub migration: Option<KeyServerSetMigration<Address>>,
+	/// Set state generation. Increases whenever the state of the set is changed, so that
+	/// snapshots with the same generation are describing the same state.
+	pub generation: u64,
+	/// Number of the block, which state is reflected by this snapshot. Only set by the
+	/// implementations that are reading the set from the blockchain.
+	pub block_number: Option<u64>,
+}
+
+/// Number of required nodes that must be connected before sessions could be started.
+///
+/// Every session only needs `threshold + 1` nodes, so `AtLeast` lets the node proceed
+/// while some nodes are down. But with partial connectivity, sessions may fail later
+/// if the selected nodes are not connected, and nodes that are disconnected will miss
+/// key generation and servers set change sessions, so their key shares will be outdated
+/// or missing until the next servers set change.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ConnectivityRequirement {
+	/// All required nodes must be connected. This is the default requirement.
+	#[default]
+	All,
+	/// At least given number of required nodes must be connected.
+	AtLeast(usize),
 }
 
 /// Key server set migration.
@@ -49,6 +84,179 @@ pub struct KeyServerSetMigration<Address> {
 	pub is_confirmed: bool,
 }
 
+impl<Address> KeyServerSetSnapshot<Address> {
+	/// Get number of blocks, produced since the block this snapshot reflects. Returns
+	/// `None` if the snapshot isn't read from the blockchain.
+	pub fn snapshot_age(&self, current_block: u64) -> Option<u64> {
+		self.block_number.map(|block_number| current_block.saturating_sub(block_number))
+	}
+}
+
+impl<Address: Clone> KeyServerSetSnapshot<Address> {
+	/// Get nodes that this node must be connected to. When migration is active,
+	/// these are nodes of both current and migration sets. Otherwise these are
+	/// nodes of the current set.
+	pub fn required_nodes(&self) -> BTreeMap<KeyServerId, Address> {
+		let mut required_nodes = self.current_set.clone();
+		if let Some(migration) = self.migration.as_ref() {
+			required_nodes.extend(migration.set.iter().map(|(node, address)| (*node, address.clone())));
+		}
+		required_nodes
+	}
+
+	/// Returns true if all required nodes are in the `connected` set.
+	pub fn is_fully_connected(&self, connected: &BTreeSet<KeyServerId>) -> bool {
+		self.required_nodes().keys().all(|node| connected.contains(node))
+	}
+
+	/// Returns true if the `connected` set satisfies given connectivity requirement.
+	pub fn is_connected(&self, connected: &BTreeSet<KeyServerId>, requirement: ConnectivityRequirement) -> bool {
+		match requirement {
+			ConnectivityRequirement::All => self.is_fully_connected(connected),
+			ConnectivityRequirement::AtLeast(count) => self.required_nodes()
+				.keys()
+				.filter(|node| connected.contains(node))
+				.count() >= count,
+		}
+	}
+}
+
+impl<Address> KeyServerSetMigration<Address> {
+	/// Get nodes that must confirm the migration for it to proceed. These are all nodes
+	/// of the migration set and the master node, even if it is leaving the set.
+	pub fn required_confirmations(&self) -> BTreeSet<KeyServerId> {
+		self.set.keys().cloned().chain(std::iter::once(self.master)).collect()
+	}
+}
+
+impl<Address: Clone + Ord> KeyServerSetSnapshot<Address> {
+	/// Get addresses that are shared by several nodes (from current, new or migration
+	/// set), mapped to these nodes. Nodes are sorted by id.
+	pub fn duplicate_addresses(&self) -> BTreeMap<Address, Vec<KeyServerId>> {
+		let mut nodes = self.required_nodes();
+		nodes.extend(self.new_set.iter().map(|(node, address)| (*node, address.clone())));
+
+		let mut addresses: BTreeMap<Address, Vec<KeyServerId>> = BTreeMap::new();
+		for (node, address) in nodes {
+			addresses.entry(address).or_default().push(node);
+		}
+		addresses.retain(|_, nodes| nodes.len() > 1);
+		addresses
+	}
+}
+
+impl<Address: PartialEq> KeyServerSetSnapshot<Address> {
+	/// Find node (either from current, or from new set) that has given address. If
+	/// several nodes have the same address, the node with the smallest id is returned.
+	pub fn node_by_address(&self, address: &Address) -> Option<&KeyServerId> {
+		self.current_set.iter()
+			.chain(self.new_set.iter())
+			.filter(|(_, node_address)| *node_address == address)
+			.map(|(node, _)| node)
+			.min()
+	}
+}
+
+/// Canonical snapshot encoding (version 1) is: generation (8 bytes, BE), optional block
+/// number (1 byte flag, followed by 8 bytes, BE, if the flag is set), current set, new set
+/// and optional migration (1 byte flag, followed by migration if the flag is set). Every
+/// set is: number of nodes (4 bytes, BE) and nodes, sorted by id. Every node is: id (20
+/// bytes), address length (4 bytes, BE) and address string. Migration is: id (32 bytes),
+/// set, master (20 bytes) and confirmation flag (1 byte).
+impl VersionedEncoding for KeyServerSetSnapshot<SocketAddr> {
+	const VERSION: u8 = 1;
+
+	fn encode_payload(&self) -> Vec<u8> {
+		fn encode_set(encoded: &mut Vec<u8>, set: &BTreeMap<KeyServerId, SocketAddr>) {
+			encoded.extend_from_slice(&(set.len() as u32).to_be_bytes());
+			for (node, address) in set {
+				let address = address.to_string();
+				encoded.extend_from_slice(node.as_bytes());
+				encoded.extend_from_slice(&(address.len() as u32).to_be_bytes());
+				encoded.extend_from_slice(address.as_bytes());
+			}
+		}
+
+		let mut encoded = Vec::new();
+		encoded.extend_from_slice(&self.generation.to_be_bytes());
+		match self.block_number {
+			Some(block_number) => {
+				encoded.push(1);
+				encoded.extend_from_slice(&block_number.to_be_bytes());
+			},
+			None => encoded.push(0),
+		}
+		encode_set(&mut encoded, &self.current_set);
+		encode_set(&mut encoded, &self.new_set);
+		match self.migration {
+			Some(ref migration) => {
+				encoded.push(1);
+				encoded.extend_from_slice(migration.id.as_bytes());
+				encode_set(&mut encoded, &migration.set);
+				encoded.extend_from_slice(migration.master.as_bytes());
+				encoded.push(migration.is_confirmed as u8);
+			},
+			None => encoded.push(0),
+		}
+		encoded
+	}
+
+	fn decode_payload(encoded: &[u8]) -> Result<Self, Error> {
+		fn decode_flag(reader: &mut CanonicalReader) -> Result<bool, Error> {
+			match reader.read(1)?[0] {
+				0 => Ok(false),
+				1 => Ok(true),
+				flag => Err(Error::Serde(format!("invalid flag: {}", flag))),
+			}
+		}
+
+		fn decode_set(reader: &mut CanonicalReader) -> Result<BTreeMap<KeyServerId, SocketAddr>, Error> {
+			let nodes_count = reader.read_u32()?;
+			let mut set = BTreeMap::new();
+			for _ in 0..nodes_count {
+				let node = KeyServerId::from_slice(reader.read(20)?);
+				let address_len = reader.read_u32()? as usize;
+				let address = std::str::from_utf8(reader.read(address_len)?)
+					.map_err(|error| Error::Serde(error.to_string()))?
+					.parse()
+					.map_err(|error: std::net::AddrParseError| Error::Serde(error.to_string()))?;
+				set.insert(node, address);
+			}
+			Ok(set)
+		}
+
+		let mut reader = CanonicalReader(encoded);
+		let generation = reader.read_u64()?;
+		let block_number = match decode_flag(&mut reader)? {
+			true => Some(reader.read_u64()?),
+			false => None,
+		};
+		let current_set = decode_set(&mut reader)?;
+		let new_set = decode_set(&mut reader)?;
+		let migration = match decode_flag(&mut reader)? {
+			true => Some(KeyServerSetMigration {
+				id: MigrationId::from_slice(reader.read(32)?),
+				set: decode_set(&mut reader)?,
+				master: KeyServerId::from_slice(reader.read(20)?),
+				is_confirmed: decode_flag(&mut reader)?,
+			}),
+			false => None,
+		};
+
+		if !reader.0.is_empty() {
+			return Err(Error::Serde("unexpected trailing bytes in encoded key server set snapshot".into()));
+		}
+
+		Ok(KeyServerSetSnapshot {
+			current_set,
+			new_set,
+			migration,
+			generation,
+			block_number,
+		})
+	}
+}
+
 /// Key Server Set.
 pub trait KeyServerSet: Send + Sync {
 	/// Type of address we need to know to connect remote key servers.
@@ -58,17 +266,261 @@ pub trait KeyServerSet: Send + Sync {
 	fn is_isolated(&self) -> bool;
 	/// Get server set state.
 	fn snapshot(&self) -> KeyServerSetSnapshot<Self::NetworkAddress>;
+	/// Get server set state without blocking. Returns `None` if the state is currently
+	/// locked by other operation. By default, falls back to the (possibly blocking) `snapshot`.
+	fn try_snapshot(&self) -> Option<KeyServerSetSnapshot<Self::NetworkAddress>> {
+		Some(self.snapshot())
+	}
 	/// Start migration.
 	fn start_migration(&self, migration_id: MigrationId);
 	/// Confirm migration.
 	fn confirm_migration(&self, migration_id: MigrationId);
+	/// Cancel migration. Does nothing if migration with given id isn't active.
+	/// WARNING: cancellation is only safe before key shares are redistributed
+	/// between nodes of the migration set. Otherwise some keys could become
+	/// unrecoverable.
+	fn cancel_migration(&self, migration_id: MigrationId);
+	/// Complete migration. Does nothing if migration with given id isn't active.
+	fn complete_migration(&self, migration_id: MigrationId);
+	/// Get ids and statuses of known migrations in the order they have been started.
+	fn migration_history(&self) -> Vec<(MigrationId, MigrationStatus)> {
+		self.snapshot().migration
+			.map(|migration| (migration.id, match migration.is_confirmed {
+				true => MigrationStatus::Confirmed,
+				false => MigrationStatus::Active,
+			}))
+			.into_iter()
+			.collect()
+	}
+	/// Get ids of all known migrations in the order they have been started.
+	fn all_migration_ids(&self) -> Vec<MigrationId> {
+		self.migration_history().into_iter().map(|(migration_id, _)| migration_id).collect()
+	}
+	/// Get addresses of nodes from the current set. Unlike `snapshot`, this could
+	/// include hostnames that are only resolved when connection is established.
+	fn snapshot_addresses(&self) -> BTreeMap<KeyServerId, NodeAddress> where Self::NetworkAddress: Into<NodeAddress> {
+		self.snapshot().current_set
+			.into_iter()
+			.map(|(node, address)| (node, address.into()))
+			.collect()
+	}
+}
+
+/// Network address of key server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeAddress {
+	/// Resolved socket address.
+	Socket(SocketAddr),
+	/// Hostname and port. Hostname is resolved on every `resolve` call, so that
+	/// nodes with changing IP addresses could be reached.
+	Host(String, u16),
+}
+
+impl NodeAddress {
+	/// Resolve node address to socket address.
+	pub fn resolve(&self) -> Result<SocketAddr, Error> {
+		match *self {
+			NodeAddress::Socket(address) => Ok(address),
+			NodeAddress::Host(ref host, port) => (host.as_str(), port)
+				.to_socket_addrs()?
+				.next()
+				.ok_or(Error::InvalidNodeAddress),
+		}
+	}
+}
+
+impl From<SocketAddr> for NodeAddress {
+	fn from(address: SocketAddr) -> Self {
+		NodeAddress::Socket(address)
+	}
+}
+
+impl FromStr for NodeAddress {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Ok(address) = s.parse() {
+			return Ok(NodeAddress::Socket(address));
+		}
+
+		let mut parts = s.rsplitn(2, ':');
+		match (parts.next(), parts.next()) {
+			(Some(port), Some(host)) if !host.is_empty() && !host.contains(':') => port.parse()
+				.map(|port| NodeAddress::Host(host.into(), port))
+				.map_err(|_| Error::InvalidNodeAddress),
+			_ => Err(Error::InvalidNodeAddress),
+		}
+	}
+}
+
+impl fmt::Display for NodeAddress {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			NodeAddress::Socket(ref address) => write!(f, "{}", address),
+			NodeAddress::Host(ref host, port) => write!(f, "{}:{}", host, port),
+		}
+	}
+}
+
+/// Migration status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationStatus {
+	/// Migration is active, but not yet confirmed by this node.
+	Active,
+	/// Migration is active and confirmed by this node.
+	Confirmed,
+	/// Migration has been completed.
+	Completed,
+	/// Migration has been cancelled.
+	Cancelled,
+}
+
+/// Migration state, recorded in the migration journal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MigrationJournalEntry {
+	/// Migration id.
+	pub id: MigrationId,
+	/// Is migration confirmed by this node?
+	pub is_confirmed: bool,
+	/// Is migration cancelled?
+	pub is_cancelled: bool,
+	/// Is migration completed?
+	pub is_completed: bool,
+}
+
+impl MigrationJournalEntry {
+	/// Create entry for just started migration.
+	pub fn started(id: MigrationId) -> Self {
+		MigrationJournalEntry {
+			id,
+			is_confirmed: false,
+			is_cancelled: false,
+			is_completed: false,
+		}
+	}
+
+	/// Is migration still active?
+	pub fn is_active(&self) -> bool {
+		!self.is_cancelled && !self.is_completed
+	}
+
+	/// Get migration status.
+	pub fn status(&self) -> MigrationStatus {
+		match (self.is_cancelled, self.is_completed, self.is_confirmed) {
+			(true, _, _) => MigrationStatus::Cancelled,
+			(false, true, _) => MigrationStatus::Completed,
+			(false, false, true) => MigrationStatus::Confirmed,
+			(false, false, false) => MigrationStatus::Active,
+		}
+	}
+}
+
+/// Journal of migrations that this node has participated in. It allows node to
+/// recover its migration state after restart.
+pub trait MigrationJournal: Send + Sync {
+	/// Record that the migration has been started.
+	fn record_started(&self, migration_id: MigrationId) -> Result<(), Error>;
+	/// Record that the migration has been confirmed by this node.
+	fn record_confirmed(&self, migration_id: MigrationId) -> Result<(), Error>;
+	/// Record that the migration has been cancelled.
+	fn record_cancelled(&self, migration_id: MigrationId) -> Result<(), Error>;
+	/// Record that the migration has been completed.
+	fn record_completed(&self, migration_id: MigrationId) -> Result<(), Error>;
+	/// Load all recorded migrations in the order they have been started.
+	fn load(&self) -> Result<Vec<MigrationJournalEntry>, Error>;
+}
+
+/// File-backed migration journal implementation. Every journal record is
+/// appended to the file as a separate line.
+#[derive(Debug)]
+pub struct FileMigrationJournal {
+	path: PathBuf,
 }
 
 /// In-memory key server set implementation.
+///
+/// The set of nodes never changes, so migration (if started) is always a migration
+/// to the same set, with the node with the smallest id as a master.
 #[derive(Default)]
 pub struct InMemoryKeyServerSet {
 	is_isolated: bool,
 	nodes: BTreeMap<KeyServerId, SocketAddr>,
+	migrations: RwLock<Vec<MigrationJournalEntry>>,
+	/// Only modified when `migrations` write lock is held.
+	generation: AtomicU64,
+	journal: Option<Arc<dyn MigrationJournal>>,
+}
+
+impl FileMigrationJournal {
+	/// Create new file-backed migration journal. The file is created on first write.
+	pub fn new(path: PathBuf) -> Self {
+		FileMigrationJournal {
+			path,
+		}
+	}
+
+	/// Append record to the journal.
+	fn append(&self, record: &str, migration_id: MigrationId) -> Result<(), Error> {
+		let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		writeln!(file, "{} {:x}", record, migration_id)?;
+		file.sync_all()?;
+		Ok(())
+	}
+}
+
+impl MigrationJournal for FileMigrationJournal {
+	fn record_started(&self, migration_id: MigrationId) -> Result<(), Error> {
+		self.append("started", migration_id)
+	}
+
+	fn record_confirmed(&self, migration_id: MigrationId) -> Result<(), Error> {
+		self.append("confirmed", migration_id)
+	}
+
+	fn record_cancelled(&self, migration_id: MigrationId) -> Result<(), Error> {
+		self.append("cancelled", migration_id)
+	}
+
+	fn record_completed(&self, migration_id: MigrationId) -> Result<(), Error> {
+		self.append("completed", migration_id)
+	}
+
+	fn load(&self) -> Result<Vec<MigrationJournalEntry>, Error> {
+		let file = match File::open(&self.path) {
+			Ok(file) => file,
+			Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(error) => return Err(error.into()),
+		};
+
+		let mut entries: Vec<MigrationJournalEntry> = Vec::new();
+		for line in BufReader::new(file).lines() {
+			let line = line?;
+			let mut parts = line.split(' ');
+			let (record, migration_id) = match (parts.next(), parts.next(), parts.next()) {
+				(Some(record), Some(migration_id), None) => (record, MigrationId::from_str(migration_id)
+					.map_err(|error| Error::Database(format!("invalid migration id in journal: {}", error)))?),
+				_ => return Err(Error::Database(format!("invalid migration journal record: {}", line))),
+			};
+
+			if record == "started" {
+				entries.push(MigrationJournalEntry::started(migration_id));
+				continue;
+			}
+
+			let entry = entries.iter_mut()
+				.rev()
+				.find(|entry| entry.id == migration_id)
+				.ok_or_else(|| Error::Database(format!("{} migration {:x} is not started", record, migration_id)))?;
+			match record {
+				"confirmed" => entry.is_confirmed = true,
+				"cancelled" => entry.is_cancelled = true,
+				"completed" => entry.is_completed = true,
+				_ => return Err(Error::Database(format!("invalid migration journal record: {}", line))),
+			}
+		}
+
+		Ok(entries)
+	}
 }
 
 impl InMemoryKeyServerSet {
@@ -77,6 +529,77 @@ impl InMemoryKeyServerSet {
 		InMemoryKeyServerSet {
 			is_isolated: is_isolated,
 			nodes: nodes,
+			migrations: RwLock::new(Vec::new()),
+			generation: AtomicU64::new(0),
+			journal: None,
+		}
+	}
+
+	/// Create new in-memory key server set that is persisting its migration state
+	/// in the given journal. The state of recorded migrations is restored.
+	pub fn with_journal(
+		is_isolated: bool,
+		nodes: BTreeMap<KeyServerId, SocketAddr>,
+		journal: Arc<dyn MigrationJournal>,
+	) -> Result<Self, Error> {
+		let migrations = journal.load()?;
+		// every mutation is recorded in the journal and increases generation by one, so
+		// restored generation is the same as it has been before restart
+		let generation = migrations.iter()
+			.map(|migration| 1 + migration.is_confirmed as u64 + migration.is_cancelled as u64 + migration.is_completed as u64)
+			.sum();
+		Ok(InMemoryKeyServerSet {
+			is_isolated,
+			nodes,
+			migrations: RwLock::new(migrations),
+			generation: AtomicU64::new(generation),
+			journal: Some(journal),
+		})
+	}
+
+	/// Record migration state change in the journal (if any). Returns false if the record
+	/// has failed, so the in-memory state must not be changed either.
+	fn record(
+		&self,
+		record: &str,
+		migration_id: MigrationId,
+		write: impl FnOnce(&dyn MigrationJournal) -> Result<(), Error>,
+	) -> bool {
+		let journal = match self.journal.as_ref() {
+			Some(journal) => journal,
+			None => return true,
+		};
+
+		match write(&**journal) {
+			Ok(()) => true,
+			Err(error) => {
+				log::error!(
+					target: "secretstore",
+					"Failed to record {} migration {:x}: {}",
+					record,
+					migration_id,
+					error,
+				);
+				false
+			},
+		}
+	}
+
+	/// Build snapshot from given migrations.
+	fn snapshot_from(&self, migrations: &[MigrationJournalEntry]) -> KeyServerSetSnapshot<SocketAddr> {
+		KeyServerSetSnapshot {
+			current_set: self.nodes.clone(),
+			new_set: self.nodes.clone(),
+			migration: migrations.last()
+				.filter(|migration| migration.is_active())
+				.map(|migration| KeyServerSetMigration {
+					id: migration.id,
+					set: self.nodes.clone(),
+					master: self.nodes.keys().next().cloned().unwrap_or_default(),
+					is_confirmed: migration.is_confirmed,
+				}),
+			generation: self.generation.load(Ordering::SeqCst),
+			block_number: None,
 		}
 	}
 }
@@ -89,18 +612,611 @@ impl KeyServerSet for InMemoryKeyServerSet {
 	}
 
 	fn snapshot(&self) -> KeyServerSetSnapshot<Self::NetworkAddress> {
+		self.snapshot_from(&self.migrations.read())
+	}
+
+	fn try_snapshot(&self) -> Option<KeyServerSetSnapshot<Self::NetworkAddress>> {
+		self.migrations.try_read().map(|migrations| self.snapshot_from(&migrations))
+	}
+
+	fn start_migration(&self, migration_id: MigrationId) {
+		let mut migrations = self.migrations.write();
+		if migrations.last().map(|migration| migration.is_active()).unwrap_or(false) {
+			return;
+		}
+
+		// the state is only changed if it has been persisted, so that the node never
+		// forgets its actions (e.g. confirmations) after restart
+		if !self.record("started", migration_id, |journal| journal.record_started(migration_id)) {
+			return;
+		}
+		migrations.push(MigrationJournalEntry::started(migration_id));
+		self.generation.fetch_add(1, Ordering::SeqCst);
+	}
+
+	fn confirm_migration(&self, migration_id: MigrationId) {
+		let mut migrations = self.migrations.write();
+		match migrations.last_mut() {
+			Some(migration) if migration.id == migration_id && migration.is_active() && !migration.is_confirmed => {
+				if !self.record("confirmed", migration_id, |journal| journal.record_confirmed(migration_id)) {
+					return;
+				}
+				migration.is_confirmed = true;
+				self.generation.fetch_add(1, Ordering::SeqCst);
+			},
+			_ => (),
+		}
+	}
+
+	fn cancel_migration(&self, migration_id: MigrationId) {
+		let mut migrations = self.migrations.write();
+		match migrations.last_mut() {
+			Some(migration) if migration.id == migration_id && migration.is_active() => {
+				if !self.record("cancelled", migration_id, |journal| journal.record_cancelled(migration_id)) {
+					return;
+				}
+				migration.is_cancelled = true;
+				self.generation.fetch_add(1, Ordering::SeqCst);
+			},
+			_ => (),
+		}
+	}
+
+	fn complete_migration(&self, migration_id: MigrationId) {
+		let mut migrations = self.migrations.write();
+		match migrations.last_mut() {
+			Some(migration) if migration.id == migration_id && migration.is_active() => {
+				if !self.record("completed", migration_id, |journal| journal.record_completed(migration_id)) {
+					return;
+				}
+				migration.is_completed = true;
+				self.generation.fetch_add(1, Ordering::SeqCst);
+			},
+			_ => (),
+		}
+	}
+
+	fn migration_history(&self) -> Vec<(MigrationId, MigrationStatus)> {
+		self.migrations.read().iter().map(|migration| (migration.id, migration.status())).collect()
+	}
+}
+
+/// Key server set snapshot along with nodes that are holding shares of every stored key.
+pub type ConsistentSnapshot<Address> = (KeyServerSetSnapshot<Address>, Vec<(ServerKeyId, BTreeSet<KeyServerId>)>);
+
+/// Max number of attempts to read key storage without key server set being changed.
+const MAX_CONSISTENT_SNAPSHOT_ATTEMPTS: usize = 3;
+
+/// Read key server set snapshot along with nodes that are holding shares of every stored key,
+/// making sure that the key server set hasn't been changed while key storage has been read.
+///
+/// Nodes that are holding shares, but are neither in the current, nor in the new, nor in
+/// the migration set are reported with warning.
+pub fn consistent_snapshot<Address: Clone + PartialEq + Send + Sync>(
+	set: &dyn KeyServerSet<NetworkAddress = Address>,
+	storage: &dyn KeyStorage,
+) -> Result<ConsistentSnapshot<Address>, Error> {
+	for _ in 0..MAX_CONSISTENT_SNAPSHOT_ATTEMPTS {
+		let snapshot = set.snapshot();
+		let holding_nodes = storage.iter()
+			.map(|(key_id, key_share)| (key_id, key_share.holding_nodes()))
+			.collect::<Vec<_>>();
+		if set.snapshot() != snapshot {
+			continue;
+		}
+
+		let known_nodes = snapshot.required_nodes()
+			.keys()
+			.chain(snapshot.new_set.keys())
+			.cloned()
+			.collect::<BTreeSet<_>>();
+		for (key_id, nodes) in &holding_nodes {
+			for unknown_node in nodes.difference(&known_nodes) {
+				log::warn!(
+					target: "secretstore",
+					"Share of key {:?} is held by node {:?}, which is not in the key server set",
+					key_id,
+					unknown_node,
+				);
+			}
+		}
+
+		return Ok((snapshot, holding_nodes));
+	}
+
+	Err(Error::Internal("key server set has been changing while key storage has been read".into()))
+}
+
+/// Elect coordinator of the session among given nodes. The node with the smallest
+/// `node_id XOR session_id` (using the last 20 bytes of the session id) is elected,
+/// so that every node elects the same coordinator for the same session, while
+/// different sessions are coordinated by different nodes.
+pub fn elect_coordinator(nodes: &BTreeSet<KeyServerId>, session_id: &SessionId) -> Option<KeyServerId> {
+	let session_id = KeyServerId::from_slice(&session_id[12..]);
+	nodes.iter().min_by_key(|node| **node ^ session_id).cloned()
+}
+
+/// Derive id of the key server from its secret key.
+pub fn key_server_id_from_secret(secret: &Secret) -> Result<KeyServerId, Error> {
+	let key_pair = KeyPair::from_secret(secret.clone())?;
+	Ok(public_to_address(key_pair.public()))
+}
+
+/// Selects nodes that are participating in the session.
+pub trait NodeSelector<Address>: Send + Sync {
+	/// Select exactly `required` nodes from the current set of key servers.
+	fn select(
+		&self,
+		snapshot: &KeyServerSetSnapshot<Address>,
+		required: usize,
+	) -> Result<BTreeSet<KeyServerId>, Error>;
+}
+
+/// Node selector that is selecting available nodes of the current set in round-robin
+/// fashion, so that the load is distributed evenly between all key servers.
+#[derive(Default, Debug)]
+pub struct RoundRobinNodeSelector {
+	next_offset: AtomicUsize,
+	unavailable: RwLock<BTreeSet<KeyServerId>>,
+}
+
+impl RoundRobinNodeSelector {
+	/// Mark node as (un)available. Unavailable nodes are never selected. All nodes are
+	/// available by default.
+	pub fn set_available(&self, node: KeyServerId, is_available: bool) {
+		let mut unavailable = self.unavailable.write();
+		match is_available {
+			true => unavailable.remove(&node),
+			false => unavailable.insert(node),
+		};
+	}
+}
+
+impl<Address> NodeSelector<Address> for RoundRobinNodeSelector {
+	fn select(
+		&self,
+		snapshot: &KeyServerSetSnapshot<Address>,
+		required: usize,
+	) -> Result<BTreeSet<KeyServerId>, Error> {
+		let unavailable = self.unavailable.read();
+		let available_nodes = snapshot.current_set.keys()
+			.filter(|node| !unavailable.contains(node))
+			.collect::<Vec<_>>();
+		if available_nodes.len() < required {
+			return Err(Error::ConsensusUnreachable);
+		}
+		if required == 0 {
+			return Ok(BTreeSet::new());
+		}
+
+		let offset = self.next_offset.fetch_add(1, Ordering::Relaxed) % available_nodes.len();
+		Ok(available_nodes.into_iter()
+			.cycle()
+			.skip(offset)
+			.take(required)
+			.cloned()
+			.collect())
+	}
+}
+
+/// Node selector that is selecting nodes with largest weights. Nodes with zero weight
+/// (and nodes without weight) are considered unavailable and are never selected.
+#[derive(Default, Debug)]
+pub struct WeightedNodeSelector {
+	weights: BTreeMap<KeyServerId, u32>,
+}
+
+impl WeightedNodeSelector {
+	/// Create new weighted node selector.
+	pub fn new(weights: BTreeMap<KeyServerId, u32>) -> Self {
+		WeightedNodeSelector {
+			weights,
+		}
+	}
+}
+
+impl<Address> NodeSelector<Address> for WeightedNodeSelector {
+	fn select(
+		&self,
+		snapshot: &KeyServerSetSnapshot<Address>,
+		required: usize,
+	) -> Result<BTreeSet<KeyServerId>, Error> {
+		let mut available_nodes = snapshot.current_set.keys()
+			.filter_map(|node| match self.weights.get(node).cloned().unwrap_or_default() {
+				0 => None,
+				weight => Some((weight, *node)),
+			})
+			.collect::<Vec<_>>();
+		if available_nodes.len() < required {
+			return Err(Error::ConsensusUnreachable);
+		}
+
+		// largest weight first, ties are resolved by node id
+		available_nodes.sort_by(|(weight1, node1), (weight2, node2)| weight2.cmp(weight1).then(node1.cmp(node2)));
+		Ok(available_nodes.into_iter().take(required).map(|(_, node)| node).collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use parity_crypto::publickey::Secret;
+	use crate::key_storage::{InMemoryKeyStorage, KeyShare, KeyShareVersion};
+	use super::*;
+
+	fn snapshot(nodes_count: u64) -> KeyServerSetSnapshot<()> {
 		KeyServerSetSnapshot {
-			current_set: self.nodes.clone(),
-			new_set: self.nodes.clone(),
+			current_set: (1..=nodes_count).map(|i| (KeyServerId::from_low_u64_be(i), ())).collect(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn consistent_snapshot_is_read() {
+		let node = |i| KeyServerId::from_low_u64_be(i);
+		let address: SocketAddr = "127.0.0.1:8083".parse().unwrap();
+		let set = InMemoryKeyServerSet::new(false, vec![(node(1), address), (node(2), address)].into_iter().collect());
+		set.start_migration(MigrationId::from_low_u64_be(42));
+
+		let key_share = |nodes: Vec<u64>| KeyShare {
+			versions: vec![KeyShareVersion::new(
+				nodes.into_iter().map(|i| (node(i), Secret::from(H256::from_low_u64_be(i)))).collect(),
+				Secret::from(H256::from_low_u64_be(100)),
+			)],
+			..Default::default()
+		};
+		let storage = InMemoryKeyStorage::default();
+		storage.insert(ServerKeyId::from_low_u64_be(1), key_share(vec![1, 2])).unwrap();
+		// node 3 isn't in the set => warning
+		storage.insert(ServerKeyId::from_low_u64_be(2), key_share(vec![2, 3])).unwrap();
+
+		let (snapshot, mut holding_nodes) = consistent_snapshot(&set, &storage).unwrap();
+		holding_nodes.sort();
+		assert_eq!(snapshot, set.snapshot());
+		assert_eq!(holding_nodes, vec![
+			(ServerKeyId::from_low_u64_be(1), vec![node(1), node(2)].into_iter().collect()),
+			(ServerKeyId::from_low_u64_be(2), vec![node(2), node(3)].into_iter().collect()),
+		]);
+	}
+
+	#[test]
+	fn try_snapshot_does_not_block_on_locked_state() {
+		let set = InMemoryKeyServerSet::new(false, vec![(KeyServerId::from_low_u64_be(1), "127.0.0.1:8083".parse().unwrap())].into_iter().collect());
+		assert_eq!(set.try_snapshot(), Some(set.snapshot()));
+
+		let migrations = set.migrations.write();
+		assert_eq!(set.try_snapshot(), None);
+		drop(migrations);
+		assert_eq!(set.try_snapshot(), Some(set.snapshot()));
+	}
+
+	#[test]
+	fn generation_is_increased_by_mutations_only() {
+		let set = InMemoryKeyServerSet::new(false, vec![(KeyServerId::from_low_u64_be(1), "127.0.0.1:8083".parse().unwrap())].into_iter().collect());
+		assert_eq!(set.snapshot().generation, 0);
+		assert_eq!(set.snapshot().generation, 0);
+
+		let migration_id = MigrationId::from_low_u64_be(1);
+		set.start_migration(migration_id);
+		assert_eq!(set.snapshot().generation, 1);
+		set.confirm_migration(migration_id);
+		assert_eq!(set.snapshot().generation, 2);
+		// confirming the same migration again doesn't change anything
+		set.confirm_migration(migration_id);
+		assert_eq!(set.try_snapshot().map(|snapshot| snapshot.generation), Some(2));
+		set.complete_migration(migration_id);
+		assert_eq!(set.snapshot().generation, 3);
+	}
+
+	#[test]
+	fn coordinator_election_is_deterministic() {
+		let nodes: BTreeSet<_> = (1..=4).map(KeyServerId::from_low_u64_be).collect();
+		assert_eq!(elect_coordinator(&BTreeSet::new(), &SessionId::zero()), None);
+		assert_eq!(elect_coordinator(&nodes, &SessionId::zero()), Some(KeyServerId::from_low_u64_be(1)));
+		assert_eq!(elect_coordinator(&nodes, &SessionId::from_low_u64_be(3)), Some(KeyServerId::from_low_u64_be(3)));
+
+		let coordinators = (0..64u64)
+			.map(|session| elect_coordinator(&nodes, &SessionId::from_low_u64_be(session)).unwrap())
+			.collect::<Vec<_>>();
+		let repeated = (0..64u64)
+			.map(|session| elect_coordinator(&nodes, &SessionId::from_low_u64_be(session)).unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(coordinators, repeated);
+		assert_eq!(coordinators.iter().collect::<BTreeSet<_>>(), nodes.iter().collect::<BTreeSet<_>>());
+	}
+
+	#[test]
+	fn key_server_id_is_derived_from_secret() {
+		let secret = Secret::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+		assert_eq!(
+			key_server_id_from_secret(&secret),
+			Ok(KeyServerId::from_str("7e5f4552091a69125d5dfcb7b8c2659029395bdf").unwrap()),
+		);
+	}
+
+	#[test]
+	fn node_address_is_parsed() {
+		assert_eq!(
+			"127.0.0.1:8083".parse::<NodeAddress>(),
+			Ok(NodeAddress::Socket("127.0.0.1:8083".parse().unwrap())),
+		);
+		assert_eq!(
+			"[::1]:8083".parse::<NodeAddress>(),
+			Ok(NodeAddress::Socket("[::1]:8083".parse().unwrap())),
+		);
+		assert_eq!(
+			"node1.example.com:8083".parse::<NodeAddress>(),
+			Ok(NodeAddress::Host("node1.example.com".into(), 8083)),
+		);
+		assert_eq!("node1.example.com".parse::<NodeAddress>(), Err(Error::InvalidNodeAddress));
+		assert_eq!("node1.example.com:port".parse::<NodeAddress>(), Err(Error::InvalidNodeAddress));
+		assert_eq!(":8083".parse::<NodeAddress>(), Err(Error::InvalidNodeAddress));
+	}
+
+	#[test]
+	fn localhost_address_is_resolved() {
+		let address = NodeAddress::Host("localhost".into(), 8083).resolve().unwrap();
+		assert!(address.ip().is_loopback());
+		assert_eq!(address.port(), 8083);
+	}
+
+	#[test]
+	fn snapshot_age_is_computed_from_block_number() {
+		let mut snapshot = snapshot(1);
+		assert_eq!(snapshot.snapshot_age(100), None);
+
+		snapshot.block_number = Some(90);
+		assert_eq!(snapshot.snapshot_age(100), Some(10));
+		assert_eq!(snapshot.snapshot_age(80), Some(0));
+	}
+
+	#[test]
+	fn duplicate_addresses_are_detected() {
+		let node = |i| KeyServerId::from_low_u64_be(i);
+		let address = |port| SocketAddr::from(([127, 0, 0, 1], port));
+		let current_set: BTreeMap<_, _> = vec![(node(1), address(8081)), (node(2), address(8082))].into_iter().collect();
+		let mut snapshot = KeyServerSetSnapshot {
+			current_set: current_set.clone(),
+			new_set: current_set,
+			migration: None,
+			generation: 0,
+			block_number: None,
+		};
+		assert!(snapshot.duplicate_addresses().is_empty());
+
+		snapshot.new_set.insert(node(3), address(8081));
+		assert_eq!(
+			snapshot.duplicate_addresses(),
+			vec![(address(8081), vec![node(1), node(3)])].into_iter().collect(),
+		);
+	}
+
+	#[test]
+	fn node_is_found_by_address() {
+		let address1: SocketAddr = "127.0.0.1:8083".parse().unwrap();
+		let address2: SocketAddr = "127.0.0.1:8084".parse().unwrap();
+		let snapshot = KeyServerSetSnapshot {
+			current_set: vec![(KeyServerId::from_low_u64_be(1), address1)].into_iter().collect(),
+			new_set: vec![(KeyServerId::from_low_u64_be(2), address2)].into_iter().collect(),
 			migration: None,
+			generation: 0,
+			block_number: None,
+		};
+		assert_eq!(snapshot.node_by_address(&address1), Some(&KeyServerId::from_low_u64_be(1)));
+		assert_eq!(snapshot.node_by_address(&address2), Some(&KeyServerId::from_low_u64_be(2)));
+		assert_eq!(snapshot.node_by_address(&"127.0.0.1:8085".parse().unwrap()), None);
+	}
+
+	#[test]
+	fn migration_nodes_are_required_for_full_connectivity() {
+		let node = |i| KeyServerId::from_low_u64_be(i);
+		let mut snapshot = snapshot(2);
+		let current_set: BTreeSet<_> = vec![node(1), node(2)].into_iter().collect();
+		assert!(snapshot.is_fully_connected(&current_set));
+
+		snapshot.migration = Some(KeyServerSetMigration {
+			set: vec![(node(2), ()), (node(3), ())].into_iter().collect(),
+			..Default::default()
+		});
+		assert!(!snapshot.is_fully_connected(&current_set));
+		assert!(snapshot.is_fully_connected(&vec![node(1), node(2), node(3)].into_iter().collect()));
+	}
+
+	#[test]
+	fn partial_connectivity_is_accepted_when_allowed() {
+		let node = |i| KeyServerId::from_low_u64_be(i);
+		let snapshot = snapshot(3);
+		let connected: BTreeSet<_> = vec![node(1), node(2)].into_iter().collect();
+		assert!(!snapshot.is_connected(&connected, ConnectivityRequirement::All));
+		assert!(snapshot.is_connected(&connected, ConnectivityRequirement::AtLeast(2)));
+		assert!(!snapshot.is_connected(&connected, ConnectivityRequirement::AtLeast(3)));
+	}
+
+	#[test]
+	fn migration_confirmations_are_required_from_migration_set_and_master() {
+		let node = |i| KeyServerId::from_low_u64_be(i);
+		let migration = KeyServerSetMigration {
+			set: vec![(node(2), ()), (node(3), ())].into_iter().collect(),
+			master: node(1),
+			..Default::default()
+		};
+		assert_eq!(migration.required_confirmations(), vec![node(1), node(2), node(3)].into_iter().collect());
+	}
+
+	#[test]
+	fn round_robin_selector_selects_required_nodes() {
+		let selector = RoundRobinNodeSelector::default();
+		let snapshot = snapshot(3);
+		let selection1 = selector.select(&snapshot, 2).unwrap();
+		let selection2 = selector.select(&snapshot, 2).unwrap();
+		assert_eq!(selection1.len(), 2);
+		assert_eq!(selection2.len(), 2);
+		assert_ne!(selection1, selection2);
+		assert!(selection1.iter().chain(selection2.iter()).all(|node| snapshot.current_set.contains_key(node)));
+		assert_eq!(selector.select(&snapshot, 4), Err(Error::ConsensusUnreachable));
+	}
+
+	#[test]
+	fn round_robin_selector_skips_unavailable_nodes() {
+		let selector = RoundRobinNodeSelector::default();
+		selector.set_available(KeyServerId::from_low_u64_be(2), false);
+		let selector: Box<dyn NodeSelector<()>> = Box::new(selector);
+		let snapshot = snapshot(3);
+		for _ in 0..3 {
+			assert_eq!(
+				selector.select(&snapshot, 2),
+				Ok(vec![KeyServerId::from_low_u64_be(1), KeyServerId::from_low_u64_be(3)].into_iter().collect()),
+			);
 		}
+		assert_eq!(selector.select(&snapshot, 3), Err(Error::ConsensusUnreachable));
+	}
+
+	#[test]
+	fn weighted_selector_respects_availability() {
+		let selector = WeightedNodeSelector::new(vec![
+			(KeyServerId::from_low_u64_be(1), 10),
+			(KeyServerId::from_low_u64_be(2), 0),
+			(KeyServerId::from_low_u64_be(3), 20),
+			(KeyServerId::from_low_u64_be(4), 5),
+		].into_iter().collect());
+		let snapshot = snapshot(4);
+		assert_eq!(
+			selector.select(&snapshot, 2),
+			Ok(vec![KeyServerId::from_low_u64_be(1), KeyServerId::from_low_u64_be(3)].into_iter().collect()),
+		);
+		assert_eq!(selector.select(&snapshot, 3).unwrap().len(), 3);
+		assert_eq!(selector.select(&snapshot, 4), Err(Error::ConsensusUnreachable));
+	}
+
+	#[test]
+	fn migration_is_cancelled() {
+		let key_server_set = InMemoryKeyServerSet::default();
+		let migration_id = MigrationId::from_low_u64_be(42);
+		key_server_set.start_migration(migration_id);
+		assert_eq!(key_server_set.snapshot().migration.map(|migration| migration.id), Some(migration_id));
+
+		key_server_set.cancel_migration(MigrationId::from_low_u64_be(43));
+		assert!(key_server_set.snapshot().migration.is_some());
+		key_server_set.cancel_migration(migration_id);
+		assert_eq!(key_server_set.snapshot().migration, None);
+	}
+
+	#[test]
+	fn completed_migration_appears_in_history() {
+		let key_server_set = InMemoryKeyServerSet::default();
+		let migration_id1 = MigrationId::from_low_u64_be(42);
+		let migration_id2 = MigrationId::from_low_u64_be(43);
+		key_server_set.start_migration(migration_id1);
+		key_server_set.confirm_migration(migration_id1);
+		assert_eq!(key_server_set.migration_history(), vec![(migration_id1, MigrationStatus::Confirmed)]);
+
+		key_server_set.complete_migration(migration_id1);
+		assert_eq!(key_server_set.snapshot().migration, None);
+		key_server_set.start_migration(migration_id2);
+		assert_eq!(key_server_set.migration_history(), vec![
+			(migration_id1, MigrationStatus::Completed),
+			(migration_id2, MigrationStatus::Active),
+		]);
+	}
+
+	#[test]
+	fn all_migration_ids_are_returned_in_start_order() {
+		let key_server_set = InMemoryKeyServerSet::default();
+		let migration_id1 = MigrationId::from_low_u64_be(43);
+		let migration_id2 = MigrationId::from_low_u64_be(42);
+		key_server_set.start_migration(migration_id1);
+		key_server_set.complete_migration(migration_id1);
+		key_server_set.start_migration(migration_id2);
+		key_server_set.cancel_migration(migration_id2);
+		assert_eq!(key_server_set.all_migration_ids(), vec![migration_id1, migration_id2]);
 	}
 
-	fn start_migration(&self, _migration_id: MigrationId) {
-		// nothing to do here
+	#[test]
+	fn confirmed_migration_survives_restart() {
+		let path = std::env::temp_dir().join(format!("migration-journal-{:x}", H256::random()));
+		let nodes: BTreeMap<_, _> = vec![
+			(KeyServerId::from_low_u64_be(1), "127.0.0.1:8083".parse().unwrap()),
+		].into_iter().collect();
+		let migration_id = MigrationId::from_low_u64_be(42);
+
+		let key_server_set = InMemoryKeyServerSet::with_journal(
+			false,
+			nodes.clone(),
+			Arc::new(FileMigrationJournal::new(path.clone())),
+		).unwrap();
+		key_server_set.start_migration(migration_id);
+		key_server_set.confirm_migration(migration_id);
+		drop(key_server_set);
+
+		let key_server_set = InMemoryKeyServerSet::with_journal(
+			false,
+			nodes,
+			Arc::new(FileMigrationJournal::new(path.clone())),
+		).unwrap();
+		let migration = key_server_set.snapshot().migration.unwrap();
+		assert_eq!(migration.id, migration_id);
+		assert!(migration.is_confirmed);
+
+		std::fs::remove_file(path).unwrap();
 	}
 
-	fn confirm_migration(&self, _migration_id: MigrationId) {
-		// nothing to do here
+	#[test]
+	fn generation_is_restored_from_journal() {
+		let path = std::env::temp_dir().join(format!("migration-journal-{:x}", H256::random()));
+		let journal = Arc::new(FileMigrationJournal::new(path.clone()));
+		let key_server_set = InMemoryKeyServerSet::with_journal(false, Default::default(), journal.clone()).unwrap();
+		key_server_set.start_migration(MigrationId::from_low_u64_be(1));
+		key_server_set.confirm_migration(MigrationId::from_low_u64_be(1));
+		key_server_set.complete_migration(MigrationId::from_low_u64_be(1));
+		key_server_set.start_migration(MigrationId::from_low_u64_be(2));
+		let generation = key_server_set.snapshot().generation;
+		assert_eq!(generation, 4);
+		drop(key_server_set);
+
+		let key_server_set = InMemoryKeyServerSet::with_journal(false, Default::default(), journal).unwrap();
+		assert_eq!(key_server_set.snapshot().generation, generation);
+
+		std::fs::remove_file(path).unwrap();
+	}
+
+	struct FailingMigrationJournal;
+
+	impl MigrationJournal for FailingMigrationJournal {
+		fn record_started(&self, _: MigrationId) -> Result<(), Error> {
+			Ok(())
+		}
+
+		fn record_confirmed(&self, _: MigrationId) -> Result<(), Error> {
+			Err(Error::Io("disk is full".into()))
+		}
+
+		fn record_cancelled(&self, _: MigrationId) -> Result<(), Error> {
+			Ok(())
+		}
+
+		fn record_completed(&self, _: MigrationId) -> Result<(), Error> {
+			Ok(())
+		}
+
+		fn load(&self) -> Result<Vec<MigrationJournalEntry>, Error> {
+			Ok(Vec::new())
+		}
+	}
+
+	#[test]
+	fn migration_is_not_confirmed_if_journal_fails() {
+		let key_server_set = InMemoryKeyServerSet::with_journal(
+			false,
+			Default::default(),
+			Arc::new(FailingMigrationJournal),
+		).unwrap();
+		let migration_id = MigrationId::from_low_u64_be(42);
+		key_server_set.start_migration(migration_id);
+		let generation = key_server_set.snapshot().generation;
+
+		key_server_set.confirm_migration(migration_id);
+		let snapshot = key_server_set.snapshot();
+		assert!(!snapshot.migration.unwrap().is_confirmed);
+		assert_eq!(snapshot.generation, generation);
+		assert_eq!(key_server_set.migration_history(), vec![(migration_id, MigrationStatus::Active)]);
 	}
 }
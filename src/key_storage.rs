@@ -14,12 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::{BTreeMap, HashMap};
-use parking_lot::RwLock;
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, sync::Arc};
+use parking_lot::{Mutex, RwLock};
+use rand::RngCore;
 use tiny_keccak::{Hasher, Keccak};
 use ethereum_types::H256;
 use parity_crypto::publickey::{Address, Public, Secret};
-use crate::{error::Error, KeyServerId, ServerKeyId};
+use crate::{clock::{Clock, SystemClock}, error::Error, serialization::{Versioned, VersionedEncoding}, KeyServerId, ServerKeyId};
 
 /// Encrypted key share, stored by key storage on the single key server.
 #[derive(Debug, Default, Clone, PartialEq)]
@@ -41,7 +42,7 @@ pub struct KeyShare {
 /// Versioned portion of key share.
 #[derive(Debug, Clone, PartialEq)]
 pub struct KeyShareVersion {
-	/// Version hash (Keccak(time + id_numbers)).
+	/// Version hash (either Keccak(id_numbers), or Keccak(time + id_numbers)).
 	pub hash: H256,
 	/// Nodes ids numbers.
 	pub id_numbers: BTreeMap<KeyServerId, Secret>,
@@ -50,6 +51,15 @@ pub struct KeyShareVersion {
 }
 
 
+/// Key share builder. `build` fails if key share has no versions, or if its public is zero.
+#[derive(Debug, Default, Clone)]
+pub struct KeyShareBuilder {
+	key_share: KeyShare,
+}
+
+/// Id of the key along with its key share, encrypted with the recovery key.
+pub type EncryptedKeyShare = (ServerKeyId, Result<Vec<u8>, Error>);
+
 /// Secret Store key storage.
 pub trait KeyStorage: Send + Sync {
 	/// Insert new key share.
@@ -64,25 +74,196 @@ pub trait KeyStorage: Send + Sync {
 	fn clear(&self) -> Result<(), Error>;
 	/// Check if storage contains encryption key
 	fn contains(&self, key_id: &ServerKeyId) -> bool;
-	/// Iterate through storage.
+
+	/// Iterate through storage. Entries are yielded in ascending order of key ids.
 	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(ServerKeyId, KeyShare)> + 'a>;
+	/// Iterate through ids of all keys in the storage. Unlike `iter`, key shares aren't
+	/// read, so it is cheaper for storages that are able to list ids only. Ids are yielded
+	/// in ascending order.
+	fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item=ServerKeyId> + 'a> {
+		Box::new(self.iter().map(|(key_id, _)| key_id))
+	}
+
+	/// Prune versions of all key shares, so that at most `keep_last` most recent versions
+	/// of every key share are retained. See `KeyShare::prune_versions`.
+	fn prune_all_versions(&self, keep_last: usize) -> Result<(), Error> {
+		let keys_to_prune = self.iter()
+			.filter(|(_, key_share)| key_share.versions.len() > keep_last.max(1))
+			.collect::<Vec<_>>();
+		for (key_id, mut key_share) in keys_to_prune {
+			key_share.prune_versions(keep_last);
+			self.update(key_id, key_share)?;
+		}
+
+		Ok(())
+	}
+
+	/// Update key share, but only if its current value is equal to `expected`. Returns
+	/// true if the key share has been updated.
+	///
+	/// Default implementation isn't atomic, so implementations that could be accessed
+	/// concurrently must override it.
+	fn update_if(&self, key_id: ServerKeyId, expected: &KeyShare, key: KeyShare) -> Result<bool, Error> {
+		if self.get(&key_id)?.as_ref() != Some(expected) {
+			return Ok(false);
+		}
+
+		self.update(key_id, key).map(|_| true)
+	}
+
+	/// Get time (seconds since Unix epoch) when the key has been removed from the storage.
+	/// Storages that are supporting tombstones are rejecting `insert` and `update` of
+	/// removed keys with `Error::KeyTombstoned`, so that stale messages can't resurrect
+	/// removed keys. By default, tombstones aren't supported.
+	fn tombstoned_at(&self, _key_id: &ServerKeyId) -> Result<Option<u64>, Error> {
+		Ok(None)
+	}
+	/// Forget that the key has been removed, so that it could be stored again.
+	fn clear_tombstone(&self, _key_id: &ServerKeyId) -> Result<(), Error> {
+		Ok(())
+	}
+	/// Insert key share, even if the key has been removed before. This is a separate
+	/// method (rather than a flag of `insert`), so that regular inserts can't bypass
+	/// tombstones by accident.
+	fn force_insert(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+		self.clear_tombstone(&key_id)?;
+		self.insert(key_id, key)
+	}
+
+	/// Iterate through storage, yielding canonically encoded key shares, encrypted with
+	/// the (offline) recovery public key. Use `decrypt_exported_share` to decrypt them.
+	/// Encryption only fails if the recovery public isn't a valid EC point.
+	fn iter_encrypted<'a>(
+		&'a self,
+		recovery_public: Public,
+	) -> Box<dyn Iterator<Item=EncryptedKeyShare> + 'a> {
+		Box::new(self.iter().map(move |(key_id, key_share)| (
+			key_id,
+			crate::ecies_encrypt(&recovery_public, &key_share.to_canonical_bytes()),
+		)))
+	}
+}
+
+/// Decrypt key share, exported with `KeyStorage::iter_encrypted`, using recovery secret.
+pub fn decrypt_exported_share(recovery_secret: &Secret, encrypted: &[u8]) -> Result<KeyShare, Error> {
+	KeyShare::from_canonical_bytes(&crate::decrypt_with_secret(recovery_secret, encrypted)?)
 }
 
-/// In-memory key storage implementation.
-#[derive(Debug, Default)]
+/// Get ids of keys that have a share on given node in their latest version. These keys
+/// need to be re-shared when the node leaves the key server set.
+pub fn keys_hosted_by(storage: &dyn KeyStorage, node: &KeyServerId) -> Result<Vec<ServerKeyId>, Error> {
+	let mut key_ids = Vec::new();
+	for (key_id, key_share) in storage.iter() {
+		if key_share.last_version()?.id_numbers.contains_key(node) {
+			key_ids.push(key_id);
+		}
+	}
+	Ok(key_ids)
+}
+
+/// Get ids of `expected` keys that are missing from the storage.
+pub fn missing_keys(storage: &dyn KeyStorage, expected: &BTreeSet<ServerKeyId>) -> Result<BTreeSet<ServerKeyId>, Error> {
+	Ok(expected.iter().filter(|key_id| !storage.contains(key_id)).cloned().collect())
+}
+
+/// Get ids of keys from the storage that are not in the `expected` set.
+pub fn extra_keys(storage: &dyn KeyStorage, expected: &BTreeSet<ServerKeyId>) -> Result<BTreeSet<ServerKeyId>, Error> {
+	Ok(storage.iter_ids().filter(|key_id| !expected.contains(key_id)).collect())
+}
+
+/// Approximate size of the message that is carrying single key share portion
+/// during migration (message header, key id, node id and secret).
+const APPROX_MIGRATION_MESSAGE_SIZE: usize = 128;
+
+/// Estimated volume of the migration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationEstimate {
+	/// Number of keys that need to be re-shared.
+	pub keys_affected: usize,
+	/// Approximate number of messages that will be sent during migration.
+	pub approx_messages: usize,
+	/// Approximate number of bytes that will be sent during migration.
+	pub approx_bytes: usize,
+}
+
+/// Estimate volume of migration from `old_set` to `new_set`.
+///
+/// Key needs to be re-shared if nodes that are holding its latest version are not
+/// the same as `new_set`. During re-sharing, every node of the old set that is holding
+/// the key share sends a message to every node of the new set.
+pub fn estimate_migration_volume(
+	storage: &dyn KeyStorage,
+	old_set: &BTreeSet<KeyServerId>,
+	new_set: &BTreeSet<KeyServerId>,
+) -> Result<MigrationEstimate, Error> {
+	let mut estimate = MigrationEstimate::default();
+	for (_, key_share) in storage.iter() {
+		let holding_nodes = key_share.last_version()?.id_numbers.keys().cloned().collect::<BTreeSet<_>>();
+		if holding_nodes == *new_set {
+			continue;
+		}
+
+		estimate.keys_affected += 1;
+		estimate.approx_messages += holding_nodes.intersection(old_set).count() * new_set.len();
+	}
+	estimate.approx_bytes = estimate.approx_messages * APPROX_MIGRATION_MESSAGE_SIZE;
+	Ok(estimate)
+}
+
+/// In-memory key storage implementation. Removed keys are tombstoned. Clearing the
+/// storage is a reset (e.g. before importing the backup), so cleared keys aren't
+/// tombstoned.
 pub struct InMemoryKeyStorage {
-	keys: RwLock<HashMap<ServerKeyId, KeyShare>>,
+	keys: RwLock<BTreeMap<ServerKeyId, KeyShare>>,
+	/// Always locked after `keys`.
+	tombstones: RwLock<BTreeMap<ServerKeyId, u64>>,
+	clock: Arc<dyn Clock>,
+}
+
+impl Default for InMemoryKeyStorage {
+	fn default() -> Self {
+		InMemoryKeyStorage::with_clock(Arc::new(SystemClock))
+	}
+}
+
+impl std::fmt::Debug for InMemoryKeyStorage {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("InMemoryKeyStorage")
+			.field("keys", &self.keys)
+			.field("tombstones", &self.tombstones)
+			.finish()
+	}
+}
+
+impl InMemoryKeyStorage {
+	/// Create new storage that is using given clock to timestamp tombstones.
+	pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+		InMemoryKeyStorage {
+			keys: Default::default(),
+			tombstones: Default::default(),
+			clock,
+		}
+	}
+
+	/// Insert or update key share if it isn't tombstoned.
+	fn write(&self, op: &str, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+		let mut keys = self.keys.write();
+		if self.tombstones.read().contains_key(&key_id) {
+			return Err(Error::KeyTombstoned.with_key_context(op, &key_id));
+		}
+
+		keys.insert(key_id, key);
+		Ok(())
+	}
 }
 
 impl KeyStorage for InMemoryKeyStorage {
 	fn insert(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
-		self.keys.write().insert(key_id, key);
-		Ok(())
+		self.write("insert", key_id, key)
 	}
 
 	fn update(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
-		self.keys.write().insert(key_id, key);
-		Ok(())
+		self.write("update", key_id, key)
 	}
 
 	fn get(&self, key_id: &ServerKeyId) -> Result<Option<KeyShare>, Error> {
@@ -90,7 +271,9 @@ impl KeyStorage for InMemoryKeyStorage {
 	}
 
 	fn remove(&self, key_id: &ServerKeyId) -> Result<(), Error> {
-		self.keys.write().remove(key_id);
+		let mut keys = self.keys.write();
+		keys.remove(key_id);
+		self.tombstones.write().insert(*key_id, self.clock.now());
 		Ok(())
 	}
 
@@ -103,9 +286,246 @@ impl KeyStorage for InMemoryKeyStorage {
 		self.keys.read().contains_key(key_id)
 	}
 
+	fn update_if(&self, key_id: ServerKeyId, expected: &KeyShare, key: KeyShare) -> Result<bool, Error> {
+		let mut keys = self.keys.write();
+		match keys.get_mut(&key_id) {
+			Some(current) if current == expected => {
+				*current = key;
+				Ok(true)
+			},
+			_ => Ok(false),
+		}
+	}
+
+	fn tombstoned_at(&self, key_id: &ServerKeyId) -> Result<Option<u64>, Error> {
+		Ok(self.tombstones.read().get(key_id).cloned())
+	}
+
+	fn clear_tombstone(&self, key_id: &ServerKeyId) -> Result<(), Error> {
+		self.tombstones.write().remove(key_id);
+		Ok(())
+	}
+
+	fn force_insert(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+		let mut keys = self.keys.write();
+		self.tombstones.write().remove(&key_id);
+		keys.insert(key_id, key);
+		Ok(())
+	}
+
 	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(ServerKeyId, KeyShare)> + 'a> {
 		Box::new(self.keys.read().clone().into_iter())
 	}
+
+	fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item=ServerKeyId> + 'a> {
+		Box::new(self.keys.read().keys().cloned().collect::<Vec<_>>().into_iter())
+	}
+}
+
+/// Read-only access to the key storage.
+pub trait KeyStorageReader: Send + Sync {
+	/// Get existing key share.
+	fn get(&self, key_id: &ServerKeyId) -> Result<Option<KeyShare>, Error>;
+	/// Check if storage contains encryption key
+	fn contains(&self, key_id: &ServerKeyId) -> bool;
+	/// Iterate through storage. Entries are yielded in ascending order of key ids.
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(ServerKeyId, KeyShare)> + 'a>;
+	/// Get number of keys in the storage.
+	fn count(&self) -> usize;
+}
+
+/// Key storage wrapper that only provides read access to the inner storage. Unlike
+/// the inner storage, it could be passed to the code that must never modify key shares:
+///
+/// ```compile_fail
+/// use parity_secretstore_primitives::key_storage::{InMemoryKeyStorage, ReadOnlyKeyStorage};
+///
+/// let storage = ReadOnlyKeyStorage::new(InMemoryKeyStorage::default());
+/// storage.remove(&Default::default());
+/// ```
+pub struct ReadOnlyKeyStorage<S> {
+	storage: S,
+}
+
+impl<S: KeyStorage> ReadOnlyKeyStorage<S> {
+	/// Create new read-only key storage.
+	pub fn new(storage: S) -> Self {
+		ReadOnlyKeyStorage {
+			storage,
+		}
+	}
+}
+
+impl<S: KeyStorage> KeyStorageReader for ReadOnlyKeyStorage<S> {
+	fn get(&self, key_id: &ServerKeyId) -> Result<Option<KeyShare>, Error> {
+		self.storage.get(key_id)
+	}
+
+	fn contains(&self, key_id: &ServerKeyId) -> bool {
+		self.storage.contains(key_id)
+	}
+
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(ServerKeyId, KeyShare)> + 'a> {
+		self.storage.iter()
+	}
+
+	fn count(&self) -> usize {
+		self.storage.iter_ids().count()
+	}
+}
+
+/// Key storage wrapper that is caching recently read key shares.
+///
+/// At most `capacity` key shares are cached. When cache is full, least recently
+/// used key share is evicted.
+pub struct CachingKeyStorage<S> {
+	storage: S,
+	cache: Mutex<LruCache>,
+}
+
+/// Cache of recently used key shares.
+struct LruCache {
+	capacity: usize,
+	next_access: u64,
+	entries: HashMap<ServerKeyId, (u64, KeyShare)>,
+	accesses: BTreeMap<u64, ServerKeyId>,
+}
+
+impl<S: KeyStorage> CachingKeyStorage<S> {
+	/// Create new caching key storage.
+	pub fn new(storage: S, capacity: usize) -> Self {
+		CachingKeyStorage {
+			storage,
+			cache: Mutex::new(LruCache {
+				capacity,
+				next_access: 0,
+				entries: HashMap::new(),
+				accesses: BTreeMap::new(),
+			}),
+		}
+	}
+
+	/// Get reference to the inner storage.
+	pub fn inner(&self) -> &S {
+		&self.storage
+	}
+}
+
+impl<S: KeyStorage> KeyStorage for CachingKeyStorage<S> {
+	// cache lock is held during inner storage calls, so that concurrent get
+	// can't put stale data into the cache
+
+	fn insert(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+		let mut cache = self.cache.lock();
+		cache.remove(&key_id);
+		self.storage.insert(key_id, key).map_err(|error| error.with_key_context("insert", &key_id))
+	}
+
+	fn update(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+		let mut cache = self.cache.lock();
+		cache.remove(&key_id);
+		self.storage.update(key_id, key).map_err(|error| error.with_key_context("update", &key_id))
+	}
+
+	fn get(&self, key_id: &ServerKeyId) -> Result<Option<KeyShare>, Error> {
+		let mut cache = self.cache.lock();
+		if let Some(key) = cache.get(key_id) {
+			return Ok(Some(key));
+		}
+
+		let key = self.storage.get(key_id).map_err(|error| error.with_key_context("get", key_id))?;
+		if let Some(key) = key.as_ref() {
+			cache.insert(*key_id, key.clone());
+		}
+		Ok(key)
+	}
+
+	fn remove(&self, key_id: &ServerKeyId) -> Result<(), Error> {
+		let mut cache = self.cache.lock();
+		cache.remove(key_id);
+		self.storage.remove(key_id).map_err(|error| error.with_key_context("remove", key_id))
+	}
+
+	fn clear(&self) -> Result<(), Error> {
+		let mut cache = self.cache.lock();
+		cache.clear();
+		self.storage.clear()
+	}
+
+	fn contains(&self, key_id: &ServerKeyId) -> bool {
+		self.cache.lock().entries.contains_key(key_id) || self.storage.contains(key_id)
+	}
+
+	fn update_if(&self, key_id: ServerKeyId, expected: &KeyShare, key: KeyShare) -> Result<bool, Error> {
+		let mut cache = self.cache.lock();
+		cache.remove(&key_id);
+		self.storage.update_if(key_id, expected, key).map_err(|error| error.with_key_context("update_if", &key_id))
+	}
+
+	fn tombstoned_at(&self, key_id: &ServerKeyId) -> Result<Option<u64>, Error> {
+		self.storage.tombstoned_at(key_id).map_err(|error| error.with_key_context("tombstoned_at", key_id))
+	}
+
+	fn clear_tombstone(&self, key_id: &ServerKeyId) -> Result<(), Error> {
+		self.storage.clear_tombstone(key_id).map_err(|error| error.with_key_context("clear_tombstone", key_id))
+	}
+
+	fn force_insert(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+		let mut cache = self.cache.lock();
+		cache.remove(&key_id);
+		self.storage.force_insert(key_id, key).map_err(|error| error.with_key_context("force_insert", &key_id))
+	}
+
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(ServerKeyId, KeyShare)> + 'a> {
+		self.storage.iter()
+	}
+
+	fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item=ServerKeyId> + 'a> {
+		self.storage.iter_ids()
+	}
+}
+
+impl LruCache {
+	fn get(&mut self, key_id: &ServerKeyId) -> Option<KeyShare> {
+		let access = self.next_access;
+		let (last_access, key) = self.entries.get_mut(key_id)?;
+		self.accesses.remove(last_access);
+		self.accesses.insert(access, *key_id);
+		*last_access = access;
+		self.next_access += 1;
+		Some(key.clone())
+	}
+
+	fn insert(&mut self, key_id: ServerKeyId, key: KeyShare) {
+		if self.capacity == 0 {
+			return;
+		}
+
+		self.remove(&key_id);
+		if self.entries.len() == self.capacity {
+			let least_recently_used = self.accesses.keys().next().cloned();
+			if let Some(access) = least_recently_used {
+				if let Some(key_id) = self.accesses.remove(&access) {
+					self.entries.remove(&key_id);
+				}
+			}
+		}
+
+		self.accesses.insert(self.next_access, key_id);
+		self.entries.insert(key_id, (self.next_access, key));
+		self.next_access += 1;
+	}
+
+	fn remove(&mut self, key_id: &ServerKeyId) {
+		if let Some((access, _)) = self.entries.remove(key_id) {
+			self.accesses.remove(&access);
+		}
+	}
+
+	fn clear(&mut self) {
+		self.entries.clear();
+		self.accesses.clear();
+	}
 }
 
 impl KeyShare {
@@ -122,7 +542,264 @@ impl KeyShare {
 			.iter()
 			.rev()
 			.find(|v| &v.hash == version)
-			.ok_or_else(|| Error::Database("key version is not found".into()))
+			.ok_or(Error::UnknownKeyVersion)
+	}
+
+	/// Check hashes of all versions. Hash of the version, which creation time is found in
+	/// `timestamps`, must be Keccak(time + id_numbers). Hashes of other versions must be
+	/// Keccak(id_numbers).
+	pub fn verify_all_version_hashes(&self, timestamps: &BTreeMap<H256, u64>) -> Result<(), Error> {
+		for version in &self.versions {
+			let is_valid = match timestamps.get(&version.hash) {
+				Some(timestamp) => version.verify_hash(*timestamp),
+				None => version.verify_untimed_hash(),
+			};
+			if !is_valid {
+				return Err(Error::InvalidKeyShare(format!("hash of version {:?} mismatch", version.hash)));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Remove all versions except `keep_last` most recent versions. The last version is
+	/// always retained, even if `keep_last` is zero.
+	pub fn prune_versions(&mut self, keep_last: usize) {
+		let keep_last = keep_last.max(1);
+		if self.versions.len() > keep_last {
+			self.versions.drain(..self.versions.len() - keep_last);
+		}
+	}
+
+	/// Merge metadata of the same key share, read from other node. Author, threshold, public
+	/// and common point of both shares must be the same. Id numbers of versions with the same
+	/// hash are merged and versions that are unknown to this share are appended. Secret shares
+	/// of this share are left untouched.
+	pub fn merge_metadata(&mut self, other: &KeyShare) -> Result<(), Error> {
+		if self.author != other.author {
+			return Err(Error::ConflictingKeyShares("author mismatch".into()));
+		}
+		if self.threshold != other.threshold {
+			return Err(Error::ConflictingKeyShares("threshold mismatch".into()));
+		}
+		if self.public != other.public {
+			return Err(Error::ConflictingKeyShares("public mismatch".into()));
+		}
+		if self.common_point != other.common_point {
+			return Err(Error::ConflictingKeyShares("common point mismatch".into()));
+		}
+
+		// check everything before modifying, so that the share is left untouched on error
+		for other_version in &other.versions {
+			let version = match self.versions.iter().find(|version| version.hash == other_version.hash) {
+				Some(version) => version,
+				None => continue,
+			};
+			for (node, other_id_number) in &other_version.id_numbers {
+				match version.id_numbers.get(node) {
+					Some(id_number) if id_number != other_id_number => return Err(Error::ConflictingKeyShares(
+						format!("id number of node {:?} in version {:?} mismatch", node, version.hash),
+					)),
+					_ => (),
+				}
+			}
+		}
+
+		for other_version in &other.versions {
+			match self.versions.iter_mut().find(|version| version.hash == other_version.hash) {
+				Some(version) => version.id_numbers.extend(
+					other_version.id_numbers.iter().map(|(node, id_number)| (*node, id_number.clone())),
+				),
+				None => self.versions.push(other_version.clone()),
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Get nodes that are holding shares of any version of this key.
+	pub fn holding_nodes(&self) -> BTreeSet<KeyServerId> {
+		self.versions
+			.iter()
+			.flat_map(|version| version.id_numbers.keys().cloned())
+			.collect()
+	}
+
+	/// Get nodes that are holding shares of given version of this key.
+	pub fn holding_nodes_at(&self, version: &H256) -> Result<BTreeSet<KeyServerId>, Error> {
+		self.version(version).map(|version| version.id_numbers.keys().cloned().collect())
+	}
+
+	/// Encode key share using canonical encoding. This encoding is intended to be used
+	/// as an interchange format between different key storage implementations.
+	pub fn to_canonical_bytes(&self) -> Vec<u8> {
+		Versioned::new(self.clone()).encode()
+	}
+
+	/// Decode key share, encoded with `to_canonical_bytes`.
+	pub fn from_canonical_bytes(encoded: &[u8]) -> Result<Self, Error> {
+		Versioned::decode(encoded).map(|versioned| versioned.inner)
+	}
+}
+
+/// Canonical key share encoding (version 1) is: author (20 bytes), threshold (8 bytes, BE),
+/// public (64 bytes), optional common point and optional encrypted point (1 byte flag,
+/// followed by 64 bytes if the flag is set), number of versions (4 bytes, BE) and all
+/// versions. Every version is: hash (32 bytes), number of nodes (4 bytes, BE), id numbers
+/// of nodes (20 + 32 bytes each, sorted by node id) and secret share (32 bytes).
+impl VersionedEncoding for KeyShare {
+	const VERSION: u8 = 1;
+
+	fn encode_payload(&self) -> Vec<u8> {
+		let mut encoded = Vec::new();
+		encoded.extend_from_slice(self.author.as_bytes());
+		encoded.extend_from_slice(&(self.threshold as u64).to_be_bytes());
+		encoded.extend_from_slice(self.public.as_bytes());
+		for point in &[self.common_point, self.encrypted_point] {
+			match *point {
+				Some(ref point) => {
+					encoded.push(1);
+					encoded.extend_from_slice(point.as_bytes());
+				},
+				None => encoded.push(0),
+			}
+		}
+		encoded.extend_from_slice(&(self.versions.len() as u32).to_be_bytes());
+		for version in &self.versions {
+			encoded.extend_from_slice(version.hash.as_bytes());
+			encoded.extend_from_slice(&(version.id_numbers.len() as u32).to_be_bytes());
+			for (node, id_number) in &version.id_numbers {
+				encoded.extend_from_slice(node.as_bytes());
+				encoded.extend_from_slice(id_number.as_bytes());
+			}
+			encoded.extend_from_slice(version.secret_share.as_bytes());
+		}
+		encoded
+	}
+
+	fn decode_payload(encoded: &[u8]) -> Result<Self, Error> {
+		let mut reader = CanonicalReader(encoded);
+		let author = Address::from_slice(reader.read(20)?);
+		let threshold = reader.read_u64()? as usize;
+		let public = Public::from_slice(reader.read(64)?);
+		let common_point = reader.read_optional_public()?;
+		let encrypted_point = reader.read_optional_public()?;
+		let versions_count = reader.read_u32()?;
+		let mut versions = Vec::new();
+		for _ in 0..versions_count {
+			let hash = H256::from_slice(reader.read(32)?);
+			let nodes_count = reader.read_u32()?;
+			let mut id_numbers = BTreeMap::new();
+			for _ in 0..nodes_count {
+				let node = KeyServerId::from_slice(reader.read(20)?);
+				let id_number = Secret::from(H256::from_slice(reader.read(32)?));
+				id_numbers.insert(node, id_number);
+			}
+			let secret_share = Secret::from(H256::from_slice(reader.read(32)?));
+			versions.push(KeyShareVersion {
+				hash,
+				id_numbers,
+				secret_share,
+			});
+		}
+
+		if !reader.0.is_empty() {
+			return Err(Error::Serde("unexpected trailing bytes in encoded key share".into()));
+		}
+
+		Ok(KeyShare {
+			author,
+			threshold,
+			public,
+			common_point,
+			encrypted_point,
+			versions,
+		})
+	}
+}
+
+/// Reader of canonically encoded data (key shares, key server set snapshots).
+pub(crate) struct CanonicalReader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> CanonicalReader<'a> {
+	pub(crate) fn read(&mut self, size: usize) -> Result<&'a [u8], Error> {
+		if self.0.len() < size {
+			return Err(Error::Serde("unexpected end of encoded data".into()));
+		}
+
+		let (data, rest) = self.0.split_at(size);
+		self.0 = rest;
+		Ok(data)
+	}
+
+	pub(crate) fn read_u32(&mut self) -> Result<u32, Error> {
+		let mut data = [0u8; 4];
+		data.copy_from_slice(self.read(4)?);
+		Ok(u32::from_be_bytes(data))
+	}
+
+	pub(crate) fn read_u64(&mut self) -> Result<u64, Error> {
+		let mut data = [0u8; 8];
+		data.copy_from_slice(self.read(8)?);
+		Ok(u64::from_be_bytes(data))
+	}
+
+	pub(crate) fn read_optional_public(&mut self) -> Result<Option<Public>, Error> {
+		match self.read(1)?[0] {
+			0 => Ok(None),
+			1 => Ok(Some(Public::from_slice(self.read(64)?))),
+			flag => Err(Error::Serde(format!("invalid optional point flag: {}", flag))),
+		}
+	}
+}
+
+impl KeyShareBuilder {
+	/// Create new key share builder.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Set author of the key.
+	pub fn author(mut self, author: Address) -> Self {
+		self.key_share.author = author;
+		self
+	}
+
+	/// Set key threshold.
+	pub fn threshold(mut self, threshold: usize) -> Self {
+		self.key_share.threshold = threshold;
+		self
+	}
+
+	/// Set server public key.
+	pub fn public(mut self, public: Public) -> Self {
+		self.key_share.public = public;
+		self
+	}
+
+	/// Set common point and encrypted point of the document key.
+	pub fn document_key(mut self, common_point: Public, encrypted_point: Public) -> Self {
+		self.key_share.common_point = Some(common_point);
+		self.key_share.encrypted_point = Some(encrypted_point);
+		self
+	}
+
+	/// Add key share version. Versions must be added in the order they have been created.
+	pub fn version(mut self, version: KeyShareVersion) -> Self {
+		self.key_share.versions.push(version);
+		self
+	}
+
+	/// Build key share.
+	pub fn build(self) -> Result<KeyShare, Error> {
+		if self.key_share.versions.is_empty() {
+			return Err(Error::InvalidKeyShare("key share has no versions".into()));
+		}
+		if self.key_share.public.is_zero() {
+			return Err(Error::InvalidKeyShare("public is zero".into()));
+		}
+
+		Ok(self.key_share)
 	}
 }
 
@@ -136,6 +813,42 @@ impl KeyShareVersion {
 		}
 	}
 
+	/// Create new key share version, which hash also depends on the current time.
+	/// So versions with the same id numbers, created at different time, have different hashes.
+	pub fn new_at(id_numbers: BTreeMap<KeyServerId, Secret>, secret_share: Secret, clock: &dyn Clock) -> Self {
+		KeyShareVersion {
+			hash: Self::timed_data_hash(clock.now(), &id_numbers),
+			id_numbers,
+			secret_share,
+		}
+	}
+
+	/// Check that the version hash is Keccak(time + id_numbers), where time is the
+	/// `timestamp` of the version creation.
+	pub fn verify_hash(&self, timestamp: u64) -> bool {
+		self.hash == Self::timed_data_hash(timestamp, &self.id_numbers)
+	}
+
+	/// Check that the version hash is Keccak(id_numbers).
+	fn verify_untimed_hash(&self) -> bool {
+		self.hash == Self::data_hash(self.id_numbers.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes())))
+	}
+
+	/// Create new version for the new set of nodes. Every node of the new set gets fresh
+	/// unique random id number. Secret share is preserved and hash is recomputed.
+	pub fn with_new_nodes(&self, new_nodes: &BTreeSet<KeyServerId>, rng: &mut impl RngCore) -> KeyShareVersion {
+		let mut used_id_numbers = BTreeSet::new();
+		let id_numbers = new_nodes.iter()
+			.map(|node| loop {
+				let id_number = crate::random_secret(rng);
+				if used_id_numbers.insert(*id_number) {
+					break (*node, id_number);
+				}
+			})
+			.collect();
+		KeyShareVersion::new(id_numbers, self.secret_share.clone())
+	}
+
 	/// Calculate hash of given version data.
 	pub fn data_hash<'a, I>(id_numbers: I) -> H256 where I: Iterator<Item=(&'a [u8], &'a [u8])> {
 		let mut nodes_keccak = Keccak::v256();
@@ -150,4 +863,537 @@ impl KeyShareVersion {
 
 		nodes_keccak_value.into()
 	}
+
+	/// Calculate hash of given version data, created at given time.
+	fn timed_data_hash(time: u64, id_numbers: &BTreeMap<KeyServerId, Secret>) -> H256 {
+		let time = time.to_be_bytes();
+		Self::data_hash(std::iter::once((&time[..], &[][..]))
+			.chain(id_numbers.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes()))))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+	use rand::{SeedableRng, rngs::StdRng};
+	use rustc_hex::FromHex;
+	use crate::testing::MockClock;
+	use super::*;
+
+	#[derive(Default)]
+	struct CountingKeyStorage {
+		storage: InMemoryKeyStorage,
+		gets: AtomicUsize,
+		iters: AtomicUsize,
+		fail_gets: AtomicBool,
+	}
+
+	impl KeyStorage for CountingKeyStorage {
+		fn insert(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+			self.storage.insert(key_id, key)
+		}
+
+		fn update(&self, key_id: ServerKeyId, key: KeyShare) -> Result<(), Error> {
+			self.storage.update(key_id, key)
+		}
+
+		fn get(&self, key_id: &ServerKeyId) -> Result<Option<KeyShare>, Error> {
+			self.gets.fetch_add(1, Ordering::SeqCst);
+			if self.fail_gets.load(Ordering::SeqCst) {
+				return Err(Error::Database("read failed".into()));
+			}
+			self.storage.get(key_id)
+		}
+
+		fn remove(&self, key_id: &ServerKeyId) -> Result<(), Error> {
+			self.storage.remove(key_id)
+		}
+
+		fn clear(&self) -> Result<(), Error> {
+			self.storage.clear()
+		}
+
+		fn contains(&self, key_id: &ServerKeyId) -> bool {
+			self.storage.contains(key_id)
+		}
+
+		fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(ServerKeyId, KeyShare)> + 'a> {
+			self.iters.fetch_add(1, Ordering::SeqCst);
+			self.storage.iter()
+		}
+
+		fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item=ServerKeyId> + 'a> {
+			self.storage.iter_ids()
+		}
+
+		fn tombstoned_at(&self, key_id: &ServerKeyId) -> Result<Option<u64>, Error> {
+			self.storage.tombstoned_at(key_id)
+		}
+
+		fn clear_tombstone(&self, key_id: &ServerKeyId) -> Result<(), Error> {
+			self.storage.clear_tombstone(key_id)
+		}
+	}
+
+	fn caching_storage(capacity: usize) -> CachingKeyStorage<CountingKeyStorage> {
+		let storage = CachingKeyStorage::new(CountingKeyStorage::default(), capacity);
+		for i in 1..=3 {
+			storage.insert(ServerKeyId::from_low_u64_be(i), KeyShare { threshold: i as usize, ..Default::default() })
+				.unwrap();
+		}
+		storage
+	}
+
+	fn inner_gets(storage: &CachingKeyStorage<CountingKeyStorage>) -> usize {
+		storage.inner().gets.load(Ordering::SeqCst)
+	}
+
+	#[test]
+	fn key_ids_are_iterated_without_reading_shares() {
+		let storage = caching_storage(2);
+		let mut key_ids = storage.iter_ids().collect::<Vec<_>>();
+		key_ids.sort();
+		assert_eq!(key_ids, (1..=3).map(ServerKeyId::from_low_u64_be).collect::<Vec<_>>());
+		assert_eq!(inner_gets(&storage), 0);
+		assert_eq!(storage.inner().iters.load(Ordering::SeqCst), 0);
+	}
+
+	#[test]
+	fn failed_operation_error_has_key_context() {
+		let storage = caching_storage(2);
+		storage.inner().fail_gets.store(true, Ordering::SeqCst);
+
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		let error = storage.get(&key_id).unwrap_err();
+		assert_eq!(error, Error::Storage {
+			op: "get".into(),
+			key_id,
+			error: Box::new(Error::Database("read failed".into())),
+		});
+		assert!(error.to_string().contains("get"));
+		assert!(error.to_string().contains(&format!("{:?}", key_id)));
+	}
+
+	#[test]
+	fn caching_key_storage_hits_and_misses() {
+		let storage = caching_storage(2);
+		let key_id = |i| ServerKeyId::from_low_u64_be(i);
+
+		assert_eq!(storage.get(&key_id(1)).unwrap().unwrap().threshold, 1);
+		assert_eq!(storage.get(&key_id(1)).unwrap().unwrap().threshold, 1);
+		assert_eq!(inner_gets(&storage), 1);
+
+		// key 1 is used more recently than key 2, so key 2 is evicted when key 3 is read
+		storage.get(&key_id(2)).unwrap();
+		storage.get(&key_id(1)).unwrap();
+		storage.get(&key_id(3)).unwrap();
+		assert_eq!(inner_gets(&storage), 3);
+		storage.get(&key_id(1)).unwrap();
+		assert_eq!(inner_gets(&storage), 3);
+		storage.get(&key_id(2)).unwrap();
+		assert_eq!(inner_gets(&storage), 4);
+
+		assert_eq!(storage.get(&key_id(4)).unwrap(), None);
+	}
+
+	#[test]
+	fn caching_key_storage_invalidates_updated_key() {
+		let storage = caching_storage(2);
+		let key_id = ServerKeyId::from_low_u64_be(1);
+
+		storage.get(&key_id).unwrap();
+		storage.update(key_id, KeyShare { threshold: 10, ..Default::default() }).unwrap();
+		assert_eq!(storage.get(&key_id).unwrap().unwrap().threshold, 10);
+		assert_eq!(inner_gets(&storage), 2);
+	}
+
+	#[test]
+	fn caching_key_storage_is_cleared() {
+		let storage = caching_storage(2);
+		let key_id = ServerKeyId::from_low_u64_be(1);
+
+		storage.get(&key_id).unwrap();
+		storage.clear().unwrap();
+		assert_eq!(storage.get(&key_id).unwrap(), None);
+		assert!(!storage.contains(&key_id));
+	}
+
+	fn key_share() -> KeyShare {
+		KeyShare {
+			author: Address::from_low_u64_be(1),
+			threshold: 1,
+			public: Public::from_low_u64_be(2),
+			common_point: Some(Public::from_low_u64_be(3)),
+			encrypted_point: None,
+			versions: vec![KeyShareVersion {
+				hash: H256::from_low_u64_be(4),
+				id_numbers: vec![
+					(KeyServerId::from_low_u64_be(5), Secret::from(H256::from_low_u64_be(6))),
+				].into_iter().collect(),
+				secret_share: Secret::from(H256::from_low_u64_be(7)),
+			}],
+		}
+	}
+
+	fn multi_version_key_share(versions: u64) -> KeyShare {
+		let mut key_share = key_share();
+		for version in 1..versions {
+			let mut new_version = key_share.versions[0].clone();
+			new_version.hash = H256::from_low_u64_be(100 + version);
+			key_share.versions.push(new_version);
+		}
+		key_share
+	}
+
+	#[test]
+	fn key_share_is_built() {
+		let expected = key_share();
+		let key_share = KeyShareBuilder::new()
+			.author(expected.author)
+			.threshold(expected.threshold)
+			.public(expected.public)
+			.version(expected.versions[0].clone())
+			.build()
+			.unwrap();
+		assert_eq!(key_share, KeyShare { common_point: None, ..expected });
+	}
+
+	#[test]
+	fn key_share_without_version_is_not_built() {
+		assert_eq!(
+			KeyShareBuilder::new().public(Public::from_low_u64_be(2)).build(),
+			Err(Error::InvalidKeyShare("key share has no versions".into())),
+		);
+		assert_eq!(
+			KeyShareBuilder::new().version(key_share().versions[0].clone()).build(),
+			Err(Error::InvalidKeyShare("public is zero".into())),
+		);
+	}
+
+	#[test]
+	fn version_hash_depends_on_clock_time() {
+		let clock = MockClock::new(1_000);
+		let version = || KeyShareVersion::new_at(
+			key_share().versions[0].id_numbers.clone(),
+			Secret::from(H256::from_low_u64_be(7)),
+			&clock,
+		);
+
+		let version1 = version();
+		assert_eq!(version1.hash, version().hash);
+		clock.advance(std::time::Duration::from_secs(1));
+		assert_ne!(version1.hash, version().hash);
+		clock.set(1_000);
+		assert_eq!(version1.hash, version().hash);
+	}
+
+	#[test]
+	fn version_hashes_are_verified() {
+		let clock = MockClock::new(1_000);
+		let mut key_share = key_share();
+		key_share.versions = vec![
+			KeyShareVersion::new(key_share.versions[0].id_numbers.clone(), Secret::from(H256::from_low_u64_be(7))),
+			KeyShareVersion::new_at(key_share.versions[0].id_numbers.clone(), Secret::from(H256::from_low_u64_be(8)), &clock),
+		];
+		let timestamps = vec![(key_share.versions[1].hash, 1_000)].into_iter().collect();
+
+		assert!(key_share.versions[1].verify_hash(1_000));
+		assert!(!key_share.versions[1].verify_hash(1_001));
+		assert_eq!(key_share.verify_all_version_hashes(&timestamps), Ok(()));
+
+		key_share.versions[0].hash = H256::from_low_u64_be(1);
+		assert!(matches!(key_share.verify_all_version_hashes(&timestamps), Err(Error::InvalidKeyShare(_))));
+	}
+
+	#[test]
+	fn key_share_is_updated_only_if_expected_value_matches() {
+		let storage = InMemoryKeyStorage::default();
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		let original = key_share();
+		storage.insert(key_id, original.clone()).unwrap();
+
+		let stale = KeyShare { threshold: 0, ..original.clone() };
+		let updated = KeyShare { threshold: 2, ..original.clone() };
+		assert_eq!(storage.update_if(key_id, &stale, updated.clone()), Ok(false));
+		assert_eq!(storage.get(&key_id), Ok(Some(original.clone())));
+
+		assert_eq!(storage.update_if(key_id, &original, updated.clone()), Ok(true));
+		assert_eq!(storage.get(&key_id), Ok(Some(updated)));
+		assert_eq!(storage.update_if(ServerKeyId::from_low_u64_be(2), &original, original.clone()), Ok(false));
+	}
+
+	#[test]
+	fn missing_and_extra_keys_are_found() {
+		let storage = InMemoryKeyStorage::default();
+		let key_id = |i| ServerKeyId::from_low_u64_be(i);
+		storage.insert(key_id(1), key_share()).unwrap();
+		storage.insert(key_id(3), key_share()).unwrap();
+
+		let expected = vec![key_id(1), key_id(2)].into_iter().collect();
+		assert_eq!(missing_keys(&storage, &expected), Ok(vec![key_id(2)].into_iter().collect()));
+		assert_eq!(extra_keys(&storage, &expected), Ok(vec![key_id(3)].into_iter().collect()));
+	}
+
+	#[test]
+	fn key_share_is_exported_encrypted_to_recovery_key() {
+		use parity_crypto::publickey::{Generator, Random};
+
+		let storage = InMemoryKeyStorage::default();
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		storage.insert(key_id, key_share()).unwrap();
+
+		let recovery = Random.generate().unwrap();
+		let exported = storage.iter_encrypted(*recovery.public()).collect::<Vec<_>>();
+		assert_eq!(exported.len(), 1);
+		assert_eq!(exported[0].0, key_id);
+		let encrypted = exported[0].1.as_ref().unwrap();
+		assert_eq!(decrypt_exported_share(recovery.secret(), encrypted), Ok(key_share()));
+		assert!(decrypt_exported_share(Random.generate().unwrap().secret(), encrypted).is_err());
+	}
+
+	#[test]
+	fn removed_key_is_not_resurrected() {
+		let storage = InMemoryKeyStorage::with_clock(Arc::new(MockClock::new(100)));
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		storage.insert(key_id, key_share()).unwrap();
+		assert_eq!(storage.tombstoned_at(&key_id), Ok(None));
+
+		storage.remove(&key_id).unwrap();
+		assert_eq!(storage.tombstoned_at(&key_id), Ok(Some(100)));
+		assert_eq!(storage.insert(key_id, key_share()), Err(Error::Storage {
+			op: "insert".into(),
+			key_id,
+			error: Box::new(Error::KeyTombstoned),
+		}));
+		assert_eq!(storage.update(key_id, key_share()), Err(Error::KeyTombstoned.with_key_context("update", &key_id)));
+		assert_eq!(storage.get(&key_id), Ok(None));
+
+		storage.clear_tombstone(&key_id).unwrap();
+		assert_eq!(storage.insert(key_id, key_share()), Ok(()));
+	}
+
+	#[test]
+	fn removed_key_is_force_inserted() {
+		let storage = caching_storage(2);
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		storage.insert(key_id, key_share()).unwrap();
+		storage.remove(&key_id).unwrap();
+		assert_eq!(storage.insert(key_id, key_share()), Err(Error::KeyTombstoned.with_key_context("insert", &key_id)));
+
+		storage.force_insert(key_id, key_share()).unwrap();
+		assert_eq!(storage.tombstoned_at(&key_id), Ok(None));
+		assert_eq!(storage.get(&key_id), Ok(Some(key_share())));
+	}
+
+	#[test]
+	fn read_only_storage_provides_read_access() {
+		let storage = InMemoryKeyStorage::default();
+		storage.insert(ServerKeyId::from_low_u64_be(1), key_share()).unwrap();
+		storage.insert(ServerKeyId::from_low_u64_be(2), key_share()).unwrap();
+
+		let reader: &dyn KeyStorageReader = &ReadOnlyKeyStorage::new(storage);
+		assert_eq!(reader.count(), 2);
+		assert!(reader.contains(&ServerKeyId::from_low_u64_be(1)));
+		assert_eq!(reader.get(&ServerKeyId::from_low_u64_be(2)), Ok(Some(key_share())));
+		assert_eq!(reader.get(&ServerKeyId::from_low_u64_be(3)), Ok(None));
+		assert_eq!(
+			reader.iter().map(|(key_id, _)| key_id).collect::<Vec<_>>(),
+			vec![ServerKeyId::from_low_u64_be(1), ServerKeyId::from_low_u64_be(2)],
+		);
+	}
+
+	#[test]
+	fn keys_are_iterated_in_ascending_order() {
+		let storage = caching_storage(2);
+		storage.insert(ServerKeyId::from_low_u64_be(0), key_share()).unwrap();
+		storage.insert(ServerKeyId::from_low_u64_be(10), key_share()).unwrap();
+		storage.insert(ServerKeyId::from_low_u64_be(5), key_share()).unwrap();
+
+		let expected_ids = vec![0, 1, 2, 3, 5, 10].into_iter().map(ServerKeyId::from_low_u64_be).collect::<Vec<_>>();
+		assert_eq!(storage.iter().map(|(key_id, _)| key_id).collect::<Vec<_>>(), expected_ids);
+		assert_eq!(storage.iter_ids().collect::<Vec<_>>(), expected_ids);
+	}
+
+	#[test]
+	fn keys_hosted_by_node_are_found() {
+		let node = KeyServerId::from_low_u64_be(5);
+		let other_node = KeyServerId::from_low_u64_be(8);
+		let mut moved_key_share = multi_version_key_share(2);
+		moved_key_share.versions[1].id_numbers = vec![
+			(other_node, Secret::from(H256::from_low_u64_be(6))),
+		].into_iter().collect();
+
+		let storage = InMemoryKeyStorage::default();
+		storage.insert(ServerKeyId::from_low_u64_be(1), key_share()).unwrap();
+		storage.insert(ServerKeyId::from_low_u64_be(2), moved_key_share).unwrap();
+		storage.insert(ServerKeyId::from_low_u64_be(3), multi_version_key_share(2)).unwrap();
+
+		assert_eq!(
+			keys_hosted_by(&storage, &node).unwrap(),
+			vec![ServerKeyId::from_low_u64_be(1), ServerKeyId::from_low_u64_be(3)],
+		);
+		assert_eq!(keys_hosted_by(&storage, &other_node).unwrap(), vec![ServerKeyId::from_low_u64_be(2)]);
+	}
+
+	#[test]
+	fn migration_volume_is_estimated() {
+		let node = |i| KeyServerId::from_low_u64_be(i);
+		let key_share = |nodes: &[u64]| KeyShare {
+			versions: vec![KeyShareVersion::new(
+				nodes.iter().map(|i| (node(*i), Secret::from(H256::from_low_u64_be(*i)))).collect(),
+				Secret::from(H256::from_low_u64_be(100)),
+			)],
+			..Default::default()
+		};
+		let old_set = vec![node(1), node(2), node(3)].into_iter().collect();
+		let new_set = vec![node(2), node(3), node(4), node(5)].into_iter().collect();
+
+		let storage = InMemoryKeyStorage::default();
+		for i in 0..3 {
+			storage.insert(ServerKeyId::from_low_u64_be(i), key_share(&[1, 2, 3])).unwrap();
+		}
+		storage.insert(ServerKeyId::from_low_u64_be(3), key_share(&[2, 3, 4, 5])).unwrap();
+
+		assert_eq!(estimate_migration_volume(&storage, &old_set, &new_set), Ok(MigrationEstimate {
+			keys_affected: 3,
+			approx_messages: 3 * 3 * 4,
+			approx_bytes: 3 * 3 * 4 * APPROX_MIGRATION_MESSAGE_SIZE,
+		}));
+	}
+
+	#[test]
+	fn key_share_versions_are_pruned_down_to_one() {
+		let mut key_share = multi_version_key_share(3);
+		key_share.prune_versions(0);
+		assert_eq!(key_share.versions.len(), 1);
+		assert_eq!(key_share.last_version().unwrap().hash, H256::from_low_u64_be(102));
+
+		let storage = InMemoryKeyStorage::default();
+		storage.insert(Default::default(), multi_version_key_share(3)).unwrap();
+		storage.prune_all_versions(1).unwrap();
+		assert_eq!(storage.get(&Default::default()).unwrap().unwrap(), key_share);
+	}
+
+	#[test]
+	fn key_share_versions_are_not_pruned_when_under_limit() {
+		let mut key_share = multi_version_key_share(3);
+		key_share.prune_versions(3);
+		assert_eq!(key_share, multi_version_key_share(3));
+		key_share.prune_versions(2);
+		assert_eq!(
+			key_share.versions.iter().map(|version| version.hash).collect::<Vec<_>>(),
+			vec![H256::from_low_u64_be(101), H256::from_low_u64_be(102)],
+		);
+	}
+
+	#[test]
+	fn key_share_metadata_is_merged() {
+		let mut key_share1 = key_share();
+		let mut key_share2 = key_share();
+		key_share2.versions[0].id_numbers = vec![
+			(KeyServerId::from_low_u64_be(8), Secret::from(H256::from_low_u64_be(9))),
+		].into_iter().collect();
+		key_share2.versions[0].secret_share = Secret::from(H256::from_low_u64_be(10));
+
+		key_share1.merge_metadata(&key_share2).unwrap();
+		assert_eq!(key_share1.versions.len(), 1);
+		assert_eq!(key_share1.versions[0].secret_share, key_share().versions[0].secret_share);
+		assert_eq!(
+			key_share1.holding_nodes(),
+			vec![5, 8].into_iter().map(KeyServerId::from_low_u64_be).collect(),
+		);
+	}
+
+	#[test]
+	fn key_share_metadata_with_conflicting_threshold_is_not_merged() {
+		let mut key_share1 = key_share();
+		let mut key_share2 = key_share();
+		key_share2.threshold = 2;
+
+		assert_eq!(
+			key_share1.merge_metadata(&key_share2),
+			Err(Error::ConflictingKeyShares("threshold mismatch".into())),
+		);
+		assert_eq!(key_share1, key_share());
+	}
+
+	#[test]
+	fn holding_nodes_of_single_version_key_share() {
+		let key_share = key_share();
+		let nodes: BTreeSet<_> = vec![KeyServerId::from_low_u64_be(5)].into_iter().collect();
+		assert_eq!(key_share.holding_nodes(), nodes);
+		assert_eq!(key_share.holding_nodes_at(&H256::from_low_u64_be(4)), Ok(nodes));
+		assert_eq!(key_share.holding_nodes_at(&H256::from_low_u64_be(5)), Err(Error::UnknownKeyVersion));
+	}
+
+	#[test]
+	fn holding_nodes_of_multi_version_key_share() {
+		let mut key_share = key_share();
+		let new_version = key_share.versions[0].with_new_nodes(
+			&(8..10).map(KeyServerId::from_low_u64_be).collect(),
+			&mut StdRng::seed_from_u64(42),
+		);
+		let new_version_hash = new_version.hash;
+		key_share.versions.push(new_version);
+
+		assert_eq!(key_share.holding_nodes(), vec![5, 8, 9].into_iter().map(KeyServerId::from_low_u64_be).collect());
+		assert_eq!(
+			key_share.holding_nodes_at(&new_version_hash),
+			Ok(vec![8, 9].into_iter().map(KeyServerId::from_low_u64_be).collect()),
+		);
+	}
+
+	#[test]
+	fn key_share_version_is_moved_to_new_nodes() {
+		let version = key_share().versions.remove(0);
+		let new_nodes: BTreeSet<_> = (20..25).map(KeyServerId::from_low_u64_be).collect();
+		let new_version = version.with_new_nodes(&new_nodes, &mut StdRng::seed_from_u64(42));
+
+		assert!(new_version.id_numbers.keys().eq(new_nodes.iter()));
+		let unique_id_numbers: BTreeSet<_> = new_version.id_numbers.values().map(|id_number| **id_number).collect();
+		assert_eq!(unique_id_numbers.len(), new_nodes.len());
+		assert_eq!(new_version.secret_share, version.secret_share);
+		assert_ne!(new_version.hash, version.hash);
+		assert_eq!(new_version, KeyShareVersion::new(new_version.id_numbers.clone(), version.secret_share.clone()));
+	}
+
+	#[test]
+	fn key_share_canonical_encoding_roundtrip() {
+		let mut key_share = key_share();
+		key_share.encrypted_point = Some(Public::from_low_u64_be(8));
+		key_share.versions.push(KeyShareVersion::new(
+			vec![
+				(KeyServerId::from_low_u64_be(10), Secret::from(H256::from_low_u64_be(11))),
+				(KeyServerId::from_low_u64_be(9), Secret::from(H256::from_low_u64_be(12))),
+			].into_iter().collect(),
+			Secret::from(H256::from_low_u64_be(13)),
+		));
+
+		let encoded = key_share.to_canonical_bytes();
+		assert_eq!(KeyShare::from_canonical_bytes(&encoded), Ok(key_share));
+		assert!(KeyShare::from_canonical_bytes(&encoded[..encoded.len() - 1]).is_err());
+	}
+
+	#[test]
+	fn key_share_is_decoded_from_pinned_fixture() {
+		let fixture: Vec<u8> = concat!(
+			"01", // format version
+			"0000000000000000000000000000000000000001", // author
+			"0000000000000001", // threshold
+			"00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000002", // public
+			"0100000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000003", // common point
+			"00", // encrypted point
+			"00000001", // versions count
+			"0000000000000000000000000000000000000000000000000000000000000004", // version hash
+			"00000001", // nodes count
+			"00000000000000000000000000000000000000050000000000000000000000000000000000000000000000000000000000000006", // node id and its id number
+			"0000000000000000000000000000000000000000000000000000000000000007", // secret share
+		).from_hex().unwrap();
+		assert_eq!(key_share().to_canonical_bytes(), fixture);
+		assert_eq!(KeyShare::from_canonical_bytes(&fixture), Ok(key_share()));
+
+		let mut fixture = fixture;
+		fixture[0] = 99;
+		assert_eq!(KeyShare::from_canonical_bytes(&fixture), Err(Error::UnsupportedSerializationVersion(99)));
+	}
 }
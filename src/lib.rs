@@ -19,6 +19,9 @@ pub use ethereum_types::H256;
 pub use parity_bytes::Bytes;
 pub use parity_crypto::publickey::{Address, Public, Signature};
 
+use parity_crypto::publickey::{KeyPair, Secret, ec_math_utils};
+use rand::RngCore;
+
 /// Every key server owns a key. This type is used where we need to encrypt
 /// message to this server key.
 pub type KeyServerPublic = Public;
@@ -30,16 +33,32 @@ pub type KeyServerId = Address;
 /// that should be encrypted by this key.
 pub type ServerKeyId = H256;
 
+/// Check if server key id could be used to identify server key. Zero id is rejected.
+pub fn is_valid_server_key_id(id: &ServerKeyId) -> bool {
+	!id.is_zero()
+}
+
 pub mod acl_storage;
+#[cfg(feature = "key-share-serde")]
+pub mod backup;
+pub mod cancellation;
+pub mod clock;
+pub mod concurrency_limited_key_server;
+pub mod consensus;
+pub mod deduplicating_key_server;
 pub mod error;
 pub mod executor;
 pub mod key_server;
 pub mod key_server_key_pair;
 pub mod key_server_set;
 pub mod key_storage;
+pub mod message;
 pub mod requester;
+pub mod retrying_key_server;
 pub mod serialization;
 pub mod service;
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod testing;
 
 /// Encrypt given data using Elliptic Curve Integrated Encryption Scheme.
 pub fn ecies_encrypt(
@@ -51,3 +70,121 @@ pub fn ecies_encrypt(
 			format!("Error encrypting data (ECIES): {}", error),
 		))
 }
+
+/// Encrypt data, returned to the requester (like document key), with requester public key.
+/// The result could be decrypted by the requester using `decrypt_with_secret`.
+pub fn encrypt_for_requester(
+	requester_public: &Public,
+	plaintext: &[u8],
+) -> Result<Bytes, crate::error::Error> {
+	ecies_encrypt(requester_public, plaintext)
+}
+
+/// Decrypt data, encrypted with `encrypt_for_requester`, using requester secret key.
+pub fn decrypt_with_secret(
+	requester_secret: &Secret,
+	ciphertext: &[u8],
+) -> Result<Bytes, crate::error::Error> {
+	parity_crypto::publickey::ecies::decrypt(requester_secret, &parity_crypto::DEFAULT_MAC, ciphertext)
+		.map_err(|error| crate::error::Error::Internal(
+			format!("Error decrypting data (ECIES): {}", error),
+		))
+}
+
+/// Encrypt document key with server key, using OS random number generator.
+/// Returns common point (`k * G`) and encrypted point (`M + k * Y`), where `M` is
+/// the document key, `Y` is the server key and `k` is random scalar.
+pub fn encrypt_document_key(
+	server_key: &Public,
+	document_key: &Public,
+) -> Result<(Public, Public), crate::error::Error> {
+	encrypt_document_key_with_rng(server_key, document_key, &mut rand::rngs::OsRng)
+}
+
+/// Encrypt document key with server key, using given random number generator.
+/// See `encrypt_document_key` for details.
+pub fn encrypt_document_key_with_rng(
+	server_key: &Public,
+	document_key: &Public,
+	rng: &mut impl RngCore,
+) -> Result<(Public, Public), crate::error::Error> {
+	let key_pair = KeyPair::from_secret(random_secret(rng))?;
+
+	let mut encrypted_point = *server_key;
+	ec_math_utils::public_mul_secret(&mut encrypted_point, key_pair.secret())?;
+	ec_math_utils::public_add(&mut encrypted_point, document_key)?;
+
+	Ok((*key_pair.public(), encrypted_point))
+}
+
+/// Generate random valid EC scalar.
+pub(crate) fn random_secret(rng: &mut impl RngCore) -> Secret {
+	loop {
+		let mut data = [0u8; 32];
+		rng.fill_bytes(&mut data);
+		if let Ok(secret) = Secret::import_key(&data) {
+			return secret;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+	use rand::{SeedableRng, rngs::StdRng};
+	use parity_crypto::publickey::{Generator, Random};
+	use super::*;
+
+	#[test]
+	fn document_key_encryption_is_reproducible_with_seeded_rng() {
+		let server_key = *Random.generate().unwrap().public();
+		let document_key = *Random.generate().unwrap().public();
+
+		let encrypt = |seed| encrypt_document_key_with_rng(&server_key, &document_key, &mut StdRng::seed_from_u64(seed))
+			.unwrap();
+		let (common_point1, encrypted_point1) = encrypt(42);
+		let (common_point2, encrypted_point2) = encrypt(42);
+		let (common_point3, _) = encrypt(43);
+		assert_eq!(common_point1, common_point2);
+		assert_eq!(encrypted_point1, encrypted_point2);
+		assert_ne!(common_point1, common_point3);
+	}
+
+	#[test]
+	fn document_key_is_encrypted_to_known_points_with_seeded_rng() {
+		// server key is 1 * G, document key is 2 * G
+		let public = |secret| *KeyPair::from_secret(Secret::from(H256::from_low_u64_be(secret))).unwrap().public();
+		let (common_point, encrypted_point) = encrypt_document_key_with_rng(
+			&public(1),
+			&public(2),
+			&mut StdRng::seed_from_u64(42),
+		).unwrap();
+		assert_eq!(common_point, Public::from_str(
+			"1e1166e890b05f5f20742d9432e7c210665328ba36c2e1c69bfe6c3ddbd54421\
+			39df8d730da3f7ae1e9a83c028089f22bf95690d2f368438f4c53c5c6823ce01",
+		).unwrap());
+		assert_eq!(encrypted_point, Public::from_str(
+			"6060265fae4eb426399b4627d5d7a6ba561b8ac86b740406ff77bc4f6afbb953\
+			02662c79ce65fc626b5048674ec2c324f7fb9828d8309249f60852884a3d00d7",
+		).unwrap());
+	}
+
+	#[test]
+	fn server_key_id_is_validated() {
+		assert!(!is_valid_server_key_id(&ServerKeyId::zero()));
+		assert!(is_valid_server_key_id(&ServerKeyId::from_low_u64_be(42)));
+	}
+
+	#[test]
+	fn data_encrypted_for_requester_is_decrypted_with_secret() {
+		let requester = Random.generate().unwrap();
+		let plaintext = b"document key";
+
+		let ciphertext = encrypt_for_requester(requester.public(), plaintext).unwrap();
+		assert_ne!(&ciphertext[..], &plaintext[..]);
+		assert_eq!(decrypt_with_secret(requester.secret(), &ciphertext).unwrap(), plaintext.to_vec());
+
+		let other = Random.generate().unwrap();
+		assert!(decrypt_with_secret(other.secret(), &ciphertext).is_err());
+	}
+}
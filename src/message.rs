@@ -0,0 +1,223 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use parking_lot::RwLock;
+use crate::{
+	KeyServerId,
+	error::Error,
+	key_server::SessionId,
+};
+
+/// Size of encoded session id.
+const SESSION_ID_SIZE: usize = 32;
+
+/// Tag of the key generation session message.
+const GENERATION_TAG: u8 = 1;
+/// Tag of the signing session message.
+const SIGNING_TAG: u8 = 2;
+/// Tag of the consensus message.
+const CONSENSUS_TAG: u8 = 3;
+/// Tag of the keep alive message.
+const KEEP_ALIVE_TAG: u8 = 4;
+
+/// Session message, wrapped into envelope, so that messages of several concurrent
+/// sessions could be sent over the same connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionEnvelope {
+	/// Id of the session this message belongs to.
+	pub session_id: SessionId,
+	/// Session message itself.
+	pub payload: Vec<u8>,
+}
+
+/// Message of the key servers protocol. Encoded message starts with the tag byte,
+/// which defines the message category.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolMessage {
+	/// Message of the key generation session.
+	Generation(SessionEnvelope),
+	/// Message of the signing session.
+	Signing(SessionEnvelope),
+	/// Message of the consensus establishment.
+	Consensus(SessionEnvelope),
+	/// Keep alive message.
+	KeepAlive,
+}
+
+/// Routes received session messages to the sessions they belong to.
+#[derive(Default)]
+pub struct SessionRouter {
+	sessions: RwLock<HashMap<SessionId, UnboundedSender<(KeyServerId, SessionEnvelope)>>>,
+}
+
+impl SessionEnvelope {
+	/// Encode envelope: session id, followed by the payload.
+	pub fn encode(&self) -> Vec<u8> {
+		let mut encoded = Vec::with_capacity(SESSION_ID_SIZE + self.payload.len());
+		encoded.extend_from_slice(self.session_id.as_bytes());
+		encoded.extend_from_slice(&self.payload);
+		encoded
+	}
+
+	/// Decode envelope.
+	pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+		if encoded.len() < SESSION_ID_SIZE {
+			return Err(Error::InvalidMessage);
+		}
+
+		Ok(SessionEnvelope {
+			session_id: SessionId::from_slice(&encoded[..SESSION_ID_SIZE]),
+			payload: encoded[SESSION_ID_SIZE..].to_vec(),
+		})
+	}
+}
+
+impl ProtocolMessage {
+	/// Encode message: tag, followed by the encoded envelope (if any).
+	pub fn encode(&self) -> Vec<u8> {
+		let (tag, envelope) = match *self {
+			ProtocolMessage::Generation(ref envelope) => (GENERATION_TAG, Some(envelope)),
+			ProtocolMessage::Signing(ref envelope) => (SIGNING_TAG, Some(envelope)),
+			ProtocolMessage::Consensus(ref envelope) => (CONSENSUS_TAG, Some(envelope)),
+			ProtocolMessage::KeepAlive => (KEEP_ALIVE_TAG, None),
+		};
+
+		let mut encoded = vec![tag];
+		if let Some(envelope) = envelope {
+			encoded.extend(envelope.encode());
+		}
+		encoded
+	}
+
+	/// Decode message.
+	pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+		match encoded.split_first() {
+			Some((&GENERATION_TAG, envelope)) => Ok(ProtocolMessage::Generation(SessionEnvelope::decode(envelope)?)),
+			Some((&SIGNING_TAG, envelope)) => Ok(ProtocolMessage::Signing(SessionEnvelope::decode(envelope)?)),
+			Some((&CONSENSUS_TAG, envelope)) => Ok(ProtocolMessage::Consensus(SessionEnvelope::decode(envelope)?)),
+			Some((&KEEP_ALIVE_TAG, [])) => Ok(ProtocolMessage::KeepAlive),
+			_ => Err(Error::InvalidMessage),
+		}
+	}
+}
+
+impl SessionRouter {
+	/// Register session. All messages of this session are sent to the returned receiver.
+	pub fn register(&self, session_id: SessionId) -> Result<UnboundedReceiver<(KeyServerId, SessionEnvelope)>, Error> {
+		let mut sessions = self.sessions.write();
+		if sessions.contains_key(&session_id) {
+			return Err(Error::DuplicateSessionId);
+		}
+
+		let (sender, receiver) = unbounded();
+		sessions.insert(session_id, sender);
+		Ok(receiver)
+	}
+
+	/// Unregister session.
+	pub fn unregister(&self, session_id: &SessionId) {
+		self.sessions.write().remove(session_id);
+	}
+
+	/// Decode message, encoded with `ProtocolMessage::encode` and received from given node,
+	/// and route it to the session it belongs to. Keep alive messages are not routed.
+	pub fn route(&self, sender: KeyServerId, message: &[u8]) -> Result<(), Error> {
+		let envelope = match ProtocolMessage::decode(message)? {
+			ProtocolMessage::Generation(envelope)
+				| ProtocolMessage::Signing(envelope)
+				| ProtocolMessage::Consensus(envelope) => envelope,
+			ProtocolMessage::KeepAlive => return Ok(()),
+		};
+		let sessions = self.sessions.read();
+		let session = sessions.get(&envelope.session_id).ok_or(Error::NoActiveSessionWithId)?;
+		session.unbounded_send((sender, envelope)).map_err(|_| Error::NoActiveSessionWithId)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn envelope(session_id: u64, payload: Vec<u8>) -> SessionEnvelope {
+		SessionEnvelope {
+			session_id: SessionId::from_low_u64_be(session_id),
+			payload,
+		}
+	}
+
+	#[test]
+	fn session_envelope_encode_decode_roundtrip() {
+		let envelope = envelope(1, vec![1, 2, 3]);
+		assert_eq!(SessionEnvelope::decode(&envelope.encode()), Ok(envelope));
+
+		let empty_envelope = self::envelope(2, Vec::new());
+		assert_eq!(SessionEnvelope::decode(&empty_envelope.encode()), Ok(empty_envelope));
+	}
+
+	#[test]
+	fn session_envelope_decode_rejects_invalid_message() {
+		let encoded = envelope(1, vec![1, 2, 3]).encode();
+		assert_eq!(SessionEnvelope::decode(&encoded[..SESSION_ID_SIZE - 1]), Err(Error::InvalidMessage));
+	}
+
+	#[test]
+	fn protocol_message_encode_decode_roundtrip() {
+		let messages = vec![
+			ProtocolMessage::Generation(envelope(1, vec![1])),
+			ProtocolMessage::Signing(envelope(2, vec![2, 3])),
+			ProtocolMessage::Consensus(envelope(3, Vec::new())),
+			ProtocolMessage::KeepAlive,
+		];
+		for message in messages {
+			assert_eq!(ProtocolMessage::decode(&message.encode()), Ok(message));
+		}
+	}
+
+	#[test]
+	fn protocol_message_decode_rejects_unknown_tag() {
+		let mut encoded = ProtocolMessage::Signing(envelope(1, vec![1])).encode();
+		encoded[0] = 0xFF;
+		assert_eq!(ProtocolMessage::decode(&encoded), Err(Error::InvalidMessage));
+		assert_eq!(ProtocolMessage::decode(&[]), Err(Error::InvalidMessage));
+		assert_eq!(ProtocolMessage::decode(&[KEEP_ALIVE_TAG, 0]), Err(Error::InvalidMessage));
+	}
+
+	#[test]
+	fn session_router_does_not_mix_sessions_messages() {
+		let router = SessionRouter::default();
+		let mut receiver1 = router.register(SessionId::from_low_u64_be(1)).unwrap();
+		let mut receiver2 = router.register(SessionId::from_low_u64_be(2)).unwrap();
+		let node = KeyServerId::from_low_u64_be(100);
+
+		router.route(node, &ProtocolMessage::Generation(envelope(1, vec![1])).encode()).unwrap();
+		router.route(node, &ProtocolMessage::Signing(envelope(2, vec![2])).encode()).unwrap();
+		router.route(node, &ProtocolMessage::Consensus(envelope(1, vec![3])).encode()).unwrap();
+		router.route(node, &ProtocolMessage::KeepAlive.encode()).unwrap();
+		assert_eq!(
+			router.route(node, &ProtocolMessage::Signing(envelope(3, vec![4])).encode()),
+			Err(Error::NoActiveSessionWithId),
+		);
+		assert_eq!(router.route(node, &envelope(1, vec![5]).encode()), Err(Error::InvalidMessage));
+
+		assert_eq!(receiver1.try_recv().unwrap(), (node, envelope(1, vec![1])));
+		assert_eq!(receiver1.try_recv().unwrap(), (node, envelope(1, vec![3])));
+		assert!(receiver1.try_recv().is_err());
+		assert_eq!(receiver2.try_recv().unwrap(), (node, envelope(2, vec![2])));
+		assert!(receiver2.try_recv().is_err());
+	}
+}
@@ -14,11 +14,18 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
-use parity_crypto::publickey::{Address, Public, Signature, public_to_address, recover};
+use rustc_hex::ToHex;
+use ethereum_types::{H256, U256};
+use parity_crypto::publickey::{
+	Address, Public, Secret, Signature, ec_math_utils::CURVE_ORDER, public_to_address, recover, sign, verify_public,
+};
 use crate::{error::Error, ServerKeyId};
 
 /// Requester identification data.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+///
+/// Both `Display` and `Debug` are printing redacted requester data, so it is
+/// safe to write requester to logs.
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Requester {
 	/// Requested with server key id signature.
 	Signature(Signature),
@@ -26,16 +33,32 @@ pub enum Requester {
 	Public(Public),
 	/// Requested with verified address.
 	Address(Address),
+	/// Requested with server key id signature and public key that the signature is
+	/// expected to be recovered to.
+	SignatureWithPublic(Signature, Public),
 }
 
 impl Requester {
+	/// Create requester by signing server key id with requester secret.
+	pub fn sign(secret: &Secret, server_key_id: &ServerKeyId) -> Result<Requester, Error> {
+		sign(secret, server_key_id)
+			.map(Requester::Signature)
+			.map_err(Into::into)
+	}
+
 	/// Return requester public key.
 	pub fn public(&self, server_key_id: &ServerKeyId) -> Result<Public, Error> {
 		match *self {
 			Requester::Signature(ref signature) => recover(signature, server_key_id)
 				.map_err(|e| Error::Internal(format!("bad signature: {}", e))),
-			Requester::Public(ref public) => Ok(public.clone()),
+			Requester::Public(ref public) => Ok(*public),
 			Requester::Address(_) => Err(Error::InsufficientRequesterData("cannot recover public from address".into())),
+			// verification is cheaper than recovery, so try it first
+			Requester::SignatureWithPublic(ref signature, ref public) => match verify_public(public, signature, server_key_id) {
+				Ok(true) => Ok(*public),
+				_ => recover(signature, server_key_id)
+					.map_err(|e| Error::Internal(format!("bad signature: {}", e))),
+			},
 		}
 	}
 
@@ -44,6 +67,58 @@ impl Requester {
 		self.public(server_key_id)
 			.map(|p| public_to_address(&p))
 	}
+
+	/// Check if requester signature (if any) is canonical, i.e. its `s` is in the lower
+	/// half of the curve order. High-s signatures are malleable: the same signer could
+	/// produce two different valid signatures of the same server key id.
+	pub fn is_canonical(&self) -> bool {
+		match *self {
+			Requester::Signature(ref signature) | Requester::SignatureWithPublic(ref signature, _) => signature.is_low_s(),
+			Requester::Public(_) | Requester::Address(_) => true,
+		}
+	}
+
+	/// Replace high-s requester signature (if any) with the equivalent low-s signature.
+	pub fn normalize(&mut self) {
+		match *self {
+			Requester::Signature(ref mut signature) | Requester::SignatureWithPublic(ref mut signature, _) =>
+				if !signature.is_low_s() {
+					*signature = flip_s(signature);
+				},
+			Requester::Public(_) | Requester::Address(_) => (),
+		}
+	}
+
+	/// Return redacted requester representation that is safe to be written to logs.
+	pub fn redacted(&self) -> String {
+		match *self {
+			Requester::Signature(ref signature) => format!("Signature({})", redact(&signature[..], true)),
+			Requester::Public(ref public) => format!("Public({})", redact(public.as_bytes(), true)),
+			Requester::Address(ref address) => format!("Address({})", redact(address.as_bytes(), false)),
+			Requester::SignatureWithPublic(ref signature, ref public) => format!(
+				"SignatureWithPublic({}, {})",
+				redact(&signature[..], true),
+				redact(public.as_bytes(), true),
+			),
+		}
+	}
+}
+
+/// Get the other valid signature of the same message: `s' = n - s` and recovery id is flipped.
+fn flip_s(signature: &Signature) -> Signature {
+	let s = *CURVE_ORDER - U256::from_big_endian(signature.s());
+	let mut flipped_s = [0u8; 32];
+	s.to_big_endian(&mut flipped_s);
+	Signature::from_rsv(&H256::from_slice(signature.r()), &H256::from(flipped_s), signature.v() ^ 1)
+}
+
+/// Truncate hex representation of given data.
+fn redact(data: &[u8], with_suffix: bool) -> String {
+	let prefix = data[..2].to_hex();
+	match with_suffix {
+		true => format!("0x{}\u{2026}{}", prefix, data[data.len() - 1..].to_hex()),
+		false => format!("0x{}\u{2026}", prefix),
+	}
 }
 
 impl From<Signature> for Requester {
@@ -64,8 +139,107 @@ impl From<Address> for Requester {
 	}
 }
 
+impl std::fmt::Debug for Requester {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		write!(f, "{}", self.redacted())
+	}
+}
+
 impl std::fmt::Display for Requester {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-		write!(f, "{:?}", self)
+		write!(f, "{}", self.redacted())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+	use parity_crypto::publickey::{Generator, Random};
+	use super::*;
+
+	#[test]
+	fn signed_requester_recovers_to_signer() {
+		let key_pair = Random.generate().unwrap();
+		let server_key_id = ServerKeyId::from_low_u64_be(42);
+		let requester = Requester::sign(key_pair.secret(), &server_key_id).unwrap();
+		assert_eq!(requester.public(&server_key_id), Ok(*key_pair.public()));
+		assert_eq!(requester.address(&server_key_id), Ok(key_pair.address()));
+	}
+
+	#[test]
+	fn signed_requester_with_matching_public_is_verified() {
+		let key_pair = Random.generate().unwrap();
+		let server_key_id = ServerKeyId::from_low_u64_be(42);
+		let signature = sign(key_pair.secret(), &server_key_id).unwrap();
+		let requester = Requester::SignatureWithPublic(signature, *key_pair.public());
+		assert_eq!(requester.public(&server_key_id), Ok(*key_pair.public()));
+	}
+
+	#[test]
+	fn signed_requester_with_wrong_public_is_recovered() {
+		let key_pair = Random.generate().unwrap();
+		let other_key_pair = Random.generate().unwrap();
+		let server_key_id = ServerKeyId::from_low_u64_be(42);
+		let signature = sign(key_pair.secret(), &server_key_id).unwrap();
+		let requester = Requester::SignatureWithPublic(signature, *other_key_pair.public());
+		assert_eq!(requester.public(&server_key_id), Ok(*key_pair.public()));
+	}
+
+	#[test]
+	fn high_s_signature_is_normalized() {
+		let server_key_id = ServerKeyId::from_low_u64_be(42);
+		let high_s = Signature::from_str(
+			"0e7cd94c6a59eefb57d49a728288925002ca721310ddf1e3b3f8b46fa9b75ae2\
+			bbe249b89e32e37801ee87ff012994bf0ccfbc72d33adde94b60ddaa42eb73ec01",
+		).unwrap();
+		let low_s = Signature::from_str(
+			"0e7cd94c6a59eefb57d49a728288925002ca721310ddf1e3b3f8b46fa9b75ae2\
+			441db64761cd1c87fe117800fed66b3faddf2073dc0dc252747180e28d4acd5500",
+		).unwrap();
+		// low-s signature is what the signer produces
+		assert_eq!(sign(&Secret::from(H256::from_low_u64_be(1)), &server_key_id).unwrap(), low_s);
+
+		let mut requester = Requester::Signature(high_s);
+		assert!(!requester.is_canonical());
+		let public = requester.public(&server_key_id).unwrap();
+		requester.normalize();
+		assert!(requester.is_canonical());
+		assert_eq!(requester, Requester::Signature(low_s.clone()));
+		assert_eq!(requester.public(&server_key_id), Ok(public));
+
+		requester.normalize();
+		assert_eq!(requester, Requester::Signature(low_s));
+		assert!(Requester::Public(public).is_canonical());
+	}
+
+	#[test]
+	fn requester_signature_is_redacted() {
+		let signature = Signature::from_str(&format!("1234{}ab", "00".repeat(62))).unwrap();
+		assert_eq!(Requester::Signature(signature).redacted(), "Signature(0x1234\u{2026}ab)");
+	}
+
+	#[test]
+	fn requester_public_is_redacted() {
+		let public = Public::from_str(&format!("1234{}ab", "00".repeat(61))).unwrap();
+		assert_eq!(Requester::Public(public).redacted(), "Public(0x1234\u{2026}ab)");
+	}
+
+	#[test]
+	fn requester_address_is_redacted() {
+		let address = Address::from_str(&format!("abcd{}ef", "00".repeat(17))).unwrap();
+		assert_eq!(Requester::Address(address).redacted(), "Address(0xabcd\u{2026})");
+		assert_eq!(format!("{}", Requester::Address(address)), "Address(0xabcd\u{2026})");
+		assert_eq!(format!("{:?}", Requester::Address(address)), "Address(0xabcd\u{2026})");
+	}
+
+	#[test]
+	fn requester_signature_with_public_is_redacted() {
+		let signature = Signature::from_str(&format!("1234{}ab", "00".repeat(62))).unwrap();
+		let public = Public::from_str(&format!("5678{}cd", "00".repeat(61))).unwrap();
+		let requester = Requester::SignatureWithPublic(signature, public);
+		let redacted = "SignatureWithPublic(0x1234\u{2026}ab, 0x5678\u{2026}cd)";
+		assert_eq!(requester.redacted(), redacted);
+		assert_eq!(format!("{}", requester), redacted);
+		assert_eq!(format!("{:?}", requester), redacted);
 	}
 }
@@ -0,0 +1,565 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::{
+	collections::BTreeSet,
+	future::Future,
+	sync::Arc,
+	time::Duration,
+};
+use futures::future::{BoxFuture, FutureExt, ready};
+use ethereum_types::{Address, H256};
+use parity_crypto::publickey::{Public, Signature};
+use crate::{
+	KeyServerPublic, ServerKeyId,
+	error::Error,
+	key_server::{
+		AdminSessionsServer, DocumentKeyServer, KeyServer, KeyServerCapabilities, MessageSigner, Origin,
+		ServerKeyGenerator, SessionResult, SignatureScheme, ServerKeyGenerationArtifacts, ServerKeyGenerationResult, ServerKeyRetrievalResult,
+		DocumentKeyStoreResult, DocumentKeyGenerationResult, EncryptedDocumentKeyGenerationResult,
+		DocumentKeyRetrievalResult, DocumentKeyCommonRetrievalResult, DocumentKeyCommonPartialRetrievalResult,
+		DocumentKeyShadowRetrievalResult, KeyOwnershipTransferResult, SchnorrSigningResult, EcdsaSigningResult, SigningResult,
+		ServersSetChangeResult,
+	},
+	requester::Requester,
+};
+
+/// Default max number of attempts of every operation.
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+/// Default delay between attempts.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Operations retry policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+	/// Max number of attempts (including the first one) of every operation.
+	pub max_attempts: usize,
+	/// Delay between attempts.
+	pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		RetryPolicy {
+			max_attempts: DEFAULT_MAX_ATTEMPTS,
+			backoff: DEFAULT_BACKOFF,
+		}
+	}
+}
+
+/// Key server wrapper that retries operations that have failed with non-fatal errors
+/// (see `Error::is_non_fatal`). Operations that have failed with fatal errors aren't
+/// retried.
+///
+/// Every attempt is made with the same arguments, so retrying key generation uses
+/// the same key id. Failed attempt of the mutating operation might have taken effect,
+/// so the next attempt could fail because the key is already generated (or stored, or
+/// transferred). Key generation is then resolved by retrieving the key: if it has the
+/// same author and threshold, generation succeeds (participants and version of the
+/// generated key are unknown, so they're left empty). Other mutations are failing with
+/// the error of the previous attempt. Batch operations (`restore_document_key_common_many`) and read-only
+/// operations that aren't touching other key servers (`plan_servers_set_change`) aren't
+/// retried.
+///
+/// When backoff is non-zero, returned futures must be polled within tokio runtime.
+pub struct RetryingKeyServer<K> {
+	key_server: Arc<K>,
+	policy: RetryPolicy,
+}
+
+impl<K> RetryingKeyServer<K> {
+	/// Create new key server that retries operations according to given policy.
+	pub fn new(key_server: K, policy: RetryPolicy) -> Self {
+		RetryingKeyServer {
+			key_server: Arc::new(key_server),
+			policy,
+		}
+	}
+
+	/// Get reference to the inner key server.
+	pub fn inner(&self) -> &K {
+		&self.key_server
+	}
+}
+
+impl<K: Send + Sync + 'static> RetryingKeyServer<K> {
+	/// Start operation and restart it while it fails with non-fatal error.
+	fn retrying<P, R, F>(
+		&self,
+		start: impl Fn(&K) -> F + Send + 'static,
+	) -> BoxFuture<'static, SessionResult<P, R>> where
+		P: Send + 'static,
+		R: Send + 'static,
+		F: Future<Output = SessionResult<P, R>> + Send + 'static,
+	{
+		self.retrying_mutation(start, |_| false, previous_error)
+	}
+
+	/// Start mutating operation and restart it while it fails with non-fatal error.
+	/// If the restarted operation fails with `is_done` error, the previous attempt might
+	/// have taken effect before failing, so the result is computed by `resolve`, which
+	/// is called with the error of the previous attempt.
+	fn retrying_mutation<P, R, F>(
+		&self,
+		start: impl Fn(&K) -> F + Send + 'static,
+		is_done: fn(&Error) -> bool,
+		resolve: impl Fn(&K, Error) -> BoxFuture<'static, Result<R, Error>> + Send + 'static,
+	) -> BoxFuture<'static, SessionResult<P, R>> where
+		P: Send + 'static,
+		R: Send + 'static,
+		F: Future<Output = SessionResult<P, R>> + Send + 'static,
+	{
+		let key_server = self.key_server.clone();
+		let policy = self.policy;
+		let mut operation = start(&key_server);
+		async move {
+			let mut attempt = 1;
+			let mut last_error = None;
+			loop {
+				let mut result = operation.await;
+				match result.result {
+					Err(ref error) if attempt > 1 && is_done(error) => {
+						let last_error = last_error.take().expect("attempt is only restarted after failure; qed");
+						result.result = resolve(&key_server, last_error).await;
+						return result;
+					},
+					Err(ref error) if error.is_non_fatal() && attempt < policy.max_attempts => {
+						if policy.backoff != Duration::from_secs(0) {
+							tokio::time::delay_for(policy.backoff).await;
+						}
+
+						attempt += 1;
+						last_error = Some(error.clone());
+						operation = start(&key_server);
+					},
+					_ => return result,
+				}
+			}
+		}.boxed()
+	}
+}
+
+/// Resolve result of the mutation that might have taken effect to the error of the
+/// previous attempt, because the caller can't be sure that the mutation has failed.
+fn previous_error<K, R>(_key_server: &K, error: Error) -> BoxFuture<'static, Result<R, Error>> where
+	R: Send + 'static,
+{
+	ready(Err(error)).boxed()
+}
+
+impl<K> ServerKeyGenerator for RetryingKeyServer<K> where
+	K: ServerKeyGenerator + Send + Sync + 'static,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+{
+	type GenerateKeyFuture = BoxFuture<'static, ServerKeyGenerationResult>;
+	type RestoreKeyFuture = BoxFuture<'static, ServerKeyRetrievalResult>;
+	type SupportsEcdsaFuture = K::SupportsEcdsaFuture;
+	type KeyAuthorFuture = K::KeyAuthorFuture;
+
+	fn generate_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateKeyFuture {
+		let start_author = author.clone();
+		self.retrying_mutation(
+			move |key_server| key_server.generate_key(origin, key_id, start_author.clone(), threshold),
+			|error| *error == Error::ServerKeyAlreadyGenerated,
+			move |key_server, error| {
+				let author_address = author.address(&key_id);
+				key_server.restore_key_public(origin, key_id, Some(author.clone()))
+					.map(move |retrieved| match (retrieved.result, author_address) {
+						(Ok(artifacts), Ok(author_address))
+							if artifacts.author == author_address && artifacts.threshold == threshold =>
+							Ok(ServerKeyGenerationArtifacts {
+								key: artifacts.key,
+								participants: Default::default(),
+								version: Default::default(),
+							}),
+						_ => Err(error),
+					})
+					.boxed()
+			},
+		)
+	}
+
+	fn restore_key_public(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Option<Requester>,
+	) -> Self::RestoreKeyFuture {
+		self.retrying(move |key_server| key_server.restore_key_public(origin, key_id, author.clone()))
+	}
+
+	fn supports_ecdsa(&self, key_id: ServerKeyId, requester: Requester) -> Self::SupportsEcdsaFuture {
+		self.key_server.supports_ecdsa(key_id, requester)
+	}
+
+	fn key_author(&self, key_id: ServerKeyId, requester: Requester) -> Self::KeyAuthorFuture {
+		self.key_server.key_author(key_id, requester)
+	}
+}
+
+impl<K> DocumentKeyServer for RetryingKeyServer<K> where
+	K: DocumentKeyServer + Send + Sync + 'static,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::StoreDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyEncryptedFuture: 'static,
+	K::RestoreDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyCommonFuture: 'static,
+	K::RestoreDocumentKeyShadowFuture: 'static,
+	K::RestoreDocumentKeyAtVersionFuture: 'static,
+	K::RestoreDocumentKeyCommonPartialFuture: 'static,
+	K::TransferKeyOwnershipFuture: 'static,
+{
+	type StoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyStoreResult>;
+	type GenerateDocumentKeyFuture = BoxFuture<'static, DocumentKeyGenerationResult>;
+	type GenerateDocumentKeyEncryptedFuture = BoxFuture<'static, EncryptedDocumentKeyGenerationResult>;
+	type RestoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonFuture = BoxFuture<'static, DocumentKeyCommonRetrievalResult>;
+	type RestoreDocumentKeyShadowFuture = BoxFuture<'static, DocumentKeyShadowRetrievalResult>;
+	type RestoreDocumentKeyAtVersionFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonManyFuture = K::RestoreDocumentKeyCommonManyFuture;
+	type RestoreDocumentKeyCommonPartialFuture = BoxFuture<'static, DocumentKeyCommonPartialRetrievalResult>;
+	type TransferKeyOwnershipFuture = BoxFuture<'static, KeyOwnershipTransferResult>;
+
+	fn store_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		common_point: Public,
+		encrypted_document_key: Public,
+	) -> Self::StoreDocumentKeyFuture {
+		self.retrying_mutation(
+			move |key_server| key_server.store_document_key(
+				origin,
+				key_id,
+				author.clone(),
+				common_point,
+				encrypted_document_key,
+			),
+			|error| *error == Error::DocumentKeyAlreadyStored,
+			previous_error,
+		)
+	}
+
+	fn generate_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyFuture {
+		self.retrying_mutation(
+			move |key_server| key_server.generate_document_key(origin, key_id, author.clone(), threshold),
+			|error| *error == Error::ServerKeyAlreadyGenerated,
+			previous_error,
+		)
+	}
+
+	fn generate_document_key_encrypted(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyEncryptedFuture {
+		self.retrying_mutation(
+			move |key_server| key_server.generate_document_key_encrypted(
+				origin,
+				key_id,
+				author.clone(),
+				threshold,
+			),
+			|error| *error == Error::ServerKeyAlreadyGenerated,
+			previous_error,
+		)
+	}
+
+	fn restore_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyFuture {
+		self.retrying(move |key_server| key_server.restore_document_key(origin, key_id, requester.clone()))
+	}
+
+	fn restore_document_key_at_version(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		version: H256,
+	) -> Self::RestoreDocumentKeyAtVersionFuture {
+		self.retrying(move |key_server| key_server.restore_document_key_at_version(
+			origin,
+			key_id,
+			requester.clone(),
+			version,
+		))
+	}
+
+	fn restore_document_key_common(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonFuture {
+		self.retrying(move |key_server| key_server.restore_document_key_common(origin, key_id, requester.clone()))
+	}
+
+	fn restore_document_key_common_many(
+		&self,
+		origin: Option<Origin>,
+		key_ids: &[ServerKeyId],
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonManyFuture {
+		self.key_server.restore_document_key_common_many(origin, key_ids, requester)
+	}
+
+	fn restore_document_key_common_partial(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonPartialFuture {
+		self.retrying(move |key_server| key_server.restore_document_key_common_partial(
+			origin,
+			key_id,
+			requester.clone(),
+		))
+	}
+
+	fn restore_document_key_shadow(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyShadowFuture {
+		self.retrying(move |key_server| key_server.restore_document_key_shadow(origin, key_id, requester.clone()))
+	}
+
+	fn transfer_key_ownership(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		current_author: Requester,
+		new_author: Address,
+	) -> Self::TransferKeyOwnershipFuture {
+		// once ownership is transferred, the current author has no access to the key
+		self.retrying_mutation(
+			move |key_server| key_server.transfer_key_ownership(
+				origin,
+				key_id,
+				current_author.clone(),
+				new_author,
+			),
+			|error| *error == Error::AccessDenied,
+			previous_error,
+		)
+	}
+}
+
+impl<K> MessageSigner for RetryingKeyServer<K> where
+	K: MessageSigner + Send + Sync + 'static,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::SignMessageSchnorrFuture: 'static,
+	K::SignMessageEcdsaFuture: 'static,
+	K::SignMessageFuture: 'static,
+{
+	type SignMessageSchnorrFuture = BoxFuture<'static, SchnorrSigningResult>;
+	type SignMessageEcdsaFuture = BoxFuture<'static, EcdsaSigningResult>;
+	type SignMessageFuture = BoxFuture<'static, SigningResult>;
+
+	fn sign_message_schnorr(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageSchnorrFuture {
+		self.retrying(move |key_server| key_server.sign_message_schnorr(origin, key_id, requester.clone(), message))
+	}
+
+	fn sign_message_ecdsa(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageEcdsaFuture {
+		self.retrying(move |key_server| key_server.sign_message_ecdsa(origin, key_id, requester.clone(), message))
+	}
+
+	fn sign_message(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> Self::SignMessageFuture {
+		self.retrying(move |key_server| key_server.sign_message(origin, key_id, requester.clone(), message, scheme))
+	}
+}
+
+impl<K> AdminSessionsServer for RetryingKeyServer<K> where
+	K: AdminSessionsServer + Send + Sync + 'static,
+	K::ChangeServersSetFuture: 'static,
+{
+	type ChangeServersSetFuture = BoxFuture<'static, ServersSetChangeResult>;
+	type PlanServersSetChangeFuture = K::PlanServersSetChangeFuture;
+
+	fn change_servers_set(
+		&self,
+		origin: Option<Origin>,
+		old_set_signature: Signature,
+		new_set_signature: Signature,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::ChangeServersSetFuture {
+		self.retrying(move |key_server| key_server.change_servers_set(
+			origin,
+			old_set_signature.clone(),
+			new_set_signature.clone(),
+			new_servers_set.clone(),
+		))
+	}
+
+	fn plan_servers_set_change(
+		&self,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::PlanServersSetChangeFuture {
+		self.key_server.plan_servers_set_change(new_servers_set)
+	}
+}
+
+impl<K> KeyServer for RetryingKeyServer<K> where
+	K: KeyServer,
+	K::GenerateKeyFuture: 'static,
+	K::RestoreKeyFuture: 'static,
+	K::StoreDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyFuture: 'static,
+	K::GenerateDocumentKeyEncryptedFuture: 'static,
+	K::RestoreDocumentKeyFuture: 'static,
+	K::RestoreDocumentKeyCommonFuture: 'static,
+	K::RestoreDocumentKeyShadowFuture: 'static,
+	K::RestoreDocumentKeyAtVersionFuture: 'static,
+	K::RestoreDocumentKeyCommonPartialFuture: 'static,
+	K::TransferKeyOwnershipFuture: 'static,
+	K::SignMessageSchnorrFuture: 'static,
+	K::SignMessageEcdsaFuture: 'static,
+	K::SignMessageFuture: 'static,
+	K::ChangeServersSetFuture: 'static,
+{
+	fn capabilities(&self) -> KeyServerCapabilities {
+		self.key_server.capabilities()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::block_on;
+	use parity_crypto::publickey::{Generator, Random};
+	use crate::{key_storage::{KeyShare, KeyStorage}, testing::MockKeyServer};
+	use super::*;
+
+	fn retrying_key_server() -> RetryingKeyServer<MockKeyServer> {
+		RetryingKeyServer::new(MockKeyServer::new(), RetryPolicy {
+			max_attempts: 3,
+			backoff: Duration::from_secs(0),
+		})
+	}
+
+	fn generate_key(key_server: &RetryingKeyServer<MockKeyServer>) -> Result<(), Error> {
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		block_on(key_server.generate_key(None, ServerKeyId::from_low_u64_be(42), requester, 0)).result.map(|_| ())
+	}
+
+	#[test]
+	fn operation_is_retried_after_transient_failures() {
+		let key_server = retrying_key_server();
+		key_server.inner().fail_next_sessions(vec![Error::NodeDisconnected, Error::ConsensusTemporaryUnreachable]);
+		assert_eq!(generate_key(&key_server), Ok(()));
+		assert_eq!(key_server.inner().started_sessions(), 3);
+	}
+
+	#[test]
+	fn operation_is_not_retried_after_fatal_failure() {
+		let key_server = retrying_key_server();
+		key_server.inner().fail_next_sessions(vec![Error::AccessDenied]);
+		assert_eq!(generate_key(&key_server), Err(Error::AccessDenied));
+		assert_eq!(key_server.inner().started_sessions(), 1);
+	}
+
+	#[test]
+	fn operation_fails_when_attempts_are_exhausted() {
+		let key_server = retrying_key_server();
+		key_server.inner().fail_next_sessions(vec![Error::NodeDisconnected; 3]);
+		assert_eq!(generate_key(&key_server), Err(Error::NodeDisconnected));
+		assert_eq!(key_server.inner().started_sessions(), 3);
+	}
+
+	#[test]
+	fn key_generation_that_has_taken_effect_is_resolved() {
+		let key_server = retrying_key_server();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		key_server.inner().fail_next_sessions_after_commit(vec![Error::NodeDisconnected]);
+
+		let artifacts = block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap();
+		let key_share = key_server.inner().key_storage().get(&key_id).unwrap().unwrap();
+		assert_eq!(artifacts.key, key_share.public);
+		// generation, retried generation and retrieval
+		assert_eq!(key_server.inner().started_sessions(), 3);
+
+		// key with different threshold isn't resolved
+		let key_id = ServerKeyId::from_low_u64_be(43);
+		key_server.inner().key_storage().insert(key_id, KeyShare { threshold: 1, ..key_share }).unwrap();
+		key_server.inner().fail_next_sessions(vec![Error::NodeDisconnected]);
+		assert_eq!(
+			block_on(key_server.generate_key(None, key_id, requester, 0)).result.map(|_| ()),
+			Err(Error::NodeDisconnected),
+		);
+	}
+
+	#[test]
+	fn document_key_store_that_has_taken_effect_fails_with_original_error() {
+		let key_server = retrying_key_server();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap();
+
+		key_server.inner().fail_next_sessions_after_commit(vec![Error::ConsensusTemporaryUnreachable]);
+		let common_point = *Random.generate().unwrap().public();
+		let encrypted_point = *Random.generate().unwrap().public();
+		assert_eq!(
+			block_on(key_server.store_document_key(None, key_id, requester, common_point, encrypted_point))
+				.result.map(|_| ()),
+			Err(Error::ConsensusTemporaryUnreachable),
+		);
+		assert!(key_server.inner().key_storage().get(&key_id).unwrap().unwrap().common_point.is_some());
+	}
+}
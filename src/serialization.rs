@@ -14,15 +14,26 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity Ethereum.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::BTreeMap;
 use std::fmt;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use rustc_hex::{self, FromHex};
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
-use serde::de::{Visitor, Error as SerdeError};
+use serde::de::{DeserializeOwned, Visitor, Error as SerdeError};
 use parity_crypto::publickey::{Public, Secret, Signature};
 use ethereum_types::{H160, H256};
 use parity_bytes::Bytes;
-use crate::requester::Requester;
+use crate::{
+	error::Error,
+	key_server::{DocumentKeyShadow, EncryptedDocumentKey},
+	key_server_set::{KeyServerSetMigration, KeyServerSetSnapshot},
+	key_storage::KeyShare,
+	message::ProtocolMessage,
+	requester::Requester,
+};
+#[cfg(feature = "key-share-serde")]
+use crate::key_storage::KeyShareVersion;
 
 trait ToHex {
 	fn to_hex(&self) -> String;
@@ -108,7 +119,7 @@ macro_rules! impl_bytes {
 					}
 
 					fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> where E: SerdeError {
-						if value.len() >= 2 && &value[0..2] == "0x" && value.len() & 1 == 0 {
+						if value.starts_with("0x") && value.len() & 1 == 0 {
 							impl_bytes_deserialize!($name, value, $from_hex)
 						} else {
 							Err(SerdeError::custom("invalid format"))
@@ -162,6 +173,8 @@ pub enum SerializableRequester {
 	Public(SerializablePublic),
 	/// Requested with verified address.
 	Address(SerializableAddress),
+	/// Requested with server key id signature and expected public key.
+	SignatureWithPublic(SerializableSignature, SerializablePublic),
 }
 
 impl From<SerializableRequester> for Requester {
@@ -170,6 +183,8 @@ impl From<SerializableRequester> for Requester {
 			SerializableRequester::Signature(signature) => Requester::Signature(signature.into()),
 			SerializableRequester::Public(public) => Requester::Public(public.into()),
 			SerializableRequester::Address(address) => Requester::Address(address.into()),
+			SerializableRequester::SignatureWithPublic(signature, public) =>
+				Requester::SignatureWithPublic(signature.into(), public.into()),
 		}
 	}
 }
@@ -180,10 +195,387 @@ impl From<Requester> for SerializableRequester {
 			Requester::Signature(signature) => SerializableRequester::Signature(signature.into()),
 			Requester::Public(public) => SerializableRequester::Public(public.into()),
 			Requester::Address(address) => SerializableRequester::Address(address.into()),
+			Requester::SignatureWithPublic(signature, public) =>
+				SerializableRequester::SignatureWithPublic(signature.into(), public.into()),
 		}
 	}
 }
 
+/// Serializable key share.
+///
+/// WARNING: serialized key share contains UNENCRYPTED secret shares. It must be
+/// protected at least as well as the key storage itself.
+#[cfg(feature = "key-share-serde")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SerializableKeyShare {
+	/// Author of the entry.
+	pub author: SerializableAddress,
+	/// Decryption threshold.
+	pub threshold: usize,
+	/// Server public key.
+	pub public: SerializablePublic,
+	/// Common (shared) encryption point.
+	pub common_point: Option<SerializablePublic>,
+	/// Encrypted point.
+	pub encrypted_point: Option<SerializablePublic>,
+	/// Key share versions.
+	pub versions: Vec<SerializableKeyShareVersion>,
+}
+
+/// Serializable key share version.
+#[cfg(feature = "key-share-serde")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SerializableKeyShareVersion {
+	/// Version hash.
+	pub hash: SerializableH256,
+	/// Nodes ids numbers.
+	pub id_numbers: BTreeMap<SerializableAddress, SerializableSecret>,
+	/// Node secret share. UNENCRYPTED.
+	pub secret_share: SerializableSecret,
+}
+
+#[cfg(feature = "key-share-serde")]
+impl From<KeyShare> for SerializableKeyShare {
+	fn from(key_share: KeyShare) -> SerializableKeyShare {
+		SerializableKeyShare {
+			author: key_share.author.into(),
+			threshold: key_share.threshold,
+			public: key_share.public.into(),
+			common_point: key_share.common_point.map(Into::into),
+			encrypted_point: key_share.encrypted_point.map(Into::into),
+			versions: key_share.versions.into_iter().map(Into::into).collect(),
+		}
+	}
+}
+
+#[cfg(feature = "key-share-serde")]
+impl From<SerializableKeyShare> for KeyShare {
+	fn from(key_share: SerializableKeyShare) -> KeyShare {
+		KeyShare {
+			author: key_share.author.into(),
+			threshold: key_share.threshold,
+			public: key_share.public.into(),
+			common_point: key_share.common_point.map(Into::into),
+			encrypted_point: key_share.encrypted_point.map(Into::into),
+			versions: key_share.versions.into_iter().map(Into::into).collect(),
+		}
+	}
+}
+
+#[cfg(feature = "key-share-serde")]
+impl From<KeyShareVersion> for SerializableKeyShareVersion {
+	fn from(version: KeyShareVersion) -> SerializableKeyShareVersion {
+		SerializableKeyShareVersion {
+			hash: version.hash.into(),
+			id_numbers: version.id_numbers.into_iter().map(|(node, id_number)| (node.into(), id_number.into())).collect(),
+			secret_share: version.secret_share.into(),
+		}
+	}
+}
+
+#[cfg(feature = "key-share-serde")]
+impl From<SerializableKeyShareVersion> for KeyShareVersion {
+	fn from(version: SerializableKeyShareVersion) -> KeyShareVersion {
+		KeyShareVersion {
+			hash: version.hash.into(),
+			id_numbers: version.id_numbers.into_iter().map(|(node, id_number)| (node.into(), id_number.into())).collect(),
+			secret_share: version.secret_share.into(),
+		}
+	}
+}
+
+/// Serializable key server set snapshot.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SerializableKeyServerSetSnapshot {
+	/// Current set of key servers.
+	pub current_set: BTreeMap<SerializableAddress, SocketAddr>,
+	/// New set of key servers.
+	pub new_set: BTreeMap<SerializableAddress, SocketAddr>,
+	/// Current migration data.
+	pub migration: Option<SerializableKeyServerSetMigration>,
+	/// Generation of the snapshot.
+	pub generation: u64,
+	/// Number of the block this snapshot has been read at.
+	pub block_number: Option<u64>,
+}
+
+/// Serializable key server set migration.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SerializableKeyServerSetMigration {
+	/// Migration id.
+	pub id: SerializableH256,
+	/// Migration set of key servers.
+	pub set: BTreeMap<SerializableAddress, SocketAddr>,
+	/// Master node of the migration process.
+	pub master: SerializableAddress,
+	/// Is migration confirmed by this node?
+	pub is_confirmed: bool,
+}
+
+impl From<KeyServerSetSnapshot<SocketAddr>> for SerializableKeyServerSetSnapshot {
+	fn from(snapshot: KeyServerSetSnapshot<SocketAddr>) -> SerializableKeyServerSetSnapshot {
+		SerializableKeyServerSetSnapshot {
+			current_set: snapshot.current_set.into_iter().map(|(node, address)| (node.into(), address)).collect(),
+			new_set: snapshot.new_set.into_iter().map(|(node, address)| (node.into(), address)).collect(),
+			migration: snapshot.migration.map(Into::into),
+			generation: snapshot.generation,
+			block_number: snapshot.block_number,
+		}
+	}
+}
+
+impl From<SerializableKeyServerSetSnapshot> for KeyServerSetSnapshot<SocketAddr> {
+	fn from(snapshot: SerializableKeyServerSetSnapshot) -> KeyServerSetSnapshot<SocketAddr> {
+		KeyServerSetSnapshot {
+			current_set: snapshot.current_set.into_iter().map(|(node, address)| (node.into(), address)).collect(),
+			new_set: snapshot.new_set.into_iter().map(|(node, address)| (node.into(), address)).collect(),
+			migration: snapshot.migration.map(Into::into),
+			generation: snapshot.generation,
+			block_number: snapshot.block_number,
+		}
+	}
+}
+
+impl From<KeyServerSetMigration<SocketAddr>> for SerializableKeyServerSetMigration {
+	fn from(migration: KeyServerSetMigration<SocketAddr>) -> SerializableKeyServerSetMigration {
+		SerializableKeyServerSetMigration {
+			id: migration.id.into(),
+			set: migration.set.into_iter().map(|(node, address)| (node.into(), address)).collect(),
+			master: migration.master.into(),
+			is_confirmed: migration.is_confirmed,
+		}
+	}
+}
+
+impl From<SerializableKeyServerSetMigration> for KeyServerSetMigration<SocketAddr> {
+	fn from(migration: SerializableKeyServerSetMigration) -> KeyServerSetMigration<SocketAddr> {
+		KeyServerSetMigration {
+			id: migration.id.into(),
+			set: migration.set.into_iter().map(|(node, address)| (node.into(), address)).collect(),
+			master: migration.master.into(),
+			is_confirmed: migration.is_confirmed,
+		}
+	}
+}
+
+/// Type with versioned binary encoding.
+pub trait VersionedEncoding: Sized {
+	/// Current version of the encoding.
+	const VERSION: u8;
+
+	/// Encode value using current version of the encoding.
+	fn encode_payload(&self) -> Vec<u8>;
+	/// Decode value, encoded using current version of the encoding.
+	fn decode_payload(payload: &[u8]) -> Result<Self, Error>;
+}
+
+/// Value with versioned binary encoding. Encoded value is prefixed with the
+/// version byte, so that the data encoded with unknown version of the encoding
+/// is rejected instead of being silently misinterpreted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Versioned<T> {
+	/// Encoding version.
+	pub version: u8,
+	/// The value itself.
+	pub inner: T,
+}
+
+impl<T: VersionedEncoding> Versioned<T> {
+	/// Wrap value that is going to be encoded using current version of the encoding.
+	pub fn new(inner: T) -> Self {
+		Versioned {
+			version: T::VERSION,
+			inner,
+		}
+	}
+
+	/// Encode value.
+	pub fn encode(&self) -> Vec<u8> {
+		let mut encoded = vec![self.version];
+		encoded.extend(self.inner.encode_payload());
+		encoded
+	}
+
+	/// Decode value.
+	pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+		match encoded.split_first() {
+			Some((&version, payload)) if version == T::VERSION => Ok(Versioned {
+				version,
+				inner: T::decode_payload(payload)?,
+			}),
+			Some((&version, _)) => Err(Error::UnsupportedSerializationVersion(version)),
+			None => Err(Error::Serde("empty versioned data".into())),
+		}
+	}
+}
+
+/// Format of the persisted data. Every value, serialized with `serialize_with`, is
+/// prefixed with the format byte, so that `deserialize` is able to detect the format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerializationFormat {
+	/// JSON format. Slower and larger, but human-readable.
+	Json,
+	/// Compact binary format.
+	Binary,
+}
+
+impl SerializationFormat {
+	/// Byte that is prefixing values serialized using this format.
+	pub fn header(&self) -> u8 {
+		match *self {
+			SerializationFormat::Json => 0x01,
+			SerializationFormat::Binary => 0x02,
+		}
+	}
+
+	/// Get format by the header byte.
+	pub fn from_header(header: u8) -> Result<Self, Error> {
+		match header {
+			0x01 => Ok(SerializationFormat::Json),
+			0x02 => Ok(SerializationFormat::Binary),
+			_ => Err(Error::Serde(format!("unknown serialization format: {}", header))),
+		}
+	}
+}
+
+/// Type that could be persisted using any of `SerializationFormat`.
+pub trait FormatSerializable: Sized {
+	/// Serialize value to JSON.
+	fn to_json(&self) -> Result<Vec<u8>, Error>;
+	/// Deserialize value from JSON.
+	fn from_json(serialized: &[u8]) -> Result<Self, Error>;
+	/// Serialize value to the compact binary format.
+	fn to_binary(&self) -> Vec<u8>;
+	/// Deserialize value from the compact binary format.
+	fn from_binary(serialized: &[u8]) -> Result<Self, Error>;
+}
+
+#[cfg(feature = "key-share-serde")]
+fn to_json_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+	serde_json::to_vec(value).map_err(|error| Error::Serde(error.to_string()))
+}
+
+#[cfg(not(feature = "key-share-serde"))]
+fn to_json_bytes<T: Serialize>(_value: &T) -> Result<Vec<u8>, Error> {
+	Err(Error::Serde("JSON format requires `key-share-serde` feature".into()))
+}
+
+#[cfg(feature = "key-share-serde")]
+fn from_json_bytes<T: DeserializeOwned>(serialized: &[u8]) -> Result<T, Error> {
+	serde_json::from_slice(serialized).map_err(|error| Error::Serde(error.to_string()))
+}
+
+#[cfg(not(feature = "key-share-serde"))]
+fn from_json_bytes<T: DeserializeOwned>(_serialized: &[u8]) -> Result<T, Error> {
+	Err(Error::Serde("JSON format requires `key-share-serde` feature".into()))
+}
+
+#[cfg(feature = "key-share-serde")]
+impl FormatSerializable for KeyShare {
+	fn to_json(&self) -> Result<Vec<u8>, Error> {
+		to_json_bytes(&SerializableKeyShare::from(self.clone()))
+	}
+
+	fn from_json(serialized: &[u8]) -> Result<Self, Error> {
+		from_json_bytes::<SerializableKeyShare>(serialized).map(Into::into)
+	}
+
+	fn to_binary(&self) -> Vec<u8> {
+		self.to_canonical_bytes()
+	}
+
+	fn from_binary(serialized: &[u8]) -> Result<Self, Error> {
+		KeyShare::from_canonical_bytes(serialized)
+	}
+}
+
+/// JSON format is only available with `key-share-serde` feature.
+impl FormatSerializable for KeyServerSetSnapshot<SocketAddr> {
+	fn to_json(&self) -> Result<Vec<u8>, Error> {
+		to_json_bytes(&SerializableKeyServerSetSnapshot::from(self.clone()))
+	}
+
+	fn from_json(serialized: &[u8]) -> Result<Self, Error> {
+		from_json_bytes::<SerializableKeyServerSetSnapshot>(serialized).map(Into::into)
+	}
+
+	fn to_binary(&self) -> Vec<u8> {
+		Versioned::new(self.clone()).encode()
+	}
+
+	fn from_binary(serialized: &[u8]) -> Result<Self, Error> {
+		Versioned::decode(serialized).map(|snapshot| snapshot.inner)
+	}
+}
+
+/// Serialize value using given format.
+pub fn serialize_with<T: FormatSerializable>(value: &T, format: SerializationFormat) -> Result<Vec<u8>, Error> {
+	let mut serialized = vec![format.header()];
+	match format {
+		SerializationFormat::Json => serialized.extend(value.to_json()?),
+		SerializationFormat::Binary => serialized.extend(value.to_binary()),
+	}
+	Ok(serialized)
+}
+
+/// Deserialize value, serialized with `serialize_with` using given format. Fails if
+/// the value has been serialized using other format.
+pub fn deserialize_with<T: FormatSerializable>(serialized: &[u8], format: SerializationFormat) -> Result<T, Error> {
+	match serialized.split_first() {
+		Some((&header, payload)) if header == format.header() => match format {
+			SerializationFormat::Json => T::from_json(payload),
+			SerializationFormat::Binary => T::from_binary(payload),
+		},
+		Some((&header, _)) => Err(Error::Serde(format!(
+			"expected {:?} format, found: {:?}",
+			format,
+			SerializationFormat::from_header(header)?,
+		))),
+		None => Err(Error::Serde("empty serialized data".into())),
+	}
+}
+
+/// Deserialize value, serialized with `serialize_with`. The format is detected using
+/// the header byte.
+pub fn deserialize<T: FormatSerializable>(serialized: &[u8]) -> Result<T, Error> {
+	match serialized.first() {
+		Some(&header) => deserialize_with(serialized, SerializationFormat::from_header(header)?),
+		None => Err(Error::Serde("empty serialized data".into())),
+	}
+}
+
+/// Artifact, decoded by `try_decode_any`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodedArtifact {
+	/// Key share, encoded with `KeyShare::to_canonical_bytes`.
+	KeyShare(KeyShare),
+	/// Document key shadow, encoded with `DocumentKeyShadow::encode`.
+	DocumentKeyShadow(DocumentKeyShadow),
+	/// Encrypted document key, encoded with `EncryptedDocumentKey::encode`.
+	EncryptedDocumentKey(EncryptedDocumentKey),
+	/// Protocol message, encoded with `ProtocolMessage::encode`.
+	ProtocolMessage(ProtocolMessage),
+	/// Key share, serialized with `serialize_with`.
+	#[cfg(feature = "key-share-serde")]
+	SerializedKeyShare(KeyShare),
+}
+
+/// Decode any of artifacts that are decoded from untrusted data. The first byte selects
+/// the artifact decoder and the rest is passed to this decoder. Never panics, so it could
+/// be used as a fuzzing target.
+pub fn try_decode_any(encoded: &[u8]) -> Result<DecodedArtifact, Error> {
+	match encoded.split_first() {
+		Some((0, encoded)) => KeyShare::from_canonical_bytes(encoded).map(DecodedArtifact::KeyShare),
+		Some((1, encoded)) => DocumentKeyShadow::decode(encoded).map(DecodedArtifact::DocumentKeyShadow),
+		Some((2, encoded)) => EncryptedDocumentKey::decode(encoded).map(DecodedArtifact::EncryptedDocumentKey),
+		Some((3, encoded)) => ProtocolMessage::decode(encoded).map(DecodedArtifact::ProtocolMessage),
+		#[cfg(feature = "key-share-serde")]
+		Some((4, encoded)) => deserialize(encoded).map(DecodedArtifact::SerializedKeyShare),
+		Some((&tag, _)) => Err(Error::Serde(format!("unknown artifact tag: {}", tag))),
+		None => Err(Error::Serde("empty encoded artifact".into())),
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use serde_json;
@@ -199,6 +591,37 @@ mod tests {
 		);
 	}
 
+	#[derive(Debug, PartialEq)]
+	struct TestPayload(Vec<u8>);
+
+	impl VersionedEncoding for TestPayload {
+		const VERSION: u8 = 1;
+
+		fn encode_payload(&self) -> Vec<u8> {
+			self.0.clone()
+		}
+
+		fn decode_payload(payload: &[u8]) -> Result<Self, Error> {
+			Ok(TestPayload(payload.to_vec()))
+		}
+	}
+
+	#[test]
+	fn versioned_encode_and_decode() {
+		let value = Versioned::new(TestPayload(vec![1, 2, 3]));
+		assert_eq!(value.encode(), vec![1, 1, 2, 3]);
+		assert_eq!(Versioned::decode(&[1, 1, 2, 3]), Ok(value));
+	}
+
+	#[test]
+	fn versioned_decode_rejects_unknown_version() {
+		assert_eq!(
+			Versioned::<TestPayload>::decode(&[99, 1, 2, 3]),
+			Err(Error::UnsupportedSerializationVersion(99)),
+		);
+		assert!(Versioned::<TestPayload>::decode(&[]).is_err());
+	}
+
 	#[test]
 	fn serialize_and_deserialize_bytes() {
 		do_test!(SerializableBytes(vec![1, 2, 3, 4]), "\"0x01020304\"".to_owned(), SerializableBytes);
@@ -242,4 +665,155 @@ mod tests {
 		let public = SerializableSignature(Signature::from_rsv(&r, &s, v));
 		do_test!(public, format!("\"0x{}{}{:x}\"", raw_r, raw_s, v), SerializableSignature);
 	}
+
+	#[cfg(feature = "key-share-serde")]
+	#[test]
+	fn serialize_and_deserialize_key_share_version() {
+		let version = SerializableKeyShareVersion {
+			hash: H256::from_low_u64_be(1).into(),
+			id_numbers: vec![(H160::from_low_u64_be(2).into(), Secret::from(H256::from_low_u64_be(3)).into())]
+				.into_iter()
+				.collect(),
+			secret_share: Secret::from(H256::from_low_u64_be(4)).into(),
+		};
+		do_test!(version, format!(
+			"{{\"hash\":\"0x{:064x}\",\"id_numbers\":{{\"0x{:040x}\":\"0x{:064x}\"}},\"secret_share\":\"0x{:064x}\"}}",
+			1, 2, 3, 4,
+		), SerializableKeyShareVersion);
+	}
+
+	#[cfg(feature = "key-share-serde")]
+	fn multi_version_key_share() -> KeyShare {
+		let version = |seed: u64| KeyShareVersion::new(
+			vec![
+				(H160::from_low_u64_be(seed), Secret::from(H256::from_low_u64_be(seed + 1))),
+				(H160::from_low_u64_be(seed + 2), Secret::from(H256::from_low_u64_be(seed + 3))),
+			].into_iter().collect(),
+			Secret::from(H256::from_low_u64_be(seed + 4)),
+		);
+		KeyShare {
+			author: H160::from_low_u64_be(1),
+			threshold: 1,
+			public: Public::from_low_u64_be(2),
+			common_point: Some(Public::from_low_u64_be(3)),
+			encrypted_point: None,
+			versions: vec![version(10), version(20)],
+		}
+	}
+
+	#[cfg(feature = "key-share-serde")]
+	#[test]
+	fn serialize_and_deserialize_multi_version_key_share() {
+		let key_share = multi_version_key_share();
+		let serialized = serde_json::to_string(&SerializableKeyShare::from(key_share.clone())).unwrap();
+		let deserialized: SerializableKeyShare = serde_json::from_str(&serialized).unwrap();
+		assert_eq!(KeyShare::from(deserialized), key_share);
+	}
+
+	#[cfg(feature = "key-share-serde")]
+	#[test]
+	fn key_share_is_serialized_with_every_format() {
+		let key_share = multi_version_key_share();
+		for format in &[SerializationFormat::Json, SerializationFormat::Binary] {
+			let serialized = serialize_with(&key_share, *format).unwrap();
+			assert_eq!(serialized[0], format.header());
+			assert_eq!(deserialize_with::<KeyShare>(&serialized, *format), Ok(key_share.clone()));
+		}
+	}
+
+	#[cfg(feature = "key-share-serde")]
+	#[test]
+	fn serialization_format_is_detected() {
+		let key_share = multi_version_key_share();
+		let json = serialize_with(&key_share, SerializationFormat::Json).unwrap();
+		let binary = serialize_with(&key_share, SerializationFormat::Binary).unwrap();
+		assert_eq!(deserialize::<KeyShare>(&json), Ok(key_share.clone()));
+		assert_eq!(deserialize::<KeyShare>(&binary), Ok(key_share));
+		assert!(deserialize_with::<KeyShare>(&json, SerializationFormat::Binary).is_err());
+		assert!(deserialize::<KeyShare>(&[0xff]).is_err());
+	}
+
+	fn key_server_set_snapshot() -> KeyServerSetSnapshot<SocketAddr> {
+		let set = |seed: u64| vec![
+			(H160::from_low_u64_be(seed), format!("127.0.0.1:{}", seed).parse().unwrap()),
+			(H160::from_low_u64_be(seed + 1), format!("[::1]:{}", seed + 1).parse().unwrap()),
+		].into_iter().collect();
+		KeyServerSetSnapshot {
+			current_set: set(10),
+			new_set: set(20),
+			migration: Some(KeyServerSetMigration {
+				id: H256::from_low_u64_be(1),
+				set: set(30),
+				master: H160::from_low_u64_be(30),
+				is_confirmed: true,
+			}),
+			generation: 5,
+			block_number: Some(100),
+		}
+	}
+
+	#[test]
+	fn key_server_set_snapshot_is_serialized_with_binary_format() {
+		let snapshot = key_server_set_snapshot();
+		let serialized = serialize_with(&snapshot, SerializationFormat::Binary).unwrap();
+		assert_eq!(serialized[0], SerializationFormat::Binary.header());
+		assert_eq!(deserialize_with(&serialized, SerializationFormat::Binary), Ok(snapshot.clone()));
+		assert_eq!(deserialize(&serialized), Ok(snapshot.clone()));
+
+		let empty_snapshot = KeyServerSetSnapshot {
+			current_set: BTreeMap::new(),
+			new_set: BTreeMap::new(),
+			migration: None,
+			generation: 0,
+			block_number: None,
+		};
+		assert_eq!(deserialize(&serialize_with(&empty_snapshot, SerializationFormat::Binary).unwrap()), Ok(empty_snapshot));
+
+		for len in 1..serialized.len() {
+			assert!(deserialize::<KeyServerSetSnapshot<SocketAddr>>(&serialized[..len]).is_err());
+		}
+		let mut with_trailing_bytes = serialized;
+		with_trailing_bytes.push(0);
+		assert!(deserialize::<KeyServerSetSnapshot<SocketAddr>>(&with_trailing_bytes).is_err());
+	}
+
+	#[cfg(feature = "key-share-serde")]
+	#[test]
+	fn key_server_set_snapshot_is_serialized_with_json_format() {
+		let snapshot = key_server_set_snapshot();
+		let json = serialize_with(&snapshot, SerializationFormat::Json).unwrap();
+		let binary = serialize_with(&snapshot, SerializationFormat::Binary).unwrap();
+		assert_eq!(json[0], SerializationFormat::Json.header());
+		assert_eq!(deserialize_with(&json, SerializationFormat::Json), Ok(snapshot.clone()));
+		assert_eq!(deserialize(&json), Ok(snapshot.clone()));
+		assert_eq!(deserialize(&binary), Ok(snapshot));
+		assert!(deserialize_with::<KeyServerSetSnapshot<SocketAddr>>(&json, SerializationFormat::Binary).is_err());
+	}
+
+	#[test]
+	fn malformed_artifacts_are_not_decoded() {
+		let key_share = KeyShare {
+			author: H160::from_low_u64_be(1),
+			threshold: 0,
+			public: Public::from_low_u64_be(2),
+			common_point: None,
+			encrypted_point: None,
+			versions: Vec::new(),
+		};
+		let mut encoded = vec![0];
+		encoded.extend(key_share.to_canonical_bytes());
+		assert_eq!(try_decode_any(&encoded), Ok(DecodedArtifact::KeyShare(key_share)));
+
+		for len in 0..encoded.len() {
+			assert!(try_decode_any(&encoded[..len]).is_err());
+		}
+		for tag in 0..=0xFF {
+			assert!(try_decode_any(&[tag, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+		}
+	}
+
+	#[test]
+	fn hex_with_non_ascii_prefix_is_not_deserialized() {
+		assert!(serde_json::from_str::<SerializableH256>("\"\u{20ac}x\"").is_err());
+	}
 }
@@ -14,12 +14,21 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::{collections::BTreeSet, sync::Arc};
+use std::{collections::{BTreeSet, HashMap}, sync::Arc};
+use parking_lot::RwLock;
+use rustc_hex::ToHex;
 use ethereum_types::H256;
-use parity_crypto::publickey::{Public, Signature};
+use tiny_keccak::{Hasher, Keccak};
+use parity_crypto::publickey::{Address, Public, Secret, Signature, public_to_address, recover, sign, verify_public};
 use crate::{
-	ServerKeyId, KeyServerPublic,
-	key_server::{ServerKeyGenerationResult, DocumentKeyShadowRetrievalResult},
+	ServerKeyId, KeyServerPublic, is_valid_server_key_id,
+	error::Error,
+	key_server::{
+		KeyServer, ServerKeyGenerationResult, ServerKeyGenerationArtifacts, ServerKeyRetrievalArtifacts,
+		EncryptedDocumentKeyGenerationArtifacts, DocumentKeyRetrievalArtifacts,
+		DocumentKeyShadowRetrievalArtifacts, DocumentKeyShadowRetrievalResult, SchnorrSigningArtifacts,
+		EcdsaSigningArtifacts, ServersSetChangeArtifacts, Artifacts, DocumentKeyStoreArtifacts,
+	},
 	requester::Requester,
 };
 
@@ -42,7 +51,7 @@ pub trait ServiceTasksListener: Send + Sync {
 }
 
 /// Service contract task.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ServiceTask {
 	// === Server key related tasks ===
 
@@ -75,5 +84,680 @@ pub enum ServiceTask {
 	// === Administrative tasks ===
 
 	/// Change servers set (old_set_signature, new_set_signature, new_set).
+	/// Both signatures are signing `servers_set_hash` of the corresponding set.
 	ChangeServersSet(Signature, Signature, BTreeSet<KeyServerPublic>),
 }
+
+impl ServiceTask {
+	/// Get id of the key this task is working with.
+	pub fn key_id(&self) -> Option<&ServerKeyId> {
+		match *self {
+			ServiceTask::GenerateServerKey(ref key_id, ..) | ServiceTask::RetrieveServerKey(ref key_id, ..) |
+				ServiceTask::GenerateDocumentKey(ref key_id, ..) | ServiceTask::StoreDocumentKey(ref key_id, ..) |
+				ServiceTask::RetrieveDocumentKey(ref key_id, ..) | ServiceTask::RetrieveShadowDocumentKey(ref key_id, ..) |
+				ServiceTask::SchnorrSignMessage(ref key_id, ..) | ServiceTask::EcdsaSignMessage(ref key_id, ..) => Some(key_id),
+			ServiceTask::ChangeServersSet(..) => None,
+		}
+	}
+
+	/// Get short key for correlating the task in logs: task kind, followed by the first
+	/// 4 bytes of the key id (e.g. `gen_sk:0x12345678…`). Neither requester, nor any key
+	/// material is included.
+	pub fn log_key(&self) -> String {
+		let kind = self.kind_name();
+		match self.key_id() {
+			Some(key_id) => format!("{}:0x{}\u{2026}", kind, key_id[..4].to_hex()),
+			None => kind.into(),
+		}
+	}
+
+	/// Get requester of the task (if any).
+	pub fn requester(&self) -> Option<&Requester> {
+		match *self {
+			ServiceTask::GenerateServerKey(_, ref requester, _) | ServiceTask::GenerateDocumentKey(_, ref requester, _) |
+				ServiceTask::StoreDocumentKey(_, ref requester, ..) | ServiceTask::RetrieveDocumentKey(_, ref requester) |
+				ServiceTask::RetrieveShadowDocumentKey(_, ref requester) |
+				ServiceTask::SchnorrSignMessage(_, ref requester, _) |
+				ServiceTask::EcdsaSignMessage(_, ref requester, _) => Some(requester),
+			ServiceTask::RetrieveServerKey(_, ref requester) => requester.as_ref(),
+			ServiceTask::ChangeServersSet(..) => None,
+		}
+	}
+
+	/// Compute hash of the task: `Keccak(kind || key_id || parameters)`. Requester is not
+	/// hashed, because it is expected to sign the hash (see `SignedServiceRequest`).
+	pub fn hash(&self) -> H256 {
+		let mut keccak = Keccak::v256();
+		keccak.update(self.kind_name().as_bytes());
+		if let Some(key_id) = self.key_id() {
+			keccak.update(key_id.as_bytes());
+		}
+		match *self {
+			ServiceTask::GenerateServerKey(_, _, threshold) | ServiceTask::GenerateDocumentKey(_, _, threshold) =>
+				keccak.update(&(threshold as u64).to_be_bytes()),
+			ServiceTask::StoreDocumentKey(_, _, ref common_point, ref encrypted_point) => {
+				keccak.update(common_point.as_bytes());
+				keccak.update(encrypted_point.as_bytes());
+			},
+			ServiceTask::SchnorrSignMessage(_, _, ref message) | ServiceTask::EcdsaSignMessage(_, _, ref message) =>
+				keccak.update(message.as_bytes()),
+			ServiceTask::ChangeServersSet(ref old_set_signature, ref new_set_signature, ref new_set) => {
+				keccak.update(&old_set_signature[..]);
+				keccak.update(&new_set_signature[..]);
+				keccak.update(servers_set_hash(new_set).as_bytes());
+			},
+			ServiceTask::RetrieveServerKey(..) | ServiceTask::RetrieveDocumentKey(..) |
+				ServiceTask::RetrieveShadowDocumentKey(..) => (),
+		}
+
+		let mut hash = [0u8; 32];
+		keccak.finalize(&mut hash);
+		hash.into()
+	}
+
+	/// Get short name of the task kind.
+	fn kind_name(&self) -> &'static str {
+		match *self {
+			ServiceTask::GenerateServerKey(..) => "gen_sk",
+			ServiceTask::RetrieveServerKey(..) => "get_sk",
+			ServiceTask::GenerateDocumentKey(..) => "gen_dk",
+			ServiceTask::StoreDocumentKey(..) => "store_dk",
+			ServiceTask::RetrieveDocumentKey(..) => "get_dk",
+			ServiceTask::RetrieveShadowDocumentKey(..) => "get_dk_shadow",
+			ServiceTask::SchnorrSignMessage(..) => "sign_schnorr",
+			ServiceTask::EcdsaSignMessage(..) => "sign_ecdsa",
+			ServiceTask::ChangeServersSet(..) => "change_servers_set",
+		}
+	}
+
+	/// Check that the task could be processed.
+	pub fn validate(&self) -> Result<(), Error> {
+		match self.key_id() {
+			Some(key_id) if !is_valid_server_key_id(key_id) => Err(Error::InvalidServiceTask("key_id is invalid".into())),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Result of successful service task execution.
+#[derive(Clone)]
+pub enum ServiceResponse {
+	/// Server key has been generated.
+	ServerKeyGenerated(ServerKeyGenerationArtifacts),
+	/// Server key has been retrieved.
+	ServerKeyRetrieved(ServerKeyRetrievalArtifacts),
+	/// Document key has been generated. It is encrypted with author public key.
+	DocumentKeyGenerated(EncryptedDocumentKeyGenerationArtifacts),
+	/// Document key has been stored.
+	DocumentKeyStored,
+	/// Document key has been retrieved and encrypted with requester public key.
+	DocumentKeyRetrieved(DocumentKeyRetrievalArtifacts),
+	/// Document key shadow has been retrieved.
+	DocumentKeyShadowRetrieved(DocumentKeyShadowRetrievalArtifacts),
+	/// Message has been signed with Schnorr scheme.
+	SchnorrSignature(SchnorrSigningArtifacts),
+	/// Message has been signed with ECDSA scheme.
+	EcdsaSignature(EcdsaSigningArtifacts),
+	/// Servers set has been changed.
+	ServersSetChanged(ServersSetChangeArtifacts),
+}
+
+impl From<ServiceResponse> for Artifacts {
+	fn from(response: ServiceResponse) -> Self {
+		match response {
+			ServiceResponse::ServerKeyGenerated(artifacts) => artifacts.into(),
+			ServiceResponse::ServerKeyRetrieved(artifacts) => artifacts.into(),
+			ServiceResponse::DocumentKeyGenerated(artifacts) => artifacts.into(),
+			ServiceResponse::DocumentKeyStored => DocumentKeyStoreArtifacts.into(),
+			ServiceResponse::DocumentKeyRetrieved(artifacts) => artifacts.into(),
+			ServiceResponse::DocumentKeyShadowRetrieved(artifacts) => artifacts.into(),
+			ServiceResponse::SchnorrSignature(artifacts) => artifacts.into(),
+			ServiceResponse::EcdsaSignature(artifacts) => artifacts.into(),
+			ServiceResponse::ServersSetChanged(artifacts) => artifacts.into(),
+		}
+	}
+}
+
+/// Execute service task by calling matching key server method.
+pub async fn execute_task<K: KeyServer>(key_server: &K, task: ServiceTask) -> Result<ServiceResponse, Error> {
+	match task {
+		ServiceTask::GenerateServerKey(key_id, author, threshold) => key_server
+			.generate_key(None, key_id, author, threshold).await
+			.map(ServiceResponse::ServerKeyGenerated),
+		ServiceTask::RetrieveServerKey(key_id, author) => key_server
+			.restore_key_public(None, key_id, author).await
+			.map(ServiceResponse::ServerKeyRetrieved),
+		ServiceTask::GenerateDocumentKey(key_id, author, threshold) => key_server
+			.generate_document_key_encrypted(None, key_id, author, threshold).await
+			.map(ServiceResponse::DocumentKeyGenerated),
+		ServiceTask::StoreDocumentKey(key_id, author, common_point, encrypted_point) => key_server
+			.store_document_key(None, key_id, author, common_point, encrypted_point).await
+			.map(|_| ServiceResponse::DocumentKeyStored),
+		ServiceTask::RetrieveDocumentKey(key_id, requester) => key_server
+			.restore_document_key(None, key_id, requester).await
+			.map(ServiceResponse::DocumentKeyRetrieved),
+		ServiceTask::RetrieveShadowDocumentKey(key_id, requester) => key_server
+			.restore_document_key_shadow(None, key_id, requester).await
+			.map(ServiceResponse::DocumentKeyShadowRetrieved),
+		ServiceTask::SchnorrSignMessage(key_id, requester, message) => key_server
+			.sign_message_schnorr(None, key_id, requester, message).await
+			.map(ServiceResponse::SchnorrSignature),
+		ServiceTask::EcdsaSignMessage(key_id, requester, message) => key_server
+			.sign_message_ecdsa(None, key_id, requester, message).await
+			.map(ServiceResponse::EcdsaSignature),
+		ServiceTask::ChangeServersSet(old_set_signature, new_set_signature, new_set) => key_server
+			.change_servers_set(None, old_set_signature, new_set_signature, new_set).await
+			.map(ServiceResponse::ServersSetChanged),
+	}
+}
+
+/// Service task, protected from replay attacks. Nonce and deadline are signed along with
+/// the task, so that they can't be replaced when the request is replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedServiceRequest {
+	/// The task itself.
+	pub task: ServiceTask,
+	/// Unique request nonce.
+	pub nonce: H256,
+	/// Timestamp (in seconds since Unix epoch) after which the request must be rejected.
+	pub deadline: u64,
+	/// Signature of the `request_hash`. If the task has a requester, the request must be
+	/// signed by this requester.
+	pub signature: Signature,
+}
+
+/// Storage of nonces of already processed service requests.
+pub trait NonceStorage: Send + Sync {
+	/// Remember given nonce until the request `deadline`. Returns false if the nonce has
+	/// been already seen. Nonces of requests, which deadline is before `now` may be
+	/// forgotten, because these requests are rejected anyway.
+	fn insert(&self, now: u64, nonce: H256, deadline: u64) -> bool;
+}
+
+/// In-memory nonce storage implementation.
+#[derive(Default, Debug)]
+pub struct InMemoryNonceStorage {
+	nonces: RwLock<InMemoryNonces>,
+}
+
+#[derive(Default, Debug)]
+struct InMemoryNonces {
+	/// Nonces, mapped to request deadlines.
+	by_nonce: HashMap<H256, u64>,
+	/// The same nonces, ordered by request deadlines.
+	by_deadline: BTreeSet<(u64, H256)>,
+}
+
+impl InMemoryNonceStorage {
+	/// Get number of remembered nonces.
+	pub fn len(&self) -> usize {
+		self.nonces.read().by_nonce.len()
+	}
+
+	/// Returns true if there are no remembered nonces.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl NonceStorage for InMemoryNonceStorage {
+	fn insert(&self, now: u64, nonce: H256, deadline: u64) -> bool {
+		let mut nonces = self.nonces.write();
+		while let Some(&(expired_deadline, expired_nonce)) = nonces.by_deadline.iter().next() {
+			if expired_deadline >= now {
+				break;
+			}
+
+			nonces.by_deadline.remove(&(expired_deadline, expired_nonce));
+			nonces.by_nonce.remove(&expired_nonce);
+		}
+
+		if nonces.by_nonce.contains_key(&nonce) {
+			return false;
+		}
+
+		nonces.by_nonce.insert(nonce, deadline);
+		nonces.by_deadline.insert((deadline, nonce));
+		true
+	}
+}
+
+impl SignedServiceRequest {
+	/// Create request, signed with the requester secret.
+	pub fn new(task: ServiceTask, nonce: H256, deadline: u64, secret: &Secret) -> Result<Self, Error> {
+		let signature = sign(secret, &Self::request_hash(&task, &nonce, deadline))?;
+		Ok(SignedServiceRequest {
+			task,
+			nonce,
+			deadline,
+			signature,
+		})
+	}
+
+	/// Compute hash that is signed by the requester: `Keccak(task_hash || nonce || deadline)`.
+	pub fn request_hash(task: &ServiceTask, nonce: &H256, deadline: u64) -> H256 {
+		let mut keccak = Keccak::v256();
+		keccak.update(task.hash().as_bytes());
+		keccak.update(nonce.as_bytes());
+		keccak.update(&deadline.to_be_bytes());
+
+		let mut hash = [0u8; 32];
+		keccak.finalize(&mut hash);
+		hash.into()
+	}
+
+	/// Recover address of the request signer. For tasks without requester (like servers
+	/// set change), the caller must check that the signer is allowed to submit the task.
+	pub fn signer(&self) -> Result<Address, Error> {
+		let request_hash = Self::request_hash(&self.task, &self.nonce, self.deadline);
+		Ok(public_to_address(&recover(&self.signature, &request_hash)?))
+	}
+
+	/// Check that the request is signed by the task requester and is neither expired,
+	/// nor replayed. The nonce is only remembered if the request is valid.
+	pub fn verify(&self, now: u64, nonces: &dyn NonceStorage) -> Result<(), Error> {
+		if now > self.deadline {
+			return Err(Error::RequestExpired);
+		}
+
+		let signer = self.signer()?;
+		if let (Some(key_id), Some(requester)) = (self.task.key_id(), self.task.requester()) {
+			if requester.address(key_id)? != signer {
+				return Err(Error::AccessDenied);
+			}
+		}
+
+		match nonces.insert(now, self.nonce, self.deadline) {
+			true => Ok(()),
+			false => Err(Error::ReplayedRequest),
+		}
+	}
+}
+
+/// Kind of service task, built by `ServiceTaskBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServiceTaskKind {
+	GenerateServerKey,
+	RetrieveServerKey,
+	GenerateDocumentKey,
+	StoreDocumentKey,
+	RetrieveDocumentKey,
+	RetrieveShadowDocumentKey,
+	SchnorrSignMessage,
+	EcdsaSignMessage,
+	ChangeServersSet,
+}
+
+/// Service task builder. Unlike `ServiceTask` variants, all task fields are named,
+/// so it is harder to pass them in wrong order. `build` fails if any field that is
+/// required by the task is missing, or if any key id, point or message is zero.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceTaskBuilder {
+	kind: Option<ServiceTaskKind>,
+	key_id: Option<ServerKeyId>,
+	requester: Option<Requester>,
+	threshold: Option<usize>,
+	common_point: Option<Public>,
+	encrypted_point: Option<Public>,
+	message: Option<H256>,
+	old_set_signature: Option<Signature>,
+	new_set_signature: Option<Signature>,
+	new_set: Option<BTreeSet<KeyServerPublic>>,
+}
+
+macro_rules! service_task_kind_setter {
+	($name: ident, $kind: ident) => {
+		/// Set kind of the task to build.
+		pub fn $name(mut self) -> Self {
+			self.kind = Some(ServiceTaskKind::$kind);
+			self
+		}
+	}
+}
+
+macro_rules! service_task_field_setter {
+	($name: ident, $field: ident, $type: ty) => {
+		/// Set task field.
+		pub fn $name(mut self, $field: $type) -> Self {
+			self.$field = Some($field);
+			self
+		}
+	}
+}
+
+impl ServiceTaskBuilder {
+	/// Create new builder.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	service_task_kind_setter!(generate_server_key, GenerateServerKey);
+	service_task_kind_setter!(retrieve_server_key, RetrieveServerKey);
+	service_task_kind_setter!(generate_document_key, GenerateDocumentKey);
+	service_task_kind_setter!(store_document_key, StoreDocumentKey);
+	service_task_kind_setter!(retrieve_document_key, RetrieveDocumentKey);
+	service_task_kind_setter!(retrieve_shadow_document_key, RetrieveShadowDocumentKey);
+	service_task_kind_setter!(schnorr_sign_message, SchnorrSignMessage);
+	service_task_kind_setter!(ecdsa_sign_message, EcdsaSignMessage);
+	service_task_kind_setter!(change_servers_set, ChangeServersSet);
+
+	service_task_field_setter!(key_id, key_id, ServerKeyId);
+	service_task_field_setter!(requester, requester, Requester);
+	service_task_field_setter!(threshold, threshold, usize);
+	service_task_field_setter!(common_point, common_point, Public);
+	service_task_field_setter!(encrypted_point, encrypted_point, Public);
+	service_task_field_setter!(message, message, H256);
+	service_task_field_setter!(old_set_signature, old_set_signature, Signature);
+	service_task_field_setter!(new_set_signature, new_set_signature, Signature);
+	service_task_field_setter!(new_set, new_set, BTreeSet<KeyServerPublic>);
+
+	/// Set key author. Same as `requester`.
+	pub fn author(self, author: Requester) -> Self {
+		self.requester(author)
+	}
+
+	/// Build service task.
+	pub fn build(self) -> Result<ServiceTask, Error> {
+		let kind = required(self.kind, "task kind")?;
+		let task = match kind {
+			ServiceTaskKind::GenerateServerKey => ServiceTask::GenerateServerKey(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "author")?,
+				required(self.threshold, "threshold")?,
+			),
+			ServiceTaskKind::RetrieveServerKey => ServiceTask::RetrieveServerKey(
+				required(self.key_id, "key_id")?,
+				self.requester,
+			),
+			ServiceTaskKind::GenerateDocumentKey => ServiceTask::GenerateDocumentKey(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "author")?,
+				required(self.threshold, "threshold")?,
+			),
+			ServiceTaskKind::StoreDocumentKey => ServiceTask::StoreDocumentKey(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "author")?,
+				non_zero(required(self.common_point, "common_point")?, "common_point")?,
+				non_zero(required(self.encrypted_point, "encrypted_point")?, "encrypted_point")?,
+			),
+			ServiceTaskKind::RetrieveDocumentKey => ServiceTask::RetrieveDocumentKey(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "requester")?,
+			),
+			ServiceTaskKind::RetrieveShadowDocumentKey => ServiceTask::RetrieveShadowDocumentKey(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "requester")?,
+			),
+			ServiceTaskKind::SchnorrSignMessage => ServiceTask::SchnorrSignMessage(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "requester")?,
+				non_zero(required(self.message, "message")?, "message")?,
+			),
+			ServiceTaskKind::EcdsaSignMessage => ServiceTask::EcdsaSignMessage(
+				required(self.key_id, "key_id")?,
+				required(self.requester, "requester")?,
+				non_zero(required(self.message, "message")?, "message")?,
+			),
+			ServiceTaskKind::ChangeServersSet => ServiceTask::ChangeServersSet(
+				required(self.old_set_signature, "old_set_signature")?,
+				required(self.new_set_signature, "new_set_signature")?,
+				required(self.new_set, "new_set")?,
+			),
+		};
+
+		task.validate()?;
+		Ok(task)
+	}
+}
+
+/// Compute hash of the servers set, that is signed by administrator when servers set is
+/// changed: `Keccak(public1 || public2 || ...)`, where publics are sorted in ascending order.
+pub fn servers_set_hash(set: &BTreeSet<KeyServerPublic>) -> H256 {
+	let mut keccak = Keccak::v256();
+	for server in set {
+		keccak.update(server.as_bytes());
+	}
+
+	let mut hash = [0u8; 32];
+	keccak.finalize(&mut hash);
+	hash.into()
+}
+
+/// Sign servers set with administrator secret. The result could be used as either
+/// `old_set_signature`, or `new_set_signature` of `ServiceTask::ChangeServersSet`.
+pub fn sign_servers_set(secret: &Secret, set: &BTreeSet<KeyServerPublic>) -> Result<Signature, Error> {
+	Ok(sign(secret, &servers_set_hash(set))?)
+}
+
+/// Verify that the servers set is signed by administrator with given public key.
+pub fn verify_servers_set_signature(
+	public: &Public,
+	set: &BTreeSet<KeyServerPublic>,
+	signature: &Signature,
+) -> Result<bool, Error> {
+	Ok(verify_public(public, signature, &servers_set_hash(set))?)
+}
+
+/// Return error if required service task field is not set.
+fn required<T>(value: Option<T>, name: &str) -> Result<T, Error> {
+	value.ok_or_else(|| Error::InvalidServiceTask(format!("{} is not set", name)))
+}
+
+/// Return error if service task field is zero.
+fn non_zero<T: Default + PartialEq>(value: T, name: &str) -> Result<T, Error> {
+	match value == T::default() {
+		false => Ok(value),
+		true => Err(Error::InvalidServiceTask(format!("{} is zero", name))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+	use futures::executor::block_on;
+	use parity_crypto::publickey::{Generator, KeyPair, Random};
+	use crate::{key_storage::{KeyShare, KeyStorage}, testing::MockKeyServer};
+	use super::*;
+
+	fn requester() -> KeyPair {
+		KeyPair::from_secret(Secret::from(H256::from_low_u64_be(1))).unwrap()
+	}
+
+	fn request(nonce: u64, deadline: u64) -> SignedServiceRequest {
+		let requester = requester();
+		SignedServiceRequest::new(
+			ServiceTask::SchnorrSignMessage(
+				ServerKeyId::from_low_u64_be(1),
+				Requester::Public(*requester.public()),
+				Default::default(),
+			),
+			H256::from_low_u64_be(nonce),
+			deadline,
+			requester.secret(),
+		).unwrap()
+	}
+
+	#[test]
+	fn service_tasks_are_executed() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		let task = ServiceTask::GenerateServerKey(key_id, requester.clone(), 0);
+		let server_key = match block_on(execute_task(&key_server, task)) {
+			Ok(ServiceResponse::ServerKeyGenerated(artifacts)) => artifacts.key,
+			_ => panic!("server key is generated"),
+		};
+
+		let task = ServiceTask::RetrieveServerKey(key_id, None);
+		match block_on(execute_task(&key_server, task)) {
+			Ok(ServiceResponse::ServerKeyRetrieved(artifacts)) => assert_eq!(artifacts.key, server_key),
+			_ => panic!("server key is retrieved"),
+		}
+
+		let task = ServiceTask::RetrieveDocumentKey(key_id, requester);
+		assert_eq!(block_on(execute_task(&key_server, task)).map(|_| ()), Err(Error::DocumentKeyIsNotFound));
+	}
+
+	#[test]
+	fn ecdsa_signing_task_with_too_high_threshold_is_rejected() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		block_on(execute_task(&key_server, ServiceTask::GenerateServerKey(key_id, requester.clone(), 0))).unwrap();
+		let key_share = key_server.key_storage().get(&key_id).unwrap().unwrap();
+		key_server.key_storage().update(key_id, KeyShare { threshold: 1, ..key_share }).unwrap();
+
+		let task = ServiceTask::EcdsaSignMessage(key_id, requester, Default::default());
+		assert_eq!(
+			block_on(execute_task(&key_server, task)).map(|_| ()),
+			Err(Error::EcdsaThresholdTooHigh { threshold: 1, node_count: 1 }),
+		);
+	}
+
+	#[test]
+	fn servers_set_signature_is_verified() {
+		let admin = Random.generate().unwrap();
+		let set: BTreeSet<_> = (1..=3).map(Public::from_low_u64_be).collect();
+		let other_set: BTreeSet<_> = (1..=2).map(Public::from_low_u64_be).collect();
+
+		let signature = sign_servers_set(admin.secret(), &set).unwrap();
+		assert_eq!(verify_servers_set_signature(admin.public(), &set, &signature), Ok(true));
+		assert_eq!(verify_servers_set_signature(admin.public(), &other_set, &signature), Ok(false));
+	}
+
+	#[test]
+	fn servers_set_hash_is_deterministic() {
+		let set: BTreeSet<_> = (1..=3).map(Public::from_low_u64_be).collect();
+		let reordered: BTreeSet<_> = vec![3, 1, 2].into_iter().map(Public::from_low_u64_be).collect();
+		assert_eq!(servers_set_hash(&set), servers_set_hash(&reordered));
+		assert_eq!(
+			servers_set_hash(&set),
+			H256::from_str("cd7e6a9524fb161ff25be2832dfd4e1066bd8441a564a0beb9592509e1eff377").unwrap(),
+		);
+	}
+
+	#[test]
+	fn fresh_request_is_accepted() {
+		let nonces = InMemoryNonceStorage::default();
+		assert_eq!(request(1, 100).verify(50, &nonces), Ok(()));
+		assert_eq!(request(2, 100).verify(100, &nonces), Ok(()));
+	}
+
+	#[test]
+	fn replayed_request_is_rejected() {
+		let nonces = InMemoryNonceStorage::default();
+		assert_eq!(request(1, 100).verify(50, &nonces), Ok(()));
+		assert_eq!(request(1, 100).verify(60, &nonces), Err(Error::ReplayedRequest));
+	}
+
+	#[test]
+	fn expired_request_is_rejected() {
+		let nonces = InMemoryNonceStorage::default();
+		assert_eq!(request(1, 100).verify(101, &nonces), Err(Error::RequestExpired));
+		assert_eq!(request(1, 200).verify(101, &nonces), Ok(()));
+	}
+
+	#[test]
+	fn request_with_tampered_nonce_or_deadline_is_rejected() {
+		let nonces = InMemoryNonceStorage::default();
+		let mut tampered_nonce = request(1, 100);
+		tampered_nonce.nonce = H256::from_low_u64_be(2);
+		assert_eq!(tampered_nonce.verify(50, &nonces), Err(Error::AccessDenied));
+
+		let mut tampered_deadline = request(1, 100);
+		tampered_deadline.deadline = 1_000;
+		assert_eq!(tampered_deadline.verify(500, &nonces), Err(Error::AccessDenied));
+
+		// rejected requests do not consume nonces
+		assert!(nonces.is_empty());
+		assert_eq!(request(1, 100).verify(50, &nonces), Ok(()));
+	}
+
+	#[test]
+	fn request_signed_by_other_key_is_rejected() {
+		let mut request = request(1, 100);
+		let other = Random.generate().unwrap();
+		request.signature = sign(other.secret(), &SignedServiceRequest::request_hash(
+			&request.task,
+			&request.nonce,
+			request.deadline,
+		)).unwrap();
+		assert_eq!(request.verify(50, &InMemoryNonceStorage::default()), Err(Error::AccessDenied));
+	}
+
+	#[test]
+	fn expired_nonces_are_pruned() {
+		let nonces = InMemoryNonceStorage::default();
+		assert_eq!(request(1, 100).verify(50, &nonces), Ok(()));
+		assert_eq!(request(2, 200).verify(60, &nonces), Ok(()));
+		assert_eq!(nonces.len(), 2);
+
+		assert_eq!(request(3, 300).verify(150, &nonces), Ok(()));
+		assert_eq!(nonces.len(), 2);
+	}
+
+	#[test]
+	fn store_document_key_task_is_built() {
+		let author = Requester::Public(Public::from_low_u64_be(1));
+		let task = ServiceTaskBuilder::new()
+			.store_document_key()
+			.key_id(ServerKeyId::from_low_u64_be(2))
+			.author(author.clone())
+			.common_point(Public::from_low_u64_be(3))
+			.encrypted_point(Public::from_low_u64_be(4))
+			.build();
+		assert_eq!(task, Ok(ServiceTask::StoreDocumentKey(
+			ServerKeyId::from_low_u64_be(2),
+			author,
+			Public::from_low_u64_be(3),
+			Public::from_low_u64_be(4),
+		)));
+	}
+
+	#[test]
+	fn service_task_with_missing_field_is_not_built() {
+		let task = ServiceTaskBuilder::new()
+			.store_document_key()
+			.key_id(ServerKeyId::from_low_u64_be(2))
+			.author(Requester::Public(Public::from_low_u64_be(1)))
+			.common_point(Public::from_low_u64_be(3))
+			.build();
+		assert_eq!(task, Err(Error::InvalidServiceTask("encrypted_point is not set".into())));
+
+		let task = ServiceTaskBuilder::new()
+			.store_document_key()
+			.key_id(ServerKeyId::from_low_u64_be(2))
+			.author(Requester::Public(Public::from_low_u64_be(1)))
+			.common_point(Public::from_low_u64_be(3))
+			.encrypted_point(Public::zero())
+			.build();
+		assert_eq!(task, Err(Error::InvalidServiceTask("encrypted_point is zero".into())));
+	}
+
+	#[test]
+	fn service_task_log_key_is_computed() {
+		let key_id = ServerKeyId::from_str("123456789abcdef0000000000000000000000000000000000000000000000000").unwrap();
+		let requester = Requester::Public(Public::from_low_u64_be(1));
+		assert_eq!(
+			ServiceTask::GenerateServerKey(key_id, requester.clone(), 1).log_key(),
+			"gen_sk:0x12345678\u{2026}",
+		);
+		assert_eq!(
+			ServiceTask::EcdsaSignMessage(key_id, requester, H256::from_low_u64_be(2)).log_key(),
+			"sign_ecdsa:0x12345678\u{2026}",
+		);
+		assert_eq!(
+			ServiceTask::ChangeServersSet(Default::default(), Default::default(), Default::default()).log_key(),
+			"change_servers_set",
+		);
+	}
+
+	#[test]
+	fn service_task_with_zero_key_id_is_invalid() {
+		let task = ServiceTask::RetrieveServerKey(ServerKeyId::zero(), None);
+		assert_eq!(task.validate(), Err(Error::InvalidServiceTask("key_id is invalid".into())));
+		let task = ServiceTask::RetrieveServerKey(ServerKeyId::from_low_u64_be(42), None);
+		assert_eq!(task.validate(), Ok(()));
+	}
+}
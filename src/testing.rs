@@ -0,0 +1,1571 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity Secret Store.
+
+// Parity Secret Store is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity Secret Store is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity Secret Store.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Utilities for testing code that is built on top of Secret Store primitives.
+
+use std::{
+	collections::{BTreeMap, BTreeSet, VecDeque},
+	sync::{Arc, atomic::{AtomicU64, Ordering}},
+	time::Duration,
+};
+use futures::{FutureExt, StreamExt, future::{BoxFuture, ready}, stream};
+use parking_lot::{Mutex, RwLock};
+use tiny_keccak::{Hasher, Keccak};
+use ethereum_types::{Address, H256};
+use parity_crypto::publickey::{
+	Generator, KeyPair, Public, Random, Secret, Signature, ec_math_utils, public_to_address, sign,
+};
+use crate::{
+	KeyServerId, KeyServerPublic, ServerKeyId,
+	acl_storage::{AclStorage, InMemoryPermissiveAclStorage},
+	clock::{Clock, SystemClock},
+	consensus::{SessionConfig, check_shares_quorum, wait_for_consensus, wait_for_establish},
+	error::Error,
+	key_server::{
+		ActiveSessionInfo, Origin, SessionId, SessionIntrospection, SessionKind, SessionResult,
+		SessionUpdate, SessionUpdates,
+		ServerKeyGenerator, ServerKeyGenerationArtifacts, ServerKeyGenerationParams,
+		ServerKeyGenerationResult, ServerKeyRetrievalArtifacts, ServerKeyRetrievalParams,
+		ServerKeyRetrievalResult, DocumentKeyServer, DocumentKeyStoreArtifacts, DocumentKeyStoreParams,
+		DocumentKeyStoreResult, DocumentKeyGenerationArtifacts, DocumentKeyGenerationParams,
+		DocumentKeyGenerationResult, EncryptedDocumentKeyGenerationArtifacts,
+		EncryptedDocumentKeyGenerationResult, DocumentKeyRetrievalArtifacts, DocumentKeyRetrievalParams,
+		DocumentKeyRetrievalResult, DocumentKeyCommonRetrievalArtifacts, DocumentKeyCommonRetrievalParams,
+		DocumentKeyCommonRetrievalResult, DocumentKeyCommonPartialRetrievalArtifacts,
+		DocumentKeyCommonPartialRetrievalResult, DocumentKeyShadowRetrievalArtifacts,
+		DocumentKeyShadowRetrievalParams, DocumentKeyShadowRetrievalResult, KeyOwnershipTransferArtifacts,
+		KeyOwnershipTransferParams, KeyOwnershipTransferResult, MessageSigner,
+		SchnorrSigningArtifacts, SchnorrSigningParams, SchnorrSigningResult, EcdsaSigningArtifacts,
+		EcdsaSigningParams, EcdsaSigningResult, SignatureScheme, SigningArtifacts, SigningParams,
+		SigningResult, AdminSessionsServer, KeyServer, KeyServerCapabilities,
+		ServersSetChangeArtifacts, ServersSetChangePlan, ServersSetChangeResult, check_ecdsa_threshold,
+		check_servers_set, schnorr_challenge,
+	},
+	key_server_set::MigrationId,
+	key_storage::{InMemoryKeyStorage, KeyShare, KeyShareVersion, KeyStorage},
+	requester::Requester,
+	service::verify_servers_set_signature,
+};
+
+/// Clock mock. Time only changes when it is explicitly set or advanced.
+#[derive(Debug, Default)]
+pub struct MockClock {
+	now: AtomicU64,
+}
+
+impl MockClock {
+	/// Create new clock that is showing given time (in seconds since Unix epoch).
+	pub fn new(now: u64) -> Self {
+		MockClock {
+			now: AtomicU64::new(now),
+		}
+	}
+
+	/// Set current time.
+	pub fn set(&self, now: u64) {
+		self.now.store(now, Ordering::SeqCst);
+	}
+
+	/// Advance current time.
+	pub fn advance(&self, duration: Duration) {
+		self.now.fetch_add(duration.as_secs(), Ordering::SeqCst);
+	}
+}
+
+impl Clock for MockClock {
+	fn now(&self) -> u64 {
+		self.now.load(Ordering::SeqCst)
+	}
+}
+
+/// Key server mock.
+///
+/// Operates as a single-node key server (i.e. only keys with zero threshold could be
+/// generated) that is storing keys in the in-memory key storage and is checking access
+/// rights using in-memory ACL storage. Every operation is registered as an active
+/// session when it is started and is unregistered when returned future is completed
+/// (or dropped). Servers set changes are only accepted if both current and new sets
+/// are signed by the administrator (see `with_admin_public`).
+///
+/// By default, requests for keys that the requester has no access to are rejected with
+/// `Error::AccessDenied`, no matter if the key exists or not. This hides existence of
+/// keys from unauthorized requesters, but makes it impossible for them to distinguish
+/// typos in key ids from ACL misconfiguration. Use `with_leak_existence(true)` to return
+/// `Error::ServerKeyIsNotFound` before checking access instead.
+pub struct MockKeyServer {
+	data: Arc<MockKeyServerData>,
+	sessions: Arc<RwLock<BTreeMap<SessionId, ActiveSessionInfo>>>,
+	next_session_id: AtomicU64,
+	capabilities: KeyServerCapabilities,
+	clock: Arc<dyn Clock>,
+	admin_public: Option<Public>,
+	injected_failures: Mutex<VecDeque<Error>>,
+	late_failures: Mutex<VecDeque<Error>>,
+	stalled_sessions: Mutex<VecDeque<SessionPhase>>,
+	session_config: Option<SessionConfig>,
+}
+
+/// Data, shared by all sessions of mock key server.
+struct MockKeyServerData {
+	self_key_pair: KeyPair,
+	self_id: KeyServerId,
+	key_storage: Arc<InMemoryKeyStorage>,
+	acl_storage: Arc<InMemoryPermissiveAclStorage>,
+	leak_existence: bool,
+}
+
+/// Active session registration. Session is unregistered when guard is dropped.
+struct SessionGuard {
+	id: SessionId,
+	sessions: Arc<RwLock<BTreeMap<SessionId, ActiveSessionInfo>>>,
+}
+
+impl MockKeyServer {
+	/// Create new mock key server.
+	pub fn new() -> Self {
+		MockKeyServer::with_leak_existence(false)
+	}
+
+	/// Create new mock key server, which reports that the key doesn't exist
+	/// before checking if requester has access to the key.
+	pub fn with_leak_existence(leak_existence: bool) -> Self {
+		let self_key_pair = Random.generate().expect("OS random generator never fails; qed");
+		MockKeyServer {
+			data: Arc::new(MockKeyServerData {
+				self_id: self_key_pair.address(),
+				self_key_pair,
+				key_storage: Arc::new(InMemoryKeyStorage::default()),
+				acl_storage: Arc::new(InMemoryPermissiveAclStorage::default()),
+				leak_existence,
+			}),
+			sessions: Arc::new(RwLock::new(BTreeMap::new())),
+			next_session_id: AtomicU64::new(1),
+			capabilities: Default::default(),
+			clock: Arc::new(SystemClock),
+			admin_public: None,
+			injected_failures: Mutex::new(VecDeque::new()),
+			late_failures: Mutex::new(VecDeque::new()),
+			stalled_sessions: Mutex::new(VecDeque::new()),
+			session_config: None,
+		}
+	}
+
+	/// Set capabilities that are advertised by this key server. Operations that
+	/// aren't advertised are still supported.
+	pub fn with_capabilities(mut self, capabilities: KeyServerCapabilities) -> Self {
+		self.capabilities = capabilities;
+		self
+	}
+
+	/// Set sessions configuration. Without configuration, stalled sessions are never
+	/// completed. With configuration, sessions are failing when phase timeout elapses,
+	/// so they must be polled from within tokio runtime.
+	pub fn with_session_config(mut self, session_config: SessionConfig) -> Self {
+		self.session_config = Some(session_config);
+		self
+	}
+
+	/// Set public key of the administrator, who is allowed to change servers set.
+	/// Without administrator, all servers set changes are rejected.
+	pub fn with_admin_public(mut self, admin_public: Public) -> Self {
+		self.admin_public = Some(admin_public);
+		self
+	}
+
+	/// Set clock that is used to timestamp started sessions.
+	pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+		self.clock = clock;
+		self
+	}
+
+	/// Get id of this key server.
+	pub fn self_id(&self) -> KeyServerId {
+		self.data.self_id
+	}
+
+	/// Get public key of this key server.
+	pub fn self_public(&self) -> KeyServerPublic {
+		*self.data.self_key_pair.public()
+	}
+
+	/// Get reference to key storage, used by this key server.
+	pub fn key_storage(&self) -> &Arc<InMemoryKeyStorage> {
+		&self.data.key_storage
+	}
+
+	/// Get reference to ACL storage, used by this key server.
+	pub fn acl_storage(&self) -> &Arc<InMemoryPermissiveAclStorage> {
+		&self.data.acl_storage
+	}
+
+	/// Make next sessions fail with given errors, one error per session.
+	pub fn fail_next_sessions(&self, errors: impl IntoIterator<Item = Error>) {
+		self.injected_failures.lock().extend(errors);
+	}
+
+	/// Make next sessions fail with given errors after they have taken effect, one
+	/// error per session.
+	pub fn fail_next_sessions_after_commit(&self, errors: impl IntoIterator<Item = Error>) {
+		self.late_failures.lock().extend(errors);
+	}
+
+	/// Make next sessions stall at given phases, one phase per session, as if other
+	/// key servers never respond.
+	pub fn stall_next_sessions(&self, phases: impl IntoIterator<Item = SessionPhase>) {
+		self.stalled_sessions.lock().extend(phases);
+	}
+
+	/// Get number of sessions that have been started by this key server.
+	pub fn started_sessions(&self) -> u64 {
+		self.next_session_id.load(Ordering::SeqCst) - 1
+	}
+
+	/// Register new active session.
+	fn start_session(&self, kind: SessionKind, key_id: ServerKeyId, requester: Option<Requester>) -> SessionGuard {
+		let id = H256::from_low_u64_be(self.next_session_id.fetch_add(1, Ordering::SeqCst));
+		let started_at = self.clock.now();
+		self.sessions.write().insert(id, ActiveSessionInfo {
+			session_id: id,
+			key_id,
+			kind,
+			started_at,
+			requester,
+		});
+
+		SessionGuard {
+			id,
+			sessions: self.sessions.clone(),
+		}
+	}
+
+	/// Check that both current and new servers sets are signed by administrator.
+	fn check_servers_set_signatures(
+		&self,
+		old_set_signature: &Signature,
+		new_set_signature: &Signature,
+		new_servers_set: &BTreeSet<KeyServerPublic>,
+	) -> Result<(), Error> {
+		let admin_public = self.admin_public.ok_or(Error::AccessDenied)?;
+		let current_servers_set = std::iter::once(self.self_public()).collect();
+		let is_signed = |set, signature| verify_servers_set_signature(&admin_public, set, signature).unwrap_or(false);
+		match is_signed(&current_servers_set, old_set_signature) && is_signed(new_servers_set, new_set_signature) {
+			true => Ok(()),
+			false => Err(Error::AccessDenied),
+		}
+	}
+
+	/// Start session that is completed when returned future is polled.
+	fn session<P, R>(
+		&self,
+		kind: SessionKind,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Option<Requester>,
+		params: P,
+		session: impl FnOnce(&MockKeyServerData) -> Result<R, Error> + Send + 'static,
+	) -> BoxFuture<'static, SessionResult<P, R>> where
+		P: Send + 'static,
+		R: Send + 'static,
+	{
+		let guard = self.start_session(kind, key_id, requester);
+		let injected_failure = self.injected_failures.lock().pop_front();
+		let late_failure = self.late_failures.lock().pop_front();
+		let stalled_at = self.stalled_sessions.lock().pop_front();
+		let session_config = self.session_config;
+		let data = self.data.clone();
+		Box::pin(async move {
+			let result = match injected_failure {
+				Some(error) => Err(error),
+				None => async {
+					session_phase(session_config, SessionPhase::Establish, stalled_at).await?;
+					session_phase(session_config, SessionPhase::Consensus, stalled_at).await?;
+					let result = session(&data)?;
+					match late_failure {
+						Some(error) => Err(error),
+						None => Ok(result),
+					}
+				}.await,
+			};
+			drop(guard);
+			SessionResult {
+				origin,
+				params,
+				result,
+			}
+		})
+	}
+}
+
+/// Phase of the mock key server session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+	/// Session is being established with other key servers.
+	Establish,
+	/// Consensus group is being formed.
+	Consensus,
+}
+
+/// Run phase of the mock session. The phase is never completed if the session is
+/// stalled at this phase, unless the phase timeout from session config elapses.
+async fn session_phase(
+	session_config: Option<SessionConfig>,
+	phase: SessionPhase,
+	stalled_at: Option<SessionPhase>,
+) -> Result<(), Error> {
+	let phase_future = async move {
+		if stalled_at == Some(phase) {
+			futures::future::pending::<()>().await;
+		}
+		Ok(())
+	};
+	match (session_config, phase) {
+		(None, _) => phase_future.await,
+		(Some(session_config), SessionPhase::Establish) => wait_for_establish(&session_config, phase_future).await,
+		(Some(session_config), SessionPhase::Consensus) => wait_for_consensus(&session_config, phase_future).await,
+	}
+}
+
+impl Default for MockKeyServer {
+	fn default() -> Self {
+		MockKeyServer::new()
+	}
+}
+
+impl Drop for SessionGuard {
+	fn drop(&mut self) {
+		self.sessions.write().remove(&self.id);
+	}
+}
+
+impl SessionIntrospection for MockKeyServer {
+	fn active_sessions(&self) -> Vec<ActiveSessionInfo> {
+		self.sessions.read().values().cloned().collect()
+	}
+}
+
+impl ServerKeyGenerator for MockKeyServer {
+	type GenerateKeyFuture = BoxFuture<'static, ServerKeyGenerationResult>;
+	type RestoreKeyFuture = BoxFuture<'static, ServerKeyRetrievalResult>;
+	type SupportsEcdsaFuture = BoxFuture<'static, Result<bool, Error>>;
+	type KeyAuthorFuture = BoxFuture<'static, Result<Option<Address>, Error>>;
+
+	fn generate_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateKeyFuture {
+		self.session(
+			SessionKind::ServerKeyGeneration,
+			origin,
+			key_id,
+			Some(author.clone()),
+			ServerKeyGenerationParams { key_id },
+			move |data| data.generate_key(key_id, &author, threshold),
+		)
+	}
+
+	fn generate_key_with_updates(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> SessionUpdates<ServerKeyGenerationArtifacts> {
+		single_node_session_updates(self.generate_key(origin, key_id, author, threshold))
+	}
+
+	fn restore_key_public(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Option<Requester>,
+	) -> Self::RestoreKeyFuture {
+		self.session(
+			SessionKind::ServerKeyRetrieval,
+			origin,
+			key_id,
+			author.clone(),
+			ServerKeyRetrievalParams { key_id },
+			move |data| {
+				let key_share = data.key_share(&key_id)?;
+				if let Some(author) = author {
+					data.check_author(&key_id, &key_share, &author)?;
+				}
+
+				Ok(ServerKeyRetrievalArtifacts {
+					author: key_share.author,
+					key: key_share.public,
+					threshold: key_share.threshold,
+				})
+			},
+		)
+	}
+
+	fn supports_ecdsa(&self, key_id: ServerKeyId, requester: Requester) -> Self::SupportsEcdsaFuture {
+		// mock is the only key server
+		let supports_ecdsa = self.data.accessible_key_share(&key_id, &requester)
+			.map(|key_share| check_ecdsa_threshold(key_share.threshold, 1).is_ok());
+		Box::pin(ready(supports_ecdsa))
+	}
+
+	fn key_author(&self, key_id: ServerKeyId, requester: Requester) -> Self::KeyAuthorFuture {
+		let author = match self.data.accessible_key_share(&key_id, &requester) {
+			Ok(key_share) => Ok(Some(key_share.author)),
+			Err(Error::ServerKeyIsNotFound) => Ok(None),
+			Err(error) => Err(error),
+		};
+		Box::pin(ready(author))
+	}
+}
+
+impl DocumentKeyServer for MockKeyServer {
+	type StoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyStoreResult>;
+	type GenerateDocumentKeyFuture = BoxFuture<'static, DocumentKeyGenerationResult>;
+	type GenerateDocumentKeyEncryptedFuture = BoxFuture<'static, EncryptedDocumentKeyGenerationResult>;
+	type RestoreDocumentKeyFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonFuture = BoxFuture<'static, DocumentKeyCommonRetrievalResult>;
+	type RestoreDocumentKeyShadowFuture = BoxFuture<'static, DocumentKeyShadowRetrievalResult>;
+	type RestoreDocumentKeyAtVersionFuture = BoxFuture<'static, DocumentKeyRetrievalResult>;
+	type RestoreDocumentKeyCommonManyFuture = BoxFuture<'static, Vec<DocumentKeyCommonRetrievalResult>>;
+	type RestoreDocumentKeyCommonPartialFuture = BoxFuture<'static, DocumentKeyCommonPartialRetrievalResult>;
+	type TransferKeyOwnershipFuture = BoxFuture<'static, KeyOwnershipTransferResult>;
+
+	fn store_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		common_point: Public,
+		encrypted_document_key: Public,
+	) -> Self::StoreDocumentKeyFuture {
+		self.session(
+			SessionKind::DocumentKeyStore,
+			origin,
+			key_id,
+			Some(author.clone()),
+			DocumentKeyStoreParams { key_id },
+			move |data| data.store_document_key(key_id, &author, common_point, encrypted_document_key)
+				.map(|_| DocumentKeyStoreArtifacts),
+		)
+	}
+
+	fn generate_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyFuture {
+		self.session(
+			SessionKind::DocumentKeyGeneration,
+			origin,
+			key_id,
+			Some(author.clone()),
+			DocumentKeyGenerationParams { key_id },
+			move |data| data.generate_document_key(key_id, &author, threshold)
+				.map(|(document_key, _)| DocumentKeyGenerationArtifacts { document_key }),
+		)
+	}
+
+	fn generate_document_key_encrypted(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		author: Requester,
+		threshold: usize,
+	) -> Self::GenerateDocumentKeyEncryptedFuture {
+		self.session(
+			SessionKind::DocumentKeyGeneration,
+			origin,
+			key_id,
+			Some(author.clone()),
+			DocumentKeyGenerationParams { key_id },
+			move |data| {
+				let author_public = author.public(&key_id)?;
+				let (document_key, common_point) = data.generate_document_key(key_id, &author, threshold)?;
+				Ok(EncryptedDocumentKeyGenerationArtifacts {
+					encrypted_document_key: crate::encrypt_for_requester(&author_public, document_key.as_bytes())?.into(),
+					common_point,
+				})
+			},
+		)
+	}
+
+	fn restore_document_key(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyFuture {
+		self.session(
+			SessionKind::DocumentKeyRetrieval,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			DocumentKeyRetrievalParams { key_id, requester: requester.clone() },
+			move |data| data.restore_document_key(&key_id, &requester, None),
+		)
+	}
+
+	fn restore_document_key_at_version(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		version: H256,
+	) -> Self::RestoreDocumentKeyAtVersionFuture {
+		self.session(
+			SessionKind::DocumentKeyRetrieval,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			DocumentKeyRetrievalParams { key_id, requester: requester.clone() },
+			move |data| data.restore_document_key(&key_id, &requester, Some(&version)),
+		)
+	}
+
+	fn restore_document_key_common(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonFuture {
+		self.session(
+			SessionKind::DocumentKeyCommonRetrieval,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			DocumentKeyCommonRetrievalParams { key_id, requester: requester.clone() },
+			move |data| data.restore_document_key_common(&key_id, &requester),
+		)
+	}
+
+	fn restore_document_key_common_many(
+		&self,
+		origin: Option<Origin>,
+		key_ids: &[ServerKeyId],
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonManyFuture {
+		let sessions = key_ids.iter()
+			.map(|key_id| self.restore_document_key_common(origin, *key_id, requester.clone()))
+			.collect::<Vec<_>>();
+		Box::pin(futures::future::join_all(sessions))
+	}
+
+	fn restore_document_key_common_partial(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyCommonPartialFuture {
+		self.session(
+			SessionKind::DocumentKeyCommonRetrieval,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			DocumentKeyCommonRetrievalParams { key_id, requester: requester.clone() },
+			move |data| data.restore_document_key_common_partial(&key_id, &requester),
+		)
+	}
+
+	fn restore_document_key_shadow(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+	) -> Self::RestoreDocumentKeyShadowFuture {
+		self.session(
+			SessionKind::DocumentKeyShadowRetrieval,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			DocumentKeyShadowRetrievalParams { key_id, requester: requester.clone() },
+			move |data| {
+				let (key_share, common_point, encrypted_point) = data.document_key_share(&key_id, &requester)?;
+				let document_key = decrypt_document_key(
+					&key_share.last_version()?.secret_share,
+					&common_point,
+					&encrypted_point,
+				)?;
+
+				let requester_public = requester.public(&key_id)?;
+				let shadow = Random.generate()?.secret().clone();
+				let mut artifacts = make_shadow_artifacts(
+					&document_key,
+					&common_point,
+					&[(data.self_id, shadow)],
+					&requester_public,
+				)?;
+				artifacts.threshold = key_share.threshold;
+				Ok(artifacts)
+			},
+		)
+	}
+
+	fn transfer_key_ownership(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		current_author: Requester,
+		new_author: Address,
+	) -> Self::TransferKeyOwnershipFuture {
+		self.session(
+			SessionKind::KeyOwnershipTransfer,
+			origin,
+			key_id,
+			Some(current_author.clone()),
+			KeyOwnershipTransferParams { key_id, new_author },
+			move |data| data.transfer_key_ownership(key_id, &current_author, new_author)
+				.map(|_| KeyOwnershipTransferArtifacts),
+		)
+	}
+}
+
+impl MessageSigner for MockKeyServer {
+	type SignMessageSchnorrFuture = BoxFuture<'static, SchnorrSigningResult>;
+	type SignMessageEcdsaFuture = BoxFuture<'static, EcdsaSigningResult>;
+	type SignMessageFuture = BoxFuture<'static, SigningResult>;
+
+	fn sign_message_schnorr(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageSchnorrFuture {
+		self.session(
+			SessionKind::SchnorrSigning,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			SchnorrSigningParams { key_id, requester: requester.clone() },
+			move |data| data.sign_message_schnorr(&key_id, &requester, message),
+		)
+	}
+
+	fn sign_message_ecdsa(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+	) -> Self::SignMessageEcdsaFuture {
+		self.session(
+			SessionKind::EcdsaSigning,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			EcdsaSigningParams { key_id, requester: requester.clone() },
+			move |data| data.sign_message_ecdsa(&key_id, &requester, message),
+		)
+	}
+
+	fn sign_message(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> Self::SignMessageFuture {
+		let kind = match scheme {
+			SignatureScheme::Schnorr => SessionKind::SchnorrSigning,
+			SignatureScheme::Ecdsa => SessionKind::EcdsaSigning,
+		};
+		self.session(
+			kind,
+			origin,
+			key_id,
+			Some(requester.clone()),
+			SigningParams { key_id, requester: requester.clone(), scheme },
+			move |data| match scheme {
+				SignatureScheme::Schnorr => data.sign_message_schnorr(&key_id, &requester, message)
+					.map(SigningArtifacts::Schnorr),
+				SignatureScheme::Ecdsa => data.sign_message_ecdsa(&key_id, &requester, message)
+					.map(SigningArtifacts::Ecdsa),
+			},
+		)
+	}
+
+	fn sign_message_with_updates(
+		&self,
+		origin: Option<Origin>,
+		key_id: ServerKeyId,
+		requester: Requester,
+		message: H256,
+		scheme: SignatureScheme,
+	) -> SessionUpdates<SigningArtifacts> {
+		single_node_session_updates(self.sign_message(origin, key_id, requester, message, scheme))
+	}
+}
+
+impl AdminSessionsServer for MockKeyServer {
+	type ChangeServersSetFuture = BoxFuture<'static, ServersSetChangeResult>;
+	type PlanServersSetChangeFuture = BoxFuture<'static, Result<ServersSetChangePlan, Error>>;
+
+	fn change_servers_set(
+		&self,
+		origin: Option<Origin>,
+		old_set_signature: Signature,
+		new_set_signature: Signature,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::ChangeServersSetFuture {
+		let check_result = self.check_servers_set_signatures(&old_set_signature, &new_set_signature, &new_servers_set)
+			.and_then(|_| check_servers_set(&new_servers_set, &*self.data.key_storage));
+		if let Err(error) = check_result {
+			return Box::pin(ready(SessionResult {
+				origin,
+				params: (),
+				result: Err(error),
+			}));
+		}
+
+		// there are no other nodes, so there's nothing to reshare
+		self.session(
+			SessionKind::ServersSetChange,
+			origin,
+			Default::default(),
+			None,
+			(),
+			move |_| Ok(ServersSetChangeArtifacts {
+				migration_id: migration_id(&new_servers_set),
+				new_set: new_servers_set,
+				keys_resharded: 0,
+			}),
+		)
+	}
+
+	fn plan_servers_set_change(
+		&self,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> Self::PlanServersSetChangeFuture {
+		let current_set = vec![self.data.self_id].into_iter().collect();
+		let new_set = new_servers_set.iter().map(public_to_address).collect();
+		Box::pin(ready(ServersSetChangePlan::new(&current_set, &new_set, &*self.data.key_storage)))
+	}
+}
+
+impl KeyServer for MockKeyServer {
+	fn capabilities(&self) -> KeyServerCapabilities {
+		self.capabilities
+	}
+}
+
+impl MockKeyServerData {
+	/// Read key share from the storage.
+	fn key_share(&self, key_id: &ServerKeyId) -> Result<KeyShare, Error> {
+		self.key_storage.get(key_id)?.ok_or(Error::ServerKeyIsNotFound)
+	}
+
+	/// Check that the requester is the author of the key.
+	fn check_author(&self, key_id: &ServerKeyId, key_share: &KeyShare, requester: &Requester) -> Result<(), Error> {
+		match requester.address(key_id)? == key_share.author {
+			true => Ok(()),
+			false => Err(Error::AccessDenied),
+		}
+	}
+
+	/// Check that the requester has access to the key.
+	fn check_access(&self, key_id: &ServerKeyId, requester: &Requester) -> Result<(), Error> {
+		match self.acl_storage.check(requester.address(key_id)?, key_id)? {
+			true => Ok(()),
+			false => Err(Error::AccessDenied),
+		}
+	}
+
+	/// Read key share from the storage, checking that the requester has access to it.
+	fn accessible_key_share(&self, key_id: &ServerKeyId, requester: &Requester) -> Result<KeyShare, Error> {
+		if self.leak_existence {
+			let key_share = self.key_share(key_id)?;
+			self.check_access(key_id, requester)?;
+			Ok(key_share)
+		} else {
+			self.check_access(key_id, requester)?;
+			self.key_share(key_id)
+		}
+	}
+
+	/// Read key share with document key from the storage, checking that the requester has access to it.
+	fn document_key_share(
+		&self,
+		key_id: &ServerKeyId,
+		requester: &Requester,
+	) -> Result<(KeyShare, Public, Public), Error> {
+		let key_share = self.accessible_key_share(key_id, requester)?;
+		match (key_share.common_point, key_share.encrypted_point) {
+			(Some(common_point), Some(encrypted_point)) => Ok((key_share, common_point, encrypted_point)),
+			_ => Err(Error::DocumentKeyIsNotFound),
+		}
+	}
+
+	/// Generate new server key and store it in the key storage.
+	fn generate_key(
+		&self,
+		key_id: ServerKeyId,
+		author: &Requester,
+		threshold: usize,
+	) -> Result<ServerKeyGenerationArtifacts, Error> {
+		if threshold != 0 {
+			return Err(Error::NotEnoughNodesForThreshold);
+		}
+		if self.key_storage.contains(&key_id) {
+			return Err(Error::ServerKeyAlreadyGenerated);
+		}
+
+		let key_pair = Random.generate()?;
+		let mut id_numbers = BTreeMap::new();
+		id_numbers.insert(self.self_id, Secret::from(H256::from_low_u64_be(1)));
+		let version = KeyShareVersion::new(id_numbers, key_pair.secret().clone());
+		let artifacts = ServerKeyGenerationArtifacts {
+			key: *key_pair.public(),
+			participants: version.id_numbers.keys().cloned().collect(),
+			version: version.hash,
+		};
+		self.key_storage.insert(key_id, KeyShare {
+			author: author.address(&key_id)?,
+			threshold,
+			public: *key_pair.public(),
+			common_point: None,
+			encrypted_point: None,
+			versions: vec![version],
+		})?;
+
+		Ok(artifacts)
+	}
+
+	/// Generate new server key and document key and store them in the key storage.
+	/// Returns document key and its common point.
+	fn generate_document_key(
+		&self,
+		key_id: ServerKeyId,
+		author: &Requester,
+		threshold: usize,
+	) -> Result<(Public, Public), Error> {
+		let server_key = self.generate_key(key_id, author, threshold)?.key;
+		let document_key = *Random.generate()?.public();
+		let (common_point, encrypted_document_key) = crate::encrypt_document_key(&server_key, &document_key)?;
+		self.store_document_key(key_id, author, common_point, encrypted_document_key)?;
+		Ok((document_key, common_point))
+	}
+
+	/// Store document key in the key storage.
+	fn store_document_key(
+		&self,
+		key_id: ServerKeyId,
+		author: &Requester,
+		common_point: Public,
+		encrypted_point: Public,
+	) -> Result<(), Error> {
+		let mut key_share = self.key_share(&key_id)?;
+		self.check_author(&key_id, &key_share, author)?;
+		if key_share.common_point.is_some() || key_share.encrypted_point.is_some() {
+			return Err(Error::DocumentKeyAlreadyStored);
+		}
+
+		key_share.common_point = Some(common_point);
+		key_share.encrypted_point = Some(encrypted_point);
+		self.key_storage.update(key_id, key_share)
+	}
+
+	/// Restore document key using given (or the latest) key version.
+	fn restore_document_key(
+		&self,
+		key_id: &ServerKeyId,
+		requester: &Requester,
+		version: Option<&H256>,
+	) -> Result<DocumentKeyRetrievalArtifacts, Error> {
+		let (key_share, common_point, encrypted_point) = self.document_key_share(key_id, requester)?;
+		// mock is the only node that is responding
+		check_shares_quorum(1, key_share.threshold)?;
+		let version = match version {
+			Some(version) => key_share.version(version)?,
+			None => key_share.last_version()?,
+		};
+		let document_key = decrypt_document_key(&version.secret_share, &common_point, &encrypted_point)?;
+		Ok(DocumentKeyRetrievalArtifacts {
+			encrypted_document_key: crate::encrypt_for_requester(&requester.public(key_id)?, document_key.as_bytes())?.into(),
+		})
+	}
+
+	/// Compute Schnorr signature of the message.
+	fn sign_message_schnorr(
+		&self,
+		key_id: &ServerKeyId,
+		requester: &Requester,
+		message: H256,
+	) -> Result<SchnorrSigningArtifacts, Error> {
+		let key_share = self.accessible_key_share(key_id, requester)?;
+		let secret = &key_share.last_version()?.secret_share;
+
+		// c = H(k * G || message), s = k - c * x
+		let nonce = Random.generate()?;
+		let signature_c = schnorr_challenge(nonce.public(), &message);
+		let mut challenge_mul_secret = signature_c.clone();
+		challenge_mul_secret.mul(secret)?;
+		let mut signature_s = nonce.secret().clone();
+		signature_s.sub(&challenge_mul_secret)?;
+
+		Ok(SchnorrSigningArtifacts {
+			signature_c: *signature_c,
+			signature_s: *signature_s,
+		})
+	}
+
+	/// Compute ECDSA signature of the message.
+	fn sign_message_ecdsa(
+		&self,
+		key_id: &ServerKeyId,
+		requester: &Requester,
+		message: H256,
+	) -> Result<EcdsaSigningArtifacts, Error> {
+		let key_share = self.accessible_key_share(key_id, requester)?;
+		check_ecdsa_threshold(key_share.threshold, 1)?;
+		let signature = sign(&key_share.last_version()?.secret_share, &message)?;
+		Ok(EcdsaSigningArtifacts { signature })
+	}
+
+	/// Restore common portion of the document key.
+	fn restore_document_key_common(
+		&self,
+		key_id: &ServerKeyId,
+		requester: &Requester,
+	) -> Result<DocumentKeyCommonRetrievalArtifacts, Error> {
+		let (key_share, common_point, _) = self.document_key_share(key_id, requester)?;
+		Ok(DocumentKeyCommonRetrievalArtifacts {
+			common_point,
+			threshold: key_share.threshold,
+		})
+	}
+
+	/// Restore common portion of the document key, even if the document key isn't stored yet.
+	/// Mock is the only key server, so the common point is confirmed by at most one node.
+	fn restore_document_key_common_partial(
+		&self,
+		key_id: &ServerKeyId,
+		requester: &Requester,
+	) -> Result<DocumentKeyCommonPartialRetrievalArtifacts, Error> {
+		let key_share = self.accessible_key_share(key_id, requester)?;
+		Ok(DocumentKeyCommonPartialRetrievalArtifacts {
+			threshold: key_share.threshold,
+			common_point: key_share.common_point,
+			confirmations: if key_share.common_point.is_some() { 1 } else { 0 },
+		})
+	}
+
+	/// Transfer ownership of the key to the new author.
+	fn transfer_key_ownership(
+		&self,
+		key_id: ServerKeyId,
+		current_author: &Requester,
+		new_author: Address,
+	) -> Result<(), Error> {
+		let mut key_share = self.key_share(&key_id)?;
+		if let Err(error) = self.check_author(&key_id, &key_share, current_author) {
+			log::warn!(
+				target: "secretstore",
+				"Rejected ownership transfer of key {:?} from {:?}: {}",
+				key_id,
+				current_author.address(&key_id).ok(),
+				error,
+			);
+			return Err(error);
+		}
+
+		let previous_author = key_share.author;
+		key_share.author = new_author;
+		self.key_storage.update(key_id, key_share)?;
+		log::info!(
+			target: "secretstore",
+			"Transferred ownership of key {:?} from {:?} to {:?}",
+			key_id,
+			previous_author,
+			new_author,
+		);
+		Ok(())
+	}
+}
+
+/// Make valid document key shadow retrieval artifacts.
+///
+/// Every participant is represented by its id and its shadow coefficient. Partially
+/// decrypted document key is `M - sum(shadows) * common_point`, so that requester
+/// could finally decrypt it by adding `sum(shadows) * common_point`.
+pub fn make_shadow_artifacts(
+	document_key: &Public,
+	common_point: &Public,
+	participants: &[(KeyServerId, Secret)],
+	requester_public: &Public,
+) -> Result<DocumentKeyShadowRetrievalArtifacts, Error> {
+	let mut encrypted_document_key = *document_key;
+	let mut participants_coefficients = BTreeMap::new();
+	for (participant, shadow) in participants {
+		let mut shadow_point = *common_point;
+		ec_math_utils::public_mul_secret(&mut shadow_point, shadow)?;
+		ec_math_utils::public_sub(&mut encrypted_document_key, &shadow_point)?;
+		participants_coefficients.insert(*participant, crate::encrypt_for_requester(requester_public, shadow.as_bytes())?);
+	}
+
+	Ok(DocumentKeyShadowRetrievalArtifacts {
+		common_point: *common_point,
+		threshold: participants.len().saturating_sub(1),
+		encrypted_document_key,
+		participants_coefficients,
+	})
+}
+
+/// Convert mock session future into the stream of session updates. Mock is the only
+/// node, so it is confirming session by itself.
+fn single_node_session_updates<P: Send + 'static, R: Send + 'static>(
+	future: BoxFuture<'static, SessionResult<P, R>>,
+) -> SessionUpdates<R> {
+	Box::pin(stream::iter(vec![
+		SessionUpdate::Initializing,
+		SessionUpdate::AwaitingConfirmations(1, 1),
+		SessionUpdate::Finalizing,
+	]).chain(stream::once(future.map(Into::into))))
+}
+
+/// Decrypt document key with server key secret: `M = encrypted_point - y * common_point`.
+fn decrypt_document_key(server_secret: &Secret, common_point: &Public, encrypted_point: &Public) -> Result<Public, Error> {
+	let mut decryption_point = *common_point;
+	ec_math_utils::public_mul_secret(&mut decryption_point, server_secret)?;
+	let mut document_key = *encrypted_point;
+	ec_math_utils::public_sub(&mut document_key, &decryption_point)?;
+	Ok(document_key)
+}
+
+/// Compute migration id as Keccak of the new servers set.
+fn migration_id(new_servers_set: &BTreeSet<KeyServerPublic>) -> MigrationId {
+	let mut keccak = Keccak::v256();
+	for server in new_servers_set {
+		keccak.update(server.as_bytes());
+	}
+
+	let mut migration_id = [0u8; 32];
+	keccak.finalize(&mut migration_id);
+	migration_id.into()
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::executor::block_on;
+	use crate::{cancellation::CancellationToken, key_server::{verify_ecdsa, verify_schnorr}, service::sign_servers_set};
+	use super::*;
+
+	#[test]
+	fn started_session_is_listed_until_completed() {
+		let key_server = MockKeyServer::new().with_clock(Arc::new(MockClock::new(100)));
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		let future = key_server.generate_key(None, key_id, requester.clone(), 0);
+		let active_sessions = key_server.active_sessions();
+		assert_eq!(active_sessions.len(), 1);
+		assert_eq!(active_sessions[0].key_id, key_id);
+		assert_eq!(active_sessions[0].kind, SessionKind::ServerKeyGeneration);
+		assert_eq!(active_sessions[0].requester, Some(requester));
+		assert_eq!(active_sessions[0].started_at, 100);
+
+		assert!(block_on(future).result.is_ok());
+		assert!(key_server.active_sessions().is_empty());
+	}
+
+	#[test]
+	fn session_updates_are_streamed() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		let updates = block_on(key_server.generate_key_with_updates(None, key_id, requester.clone(), 0)
+			.collect::<Vec<_>>());
+		assert_eq!(updates.len(), 4);
+		assert!(matches!(updates[0], SessionUpdate::Initializing));
+		assert!(matches!(updates[1], SessionUpdate::AwaitingConfirmations(1, 1)));
+		assert!(matches!(updates[2], SessionUpdate::Finalizing));
+		let key = match updates[3] {
+			SessionUpdate::Completed(ref artifacts) => artifacts.key,
+			_ => panic!("expected completed session"),
+		};
+
+		let updates = block_on(key_server.sign_message_with_updates(
+			None,
+			ServerKeyId::from_low_u64_be(43),
+			requester,
+			H256::from_low_u64_be(1),
+			SignatureScheme::Schnorr,
+		).collect::<Vec<_>>());
+		assert!(matches!(updates.last(), Some(SessionUpdate::Failed(Error::ServerKeyIsNotFound))));
+		assert_ne!(key, Public::zero());
+	}
+
+	#[test]
+	fn key_ownership_is_transferred_by_author_only() {
+		let key_server = MockKeyServer::new();
+		let author = Requester::Public(*Random.generate().unwrap().public());
+		let new_author_key_pair = Random.generate().unwrap();
+		let new_author = Requester::Public(*new_author_key_pair.public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		block_on(key_server.generate_key(None, key_id, author.clone(), 0)).result.unwrap();
+
+		let result = block_on(key_server.transfer_key_ownership(None, key_id, new_author.clone(), Address::zero())).result;
+		assert!(matches!(result, Err(Error::AccessDenied)));
+
+		block_on(key_server.transfer_key_ownership(None, key_id, author.clone(), new_author_key_pair.address()))
+			.result.unwrap();
+		assert_eq!(key_server.key_storage().get(&key_id).unwrap().unwrap().author, new_author_key_pair.address());
+		assert!(block_on(key_server.restore_key_public(None, key_id, Some(author))).result.is_err());
+		assert!(block_on(key_server.restore_key_public(None, key_id, Some(new_author))).result.is_ok());
+	}
+
+	#[test]
+	fn ecdsa_support_is_checked() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let supported_key_id = ServerKeyId::from_low_u64_be(1);
+		let unsupported_key_id = ServerKeyId::from_low_u64_be(2);
+		block_on(key_server.generate_key(None, supported_key_id, requester.clone(), 0)).result.unwrap();
+		let key_share = key_server.key_storage().get(&supported_key_id).unwrap().unwrap();
+		key_server.key_storage().insert(unsupported_key_id, KeyShare { threshold: 1, ..key_share }).unwrap();
+
+		assert_eq!(block_on(key_server.supports_ecdsa(supported_key_id, requester.clone())), Ok(true));
+		assert_eq!(block_on(key_server.supports_ecdsa(unsupported_key_id, requester.clone())), Ok(false));
+		assert_eq!(
+			block_on(key_server.supports_ecdsa(ServerKeyId::from_low_u64_be(3), requester.clone())),
+			Err(Error::ServerKeyIsNotFound),
+		);
+
+		key_server.acl_storage().forbid(requester.address(&supported_key_id).unwrap(), supported_key_id);
+		assert_eq!(block_on(key_server.supports_ecdsa(supported_key_id, requester)), Err(Error::AccessDenied));
+	}
+
+	#[test]
+	fn document_key_is_not_restored_without_enough_shares() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		block_on(key_server.generate_document_key(None, key_id, requester.clone(), 0)).result.unwrap();
+		let key_share = key_server.key_storage().get(&key_id).unwrap().unwrap();
+		key_server.key_storage().update(key_id, KeyShare { threshold: 1, ..key_share }).unwrap();
+
+		assert_eq!(
+			block_on(key_server.restore_document_key(None, key_id, requester)).result.map(|_| ()),
+			Err(Error::NotEnoughShares { have: 1, need: 2 }),
+		);
+	}
+
+	#[test]
+	fn key_author_is_read_without_restoring_key() {
+		let key_server = MockKeyServer::new();
+		let author = Random.generate().unwrap();
+		let requester = Requester::Public(*author.public());
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap();
+
+		assert_eq!(block_on(key_server.key_author(key_id, requester.clone())), Ok(Some(author.address())));
+		assert_eq!(block_on(key_server.key_author(ServerKeyId::from_low_u64_be(2), requester.clone())), Ok(None));
+
+		key_server.acl_storage().forbid(author.address(), key_id);
+		assert_eq!(block_on(key_server.key_author(key_id, requester)), Err(Error::AccessDenied));
+	}
+
+	#[test]
+	fn generation_artifacts_carry_participants_and_version() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		let artifacts = block_on(key_server.generate_key(None, key_id, requester, 0)).result.unwrap();
+		assert_eq!(artifacts.participants, vec![key_server.self_id()].into_iter().collect());
+		let key_share = key_server.key_storage().get(&key_id).unwrap().unwrap();
+		assert_eq!(artifacts.version, key_share.last_version().unwrap().hash);
+	}
+
+	#[test]
+	fn generated_document_key_is_restored() {
+		let key_server = MockKeyServer::new();
+		let requester_key_pair = Random.generate().unwrap();
+		let requester = Requester::Public(*requester_key_pair.public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		let generated = block_on(key_server.generate_document_key(None, key_id, requester.clone(), 0))
+			.result.unwrap();
+		let restored = block_on(key_server.restore_document_key(None, key_id, requester))
+			.result.unwrap();
+		assert_eq!(
+			restored.encrypted_document_key.decrypt(requester_key_pair.secret()),
+			Ok(generated.document_key),
+		);
+	}
+
+	#[test]
+	fn cancelled_document_key_retrieval_is_aborted() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		block_on(key_server.generate_document_key(None, key_id, requester.clone(), 0)).result.unwrap();
+
+		let token = CancellationToken::new();
+		let future = key_server.restore_document_key_cancellable(None, key_id, requester.clone(), token.clone());
+		assert_eq!(key_server.active_sessions().len(), 1);
+		token.cancel();
+		assert!(matches!(block_on(future).result, Err(Error::Cancelled)));
+		assert!(key_server.active_sessions().is_empty());
+
+		let future = key_server.restore_document_key_cancellable(None, key_id, requester, CancellationToken::new());
+		assert!(block_on(future).result.is_ok());
+	}
+
+	#[test]
+	fn document_key_common_is_restored_for_many_keys() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id1 = ServerKeyId::from_low_u64_be(1);
+		let key_id2 = ServerKeyId::from_low_u64_be(2);
+		let key_id3 = ServerKeyId::from_low_u64_be(3);
+		block_on(key_server.generate_document_key(None, key_id1, requester.clone(), 0)).result.unwrap();
+		block_on(key_server.generate_document_key(None, key_id3, requester.clone(), 0)).result.unwrap();
+
+		let results = block_on(key_server.restore_document_key_common_many(
+			None,
+			&[key_id1, key_id2, key_id3],
+			requester,
+		));
+		assert_eq!(results.len(), 3);
+		assert_eq!(results[0].params.key_id, key_id1);
+		assert_eq!(results[0].result.as_ref().map(|artifacts| artifacts.threshold), Ok(0));
+		assert_eq!(results[1].params.key_id, key_id2);
+		assert_eq!(results[1].result.as_ref().map(|_| ()), Err(&Error::ServerKeyIsNotFound));
+		assert_eq!(results[2].params.key_id, key_id3);
+		assert!(results[2].result.is_ok());
+	}
+
+	#[test]
+	fn document_key_common_is_partially_restored() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap();
+
+		assert_eq!(
+			block_on(key_server.restore_document_key_common(None, key_id, requester.clone())).result.map(|_| ()),
+			Err(Error::DocumentKeyIsNotFound),
+		);
+		let partial = block_on(key_server.restore_document_key_common_partial(None, key_id, requester))
+			.result.unwrap();
+		assert_eq!(partial.threshold, 0);
+		assert_eq!(partial.common_point, None);
+		assert_eq!(partial.confirmations, 0);
+	}
+
+	#[test]
+	fn servers_set_change_is_planned() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		block_on(key_server.generate_key(None, key_id, requester, 0)).result.unwrap();
+
+		let new_node = *Random.generate().unwrap().public();
+		let plan = block_on(key_server.plan_servers_set_change(
+			vec![key_server.self_public(), new_node].into_iter().collect(),
+		)).unwrap();
+		assert_eq!(plan, ServersSetChangePlan {
+			joining: vec![public_to_address(&new_node)].into_iter().collect(),
+			leaving: BTreeSet::new(),
+			staying: vec![key_server.self_id()].into_iter().collect(),
+			keys_to_reshare: vec![key_id].into_iter().collect(),
+		});
+
+		let plan = block_on(key_server.plan_servers_set_change(
+			vec![key_server.self_public()].into_iter().collect(),
+		)).unwrap();
+		assert!(plan.joining.is_empty());
+		assert!(plan.keys_to_reshare.is_empty());
+	}
+
+	fn admin_key_server() -> (MockKeyServer, KeyPair) {
+		let admin = Random.generate().unwrap();
+		(MockKeyServer::new().with_admin_public(*admin.public()), admin)
+	}
+
+	fn change_servers_set(
+		key_server: &MockKeyServer,
+		admin: &KeyPair,
+		new_servers_set: BTreeSet<KeyServerPublic>,
+	) -> ServersSetChangeResult {
+		let current_servers_set = std::iter::once(key_server.self_public()).collect();
+		block_on(key_server.change_servers_set(
+			None,
+			sign_servers_set(admin.secret(), &current_servers_set).unwrap(),
+			sign_servers_set(admin.secret(), &new_servers_set).unwrap(),
+			new_servers_set,
+		))
+	}
+
+	#[test]
+	fn servers_set_change_is_rejected_unless_signed_by_admin() {
+		let (key_server, admin) = admin_key_server();
+		let new_servers_set: BTreeSet<_> = vec![key_server.self_public(), *Random.generate().unwrap().public()]
+			.into_iter()
+			.collect();
+		let other = Random.generate().unwrap();
+		let current_servers_set = std::iter::once(key_server.self_public()).collect();
+		let change = |old_set_signer: &KeyPair, new_set_signer: &KeyPair, new_servers_set: &BTreeSet<_>| block_on(
+			key_server.change_servers_set(
+				None,
+				sign_servers_set(old_set_signer.secret(), &current_servers_set).unwrap(),
+				sign_servers_set(new_set_signer.secret(), new_servers_set).unwrap(),
+				new_servers_set.clone(),
+			)
+		).result.map(|_| ());
+
+		assert_eq!(change(&other, &admin, &new_servers_set), Err(Error::AccessDenied));
+		assert_eq!(change(&admin, &other, &new_servers_set), Err(Error::AccessDenied));
+		assert_eq!(change(&admin, &admin, &new_servers_set), Ok(()));
+		assert_eq!(
+			change_servers_set(&MockKeyServer::new(), &admin, new_servers_set).result.map(|_| ()),
+			Err(Error::AccessDenied),
+		);
+
+		// new set signature must be made over the new set
+		let signed_servers_set = std::iter::once(key_server.self_public()).collect();
+		assert_eq!(
+			block_on(key_server.change_servers_set(
+				None,
+				sign_servers_set(admin.secret(), &current_servers_set).unwrap(),
+				sign_servers_set(admin.secret(), &signed_servers_set).unwrap(),
+				vec![key_server.self_public(), *other.public()].into_iter().collect(),
+			)).result.map(|_| ()),
+			Err(Error::AccessDenied),
+		);
+	}
+
+	#[test]
+	fn servers_set_change_artifacts_reflect_requested_set() {
+		let (key_server, admin) = admin_key_server();
+		let new_servers_set: BTreeSet<_> = vec![key_server.self_public(), *Random.generate().unwrap().public()]
+			.into_iter()
+			.collect();
+		let artifacts = change_servers_set(&key_server, &admin, new_servers_set.clone()).result.unwrap();
+		assert_eq!(artifacts.new_set, new_servers_set);
+		assert_eq!(artifacts.migration_id, migration_id(&new_servers_set));
+		assert_eq!(artifacts.keys_resharded, 0);
+	}
+
+	#[test]
+	fn empty_servers_set_is_rejected() {
+		let (key_server, admin) = admin_key_server();
+		let result = change_servers_set(&key_server, &admin, BTreeSet::new()).result;
+		assert!(matches!(result, Err(Error::InvalidServersSet(_))));
+	}
+
+	#[test]
+	fn servers_set_too_small_for_key_threshold_is_rejected() {
+		let (key_server, admin) = admin_key_server();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(1);
+		block_on(key_server.generate_key(None, key_id, requester, 0)).result.unwrap();
+		let key_share = key_server.key_storage().get(&key_id).unwrap().unwrap();
+		key_server.key_storage().insert(key_id, KeyShare { threshold: 2, ..key_share }).unwrap();
+
+		let two_nodes_set: BTreeSet<_> = vec![key_server.self_public(), *Random.generate().unwrap().public()]
+			.into_iter()
+			.collect();
+		let result = change_servers_set(&key_server, &admin, two_nodes_set.clone()).result;
+		assert!(matches!(result, Err(Error::InvalidServersSet(_))));
+
+		let three_nodes_set: BTreeSet<_> = two_nodes_set.into_iter()
+			.chain(std::iter::once(*Random.generate().unwrap().public()))
+			.collect();
+		assert!(change_servers_set(&key_server, &admin, three_nodes_set).result.is_ok());
+	}
+
+	#[test]
+	fn message_is_signed_with_schnorr_scheme() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		let message = H256::from_low_u64_be(100);
+		let server_key = block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap().key;
+
+		let artifacts = block_on(key_server.sign_message(None, key_id, requester, message, SignatureScheme::Schnorr))
+			.result.unwrap();
+		let artifacts = match artifacts {
+			SigningArtifacts::Schnorr(artifacts) => artifacts,
+			SigningArtifacts::Ecdsa(_) => panic!("Schnorr signature is requested"),
+		};
+
+		assert_eq!(verify_schnorr(&server_key, &message, &artifacts), Ok(true));
+		assert_eq!(verify_schnorr(&server_key, &H256::from_low_u64_be(101), &artifacts), Ok(false));
+	}
+
+	#[test]
+	fn message_is_signed_with_ecdsa_scheme() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		let message = H256::from_low_u64_be(100);
+		let server_key = block_on(key_server.generate_key(None, key_id, requester.clone(), 0)).result.unwrap().key;
+
+		let artifacts = block_on(key_server.sign_message(None, key_id, requester, message, SignatureScheme::Ecdsa))
+			.result.unwrap();
+		let artifacts = match artifacts {
+			SigningArtifacts::Ecdsa(artifacts) => artifacts,
+			SigningArtifacts::Schnorr(_) => panic!("ECDSA signature is requested"),
+		};
+		assert_eq!(verify_ecdsa(&server_key, &message, &artifacts), Ok(true));
+		assert_eq!(verify_ecdsa(&server_key, &H256::from_low_u64_be(101), &artifacts), Ok(false));
+
+		// signature with wrong recovery id isn't recovering server key
+		let mut tampered = artifacts.signature.clone();
+		tampered[64] ^= 1;
+		let tampered = EcdsaSigningArtifacts { signature: tampered };
+		assert_eq!(verify_ecdsa(&server_key, &message, &tampered), Ok(false));
+	}
+
+	#[test]
+	fn key_existence_is_hidden_from_unauthorized_requester() {
+		let key_server = MockKeyServer::new();
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let existing_key_id = ServerKeyId::from_low_u64_be(42);
+		let missing_key_id = ServerKeyId::from_low_u64_be(43);
+		block_on(key_server.generate_document_key(None, existing_key_id, requester.clone(), 0)).result.unwrap();
+
+		let restore = |key_id| block_on(key_server.restore_document_key(None, key_id, requester.clone()))
+			.result.map(|_| ());
+		assert_eq!(restore(missing_key_id), Err(Error::ServerKeyIsNotFound));
+
+		let requester_address = requester.address(&existing_key_id).unwrap();
+		key_server.acl_storage().forbid(requester_address, existing_key_id);
+		key_server.acl_storage().forbid(requester_address, missing_key_id);
+		assert_eq!(restore(existing_key_id), Err(Error::AccessDenied));
+		assert_eq!(restore(missing_key_id), Err(Error::AccessDenied));
+	}
+
+	#[test]
+	fn key_existence_is_leaked_to_unauthorized_requester() {
+		let key_server = MockKeyServer::with_leak_existence(true);
+		let requester = Requester::Public(*Random.generate().unwrap().public());
+		let existing_key_id = ServerKeyId::from_low_u64_be(42);
+		let missing_key_id = ServerKeyId::from_low_u64_be(43);
+		block_on(key_server.generate_document_key(None, existing_key_id, requester.clone(), 0)).result.unwrap();
+
+		let requester_address = requester.address(&existing_key_id).unwrap();
+		key_server.acl_storage().forbid(requester_address, existing_key_id);
+		key_server.acl_storage().forbid(requester_address, missing_key_id);
+		let restore = |key_id| block_on(key_server.restore_document_key(None, key_id, requester.clone()))
+			.result.map(|_| ());
+		assert_eq!(restore(existing_key_id), Err(Error::AccessDenied));
+		assert_eq!(restore(missing_key_id), Err(Error::ServerKeyIsNotFound));
+	}
+
+	#[test]
+	fn key_server_advertises_capabilities() {
+		let key_server = MockKeyServer::new();
+		assert_eq!(key_server.capabilities(), KeyServerCapabilities::default());
+
+		let key_server = MockKeyServer::new().with_capabilities(KeyServerCapabilities {
+			ecdsa: false,
+			admin: false,
+			..Default::default()
+		});
+		let capabilities = key_server.capabilities();
+		assert!(capabilities.supports_signature_scheme(SignatureScheme::Schnorr));
+		assert!(!capabilities.supports_signature_scheme(SignatureScheme::Ecdsa));
+		assert!(!capabilities.admin);
+		assert!(capabilities.document_key_generation);
+	}
+
+	#[test]
+	fn document_key_is_restored_at_given_version() {
+		let key_server = MockKeyServer::new();
+		let requester_key_pair = Random.generate().unwrap();
+		let requester = Requester::Public(*requester_key_pair.public());
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		let document_key = block_on(key_server.generate_document_key(None, key_id, requester.clone(), 0))
+			.result.unwrap().document_key;
+
+		// add new version with different secret share
+		let mut key_share = key_server.key_storage().get(&key_id).unwrap().unwrap();
+		let old_version = key_share.last_version().unwrap().hash;
+		let mut new_version = key_share.last_version().unwrap().clone();
+		new_version.hash = H256::from_low_u64_be(1);
+		new_version.secret_share = Random.generate().unwrap().secret().clone();
+		key_share.versions.push(new_version);
+		key_server.key_storage().update(key_id, key_share).unwrap();
+
+		let restore_at_version = |version| block_on(
+			key_server.restore_document_key_at_version(None, key_id, requester.clone(), version)
+		).result.and_then(|artifacts| artifacts.encrypted_document_key.decrypt(requester_key_pair.secret()));
+		assert_eq!(restore_at_version(old_version), Ok(document_key));
+		assert_ne!(restore_at_version(H256::from_low_u64_be(1)), Ok(document_key));
+		assert_eq!(restore_at_version(H256::from_low_u64_be(2)), Err(Error::UnknownKeyVersion));
+	}
+
+	#[test]
+	fn shadow_artifacts_are_decrypted_to_document_key() {
+		let document_key = *Random.generate().unwrap().public();
+		let common_point = *Random.generate().unwrap().public();
+		let requester = Random.generate().unwrap();
+		let participants = (1..=3)
+			.map(|i| (KeyServerId::from_low_u64_be(i), Random.generate().unwrap().secret().clone()))
+			.collect::<Vec<_>>();
+
+		let artifacts = make_shadow_artifacts(&document_key, &common_point, &participants, requester.public())
+			.unwrap();
+		assert_eq!(artifacts.threshold, 2);
+		assert_eq!(artifacts.participants_coefficients.len(), 3);
+		assert_eq!(artifacts.decrypt(requester.secret()), Ok(document_key));
+	}
+
+	#[test]
+	fn document_key_shadow_is_restored() {
+		let key_server = MockKeyServer::new();
+		let requester = Random.generate().unwrap();
+		let key_id = ServerKeyId::from_low_u64_be(42);
+		let document_key = block_on(key_server.generate_document_key(None, key_id, Requester::Public(*requester.public()), 0))
+			.result.unwrap().document_key;
+
+		let artifacts = block_on(key_server.restore_document_key_shadow(None, key_id, Requester::Public(*requester.public())))
+			.result.unwrap();
+		assert_eq!(artifacts.decrypt(requester.secret()), Ok(document_key));
+	}
+
+	#[test]
+	fn encrypted_document_key_is_generated() {
+		let key_server = MockKeyServer::new();
+		let requester = Random.generate().unwrap();
+		let key_id = ServerKeyId::from_low_u64_be(42);
+
+		let artifacts = block_on(key_server.generate_document_key_encrypted(
+			None,
+			key_id,
+			Requester::Public(*requester.public()),
+			0,
+		)).result.unwrap();
+		let restored = block_on(key_server.restore_document_key(None, key_id, Requester::Public(*requester.public())))
+			.result.unwrap();
+		assert_eq!(
+			artifacts.encrypted_document_key.decrypt(requester.secret()),
+			restored.encrypted_document_key.decrypt(requester.secret()),
+		);
+		assert_eq!(
+			Some(artifacts.common_point),
+			key_server.key_storage().get(&key_id).unwrap().unwrap().common_point,
+		);
+	}
+}